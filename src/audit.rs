@@ -0,0 +1,143 @@
+use crate::{
+    db::{db_add_audit_entry, db_list_audit, AuditEntry, Db},
+    rate_limit::caller_ip,
+    response::RequestResult,
+    token::{authenticated, AdminRead, AdminToken},
+};
+use async_trait::async_trait;
+use rocket::{
+    fairing::AdHoc,
+    request::{FromRequest, Outcome},
+    serde::json::Json,
+    Request,
+};
+use rocket_db_pools::{sqlx::SqliteConnection, Connection};
+use std::convert::Infallible;
+use tracing::error;
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// who did something, name of the token and ip of requests, or a name like
+/// `cli` for everything else
+#[derive(Clone, Debug, PartialEq)]
+pub struct Actor {
+    pub name: String,
+    pub ip: Option<String>,
+}
+
+impl Actor {
+    pub fn named(name: &str) -> Self {
+        Actor {
+            name: name.to_string(),
+            ip: None,
+        }
+    }
+
+    /// caller of the request, for guards that need it without failing
+    pub async fn of(request: &Request<'_>) -> Self {
+        let name = match authenticated(request).await {
+            Ok(caller) => caller.name.clone(),
+            Err(_) => "anonymous".to_string(),
+        };
+        Actor {
+            name,
+            ip: caller_ip(request).map(|ip| ip.to_string()),
+        }
+    }
+}
+
+/// requests without a valid token, like `/verify_payment`, are `anonymous`
+#[async_trait]
+impl<'r> FromRequest<'r> for Actor {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Actor::of(request).await)
+    }
+}
+
+/// what is changed, `before` and `after` are states of the target, targets
+/// other than transactions and clients are like `customer:12`
+#[derive(Default)]
+pub struct Change<'a> {
+    pub action: &'a str,
+    pub authority: Option<&'a str>,
+    pub clients: Option<&'a str>,
+    pub target: Option<&'a str>,
+    pub before: Option<&'a str>,
+    pub after: Option<&'a str>,
+    pub note: Option<&'a str>,
+}
+
+/// query of audit log, every field that is given must match
+#[derive(Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub authority: Option<String>,
+    pub client: Option<String>,
+    pub target: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// filter is read from query of the request, like `?authority=A1&from=2023-01-31`
+#[async_trait]
+impl<'r> FromRequest<'r> for AuditFilter {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let field = |name| {
+            request
+                .query_value::<String>(name)
+                .and_then(|value| value.ok())
+        };
+        Outcome::Success(AuditFilter {
+            actor: field("actor"),
+            action: field("action"),
+            authority: field("authority"),
+            client: field("client"),
+            target: field("target"),
+            from: field("from"),
+            to: field("to"),
+        })
+    }
+}
+
+/// adds the change to audit log, the change is already done so failing to
+/// record it is only logged
+pub async fn record(db: &mut SqliteConnection, actor: &Actor, change: &Change<'_>) {
+    if let Err(error) = db_add_audit_entry(db, actor, change).await {
+        error!(
+            "cannot record '{}' of '{}' in audit log: {error}",
+            change.action, actor.name
+        );
+    }
+}
+
+/// mounts admin endpoint of audit log
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("audit log", |rocket| async {
+        rocket.mount("/admin", routes![audit_log])
+    })
+}
+
+/// `from` and `to` of filter are dates like `2023-01-31`, both are included
+#[get("/audit?<limit>")]
+async fn audit_log(
+    _token: AdminToken<AdminRead>,
+    mut db: Connection<Db>,
+    filter: AuditFilter,
+    limit: Option<u32>,
+) -> Json<RequestResult<Vec<AuditEntry>>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let entries = try_in_request!(db_list_audit(&mut db, &filter, limit)
+        .await
+        .map_err(|e| format!("cannot list audit log: {e}")));
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(entries),
+    })
+}
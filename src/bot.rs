@@ -0,0 +1,274 @@
+mod telegram;
+
+use crate::{
+    audit::Actor,
+    checkout::{Checkout, NewPayment, Verified},
+    clients_price,
+    customer::{authorize_clients, SessionError},
+    db::{db_find_customer_login, Db},
+    notifier::{Notify, TELEGRAM_API_URL},
+    payment::Payment,
+    rate_limit::{LimitKey, RateLimiter},
+    runner::Runner,
+    webhook::Webhooks,
+};
+use rocket::fairing::AdHoc;
+use rocket_db_pools::{sqlx::SqlitePool, Database};
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use telegram::Telegram;
+use tracing::{error, field::Empty, info, info_span, Instrument};
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const HELP: &str = "send names of clients that you want to buy, separated by space, \
+    then /pay to pay for them and /verify after you paid";
+
+pub struct BotConfig {
+    pub api_url: String,
+    pub bot_token: String,
+}
+
+impl BotConfig {
+    /// bot only runs when `MANJALIOF_BOT_TOKEN` is set
+    pub fn from_env() -> Option<Self> {
+        let bot_token = env::var("MANJALIOF_BOT_TOKEN").ok()?;
+        Some(BotConfig {
+            api_url: env::var("MANJALIOF_TELEGRAM_API_URL")
+                .unwrap_or_else(|_| TELEGRAM_API_URL.to_string()),
+            bot_token,
+        })
+    }
+}
+
+/// what each chat is buying, it's lost on restart but payments are in
+/// database and can be verified from website too
+#[derive(Default)]
+struct Order {
+    clients: Vec<String>,
+    authority: Option<String>,
+}
+
+/// sells clients on telegram with the same payment flow as website
+pub struct Bot {
+    telegram: Telegram,
+    db: SqlitePool,
+    payment: Arc<dyn Payment>,
+    runner: Arc<dyn Runner>,
+    notify: Notify,
+    webhooks: Webhooks,
+    /// `/pay` of each chat is limited like `/create_payment` of each ip
+    limiter: Arc<RateLimiter>,
+    orders: HashMap<i64, Order>,
+    /// id of the next update that is not answered yet
+    offset: i64,
+}
+
+impl Bot {
+    pub fn new(
+        config: &BotConfig,
+        db: SqlitePool,
+        payment: Arc<dyn Payment>,
+        runner: Arc<dyn Runner>,
+        notify: Notify,
+        webhooks: Webhooks,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Bot {
+            telegram: Telegram::new(&config.api_url, &config.bot_token),
+            db,
+            payment,
+            runner,
+            notify,
+            webhooks,
+            limiter,
+            orders: HashMap::new(),
+            offset: 0,
+        }
+    }
+
+    /// answers messages that came until now or in the next `timeout`
+    pub async fn poll(&mut self, timeout: Duration) -> Result<(), String> {
+        let updates = self.telegram.get_updates(self.offset, timeout).await?;
+        for update in updates {
+            self.offset = update.update_id + 1;
+            let (chat_id, text) = match update.message {
+                Some(telegram::Message {
+                    chat,
+                    text: Some(text),
+                }) => (chat.id, text),
+                _ => continue,
+            };
+            let span = info_span!("bot", chat_id, authority = Empty, names = Empty);
+            let answer = self.answer(chat_id, text.trim()).instrument(span).await;
+            self.telegram.send_message(chat_id, &answer).await?;
+        }
+        Ok(())
+    }
+
+    async fn answer(&mut self, chat_id: i64, text: &str) -> String {
+        let result = match text.split_whitespace().next().unwrap_or_default() {
+            "/start" | "/help" => Ok(HELP.to_string()),
+            "/pay" => self.pay(chat_id).await,
+            "/verify" => self.verify(chat_id).await,
+            command if command.starts_with('/') => Err(format!("unknown command '{command}'")),
+            _ => self.choose_clients(chat_id, text).await,
+        };
+        result.unwrap_or_else(|error| {
+            error!("bot cannot answer: {error}");
+            error
+        })
+    }
+
+    /// payments of bot are done by `telegram:{chat_id}`
+    fn checkout(&self, chat_id: i64) -> Checkout<'_> {
+        Checkout {
+            payment: self.payment.as_ref(),
+            runner: self.runner.as_ref(),
+            notify: &self.notify,
+            webhooks: &self.webhooks,
+            actor: Actor::named(&format!("telegram:{chat_id}")),
+        }
+    }
+
+    async fn choose_clients(&mut self, chat_id: i64, text: &str) -> Result<String, String> {
+        let clients: Vec<String> = text
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string())
+            .collect();
+        self.runner
+            .validate_clients(&clients)
+            .await
+            .map_err(|e| format!("cannot validate clients: {e}"))?;
+
+        let answer = format!(
+            "price of '{}' is {} rials, send /pay to buy them",
+            clients.join(","),
+            clients_price(clients.len())
+        );
+        self.orders.insert(
+            chat_id,
+            Order {
+                clients,
+                authority: None,
+            },
+        );
+        Ok(answer)
+    }
+
+    async fn pay(&mut self, chat_id: i64) -> Result<String, String> {
+        let clients = match self.orders.get(&chat_id) {
+            Some(order) if !order.clients.is_empty() => order.clients.clone(),
+            _ => return Err("send names of clients first".to_string()),
+        };
+        self.limiter
+            .check(
+                "bot_pay",
+                LimitKey::Chat,
+                &chat_id.to_string(),
+                Instant::now(),
+            )
+            .map_err(|retry_after| {
+                format!(
+                    "too many payments, try again in {} seconds",
+                    retry_after.as_secs_f64().ceil().max(1.0)
+                )
+            })?;
+
+        let mut db = self.db.acquire().await.map_err(|e| e.to_string())?;
+        // private chats have the id of their user, which is the customer's telegram id
+        let customer = db_find_customer_login(&mut db, None, Some(&chat_id.to_string()))
+            .await
+            .map_err(|e| format!("cannot find customer: {e}"))?
+            .map(|(customer, _)| customer)
+            .ok_or(SessionError::Missing);
+        let customer_id = authorize_clients(&mut db, customer, &clients)
+            .await
+            .map_err(|e| format!("cannot authorize clients: {e}"))?;
+        let NewPayment {
+            authority, price, ..
+        } = self
+            .checkout(chat_id)
+            .create(&mut db, &clients, customer_id, None)
+            .await?;
+
+        let answer = format!(
+            "pay {price} rials at {} and then send /verify",
+            self.payment.start_url(&authority)
+        );
+        info!("payment is created by bot");
+        self.orders.entry(chat_id).or_default().authority = Some(authority);
+        Ok(answer)
+    }
+
+    async fn verify(&mut self, chat_id: i64) -> Result<String, String> {
+        let authority = self
+            .orders
+            .get(&chat_id)
+            .and_then(|order| order.authority.clone())
+            .ok_or("there is no payment to verify, send /pay first")?;
+
+        let mut db = self.db.acquire().await.map_err(|e| e.to_string())?;
+        match self.checkout(chat_id).verify(&mut db, &authority).await? {
+            Verified::Clients(names) => {
+                self.orders.remove(&chat_id);
+                Ok(format!(
+                    "payment '{authority}' is verified and '{names}' are activated"
+                ))
+            }
+            Verified::TopUp => Err(format!("payment '{authority}' is not for clients")),
+        }
+    }
+}
+
+/// starts polling after liftoff when bot is configured
+pub fn stage(config: Option<BotConfig>) -> AdHoc {
+    AdHoc::on_liftoff("telegram bot", |rocket| {
+        Box::pin(async move {
+            let config = match config {
+                Some(config) => config,
+                None => return,
+            };
+            let (db, payment, runner, notify, webhooks, limiter) = match (
+                Db::fetch(rocket),
+                rocket.state::<Arc<dyn Payment>>(),
+                rocket.state::<Arc<dyn Runner>>(),
+                rocket.state::<Notify>(),
+                rocket.state::<Webhooks>(),
+                rocket.state::<Arc<RateLimiter>>(),
+            ) {
+                (
+                    Some(db),
+                    Some(payment),
+                    Some(runner),
+                    Some(notify),
+                    Some(webhooks),
+                    Some(limiter),
+                ) => (
+                    (**db).clone(),
+                    payment.clone(),
+                    runner.clone(),
+                    notify.clone(),
+                    webhooks.clone(),
+                    limiter.clone(),
+                ),
+                _ => return error!("cannot start telegram bot: state is not set up"),
+            };
+
+            let mut bot = Bot::new(&config, db, payment, runner, notify, webhooks, limiter);
+            rocket::tokio::spawn(async move {
+                loop {
+                    if let Err(error) = bot.poll(POLL_TIMEOUT).await {
+                        error!("telegram bot cannot poll: {error}");
+                        rocket::tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,88 @@
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct ApiResult<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Update {
+    pub update_id: i64,
+    pub message: Option<Message>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Message {
+    pub chat: Chat,
+    pub text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Chat {
+    pub id: i64,
+}
+
+/// the few methods of bot api that bot needs
+pub struct Telegram {
+    /// api url with bot token, like `https://api.telegram.org/bot123:abc`
+    bot_url: String,
+    client: Client,
+}
+
+impl Telegram {
+    pub fn new(api_url: &str, bot_token: &str) -> Self {
+        Telegram {
+            bot_url: format!("{api_url}/bot{bot_token}"),
+            client: Client::new(),
+        }
+    }
+
+    /// waits up to `timeout` for updates after `offset`
+    pub async fn get_updates(&self, offset: i64, timeout: Duration) -> Result<Vec<Update>, String> {
+        let body = json!({ "offset": offset, "timeout": timeout.as_secs() });
+        // give telegram some time to answer after the long poll is over
+        self.call("getUpdates", body, timeout + Duration::from_secs(10))
+            .await
+    }
+
+    pub async fn send_message(&self, chat_id: i64, text: &str) -> Result<(), String> {
+        let body = json!({ "chat_id": chat_id, "text": text });
+        self.call::<Value>("sendMessage", body, Duration::from_secs(10))
+            .await?;
+        Ok(())
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: Value,
+        timeout: Duration,
+    ) -> Result<T, String> {
+        let resp = self
+            .client
+            .post(format!("{}/{method}", self.bot_url))
+            .json(&body)
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(|e| format!("send failed: {e}"))?
+            .text()
+            .await
+            .map_err(|e| format!("receiving failed: {e}"))?;
+
+        let result: ApiResult<T> = serde_json::from_str(&resp)
+            .map_err(|e| format!("desrializing '{resp}' failed: {e}"))?;
+        match (result.ok, result.result) {
+            (true, Some(result)) => Ok(result),
+            _ => Err(format!(
+                "'{method}' failed: {}",
+                result.description.unwrap_or_default()
+            )),
+        }
+    }
+}
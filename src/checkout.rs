@@ -0,0 +1,524 @@
+use crate::{
+    audit::{self, Actor, Change},
+    client_config::generate_config_token,
+    clients_price,
+    db::{
+        db_add_paid_client, db_add_transaction, db_credit_top_up, db_find_transaction,
+        db_paid_clients, db_report_failure, db_set_status, db_set_verified, db_transaction,
+        Transaction, TransactionKind, TransactionStatus,
+    },
+    ledger::{self, Account, Movement},
+    notifier::{Event, Notify},
+    payment::{Payment, AUTHORITY_TTL},
+    runner::{PaymentInfo, Runner},
+    webhook::{PaymentEvent, Webhooks},
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use rocket::{
+    outcome::try_outcome,
+    request::{FromRequest, Outcome},
+    Request, State,
+};
+use rocket_db_pools::sqlx::SqliteConnection;
+use std::sync::Arc;
+use tracing::Span;
+use tracing::{error, info};
+
+const EXPIRED_ERROR: &str = "payment link expired, start again";
+/// referrers are free text from anyone, they are kept short
+const MAX_REFERRER_LENGTH: usize = 64;
+
+/// buying clients through the gateway, shared by `/create_payment`,
+/// `/verify_payment` and telegram bot
+pub struct Checkout<'a> {
+    pub payment: &'a dyn Payment,
+    pub runner: &'a dyn Runner,
+    pub notify: &'a Notify,
+    pub webhooks: &'a Webhooks,
+    /// who changes are recorded for in audit log
+    pub actor: Actor,
+}
+
+pub struct NewPayment {
+    pub authority: String,
+    pub config_token: String,
+    pub price: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verified {
+    /// clients are made paid, or they were already
+    Clients(String),
+    TopUp,
+}
+
+impl Checkout<'_> {
+    /// clients should already be authorized
+    pub async fn create(
+        &self,
+        db: &mut SqliteConnection,
+        clients: &[String],
+        customer_id: Option<i64>,
+        referrer: Option<&str>,
+    ) -> Result<NewPayment, String> {
+        if clients.is_empty() {
+            return Err("at least provide one client".to_string());
+        }
+        if referrer.map_or(0, |referrer| referrer.chars().count()) > MAX_REFERRER_LENGTH {
+            return Err(format!(
+                "referrer should be at most {MAX_REFERRER_LENGTH} characters"
+            ));
+        }
+
+        self.runner
+            .validate_clients(clients)
+            .await
+            .map_err(|e| format!("cannot validate clients: {e}"))?;
+
+        let price = clients_price(clients.len());
+        let names = clients.join(",");
+        Span::current().record("names", names.as_str());
+        let authority = self
+            .payment
+            .request_payment_authority(&names, price)
+            .await
+            .map_err(|e| format!("cannot request payment: {e}"))?;
+        Span::current().record("authority", authority.as_str());
+
+        let config_token = generate_config_token();
+        db_add_transaction(
+            db,
+            &authority,
+            &names,
+            price,
+            &config_token,
+            customer_id,
+            referrer.filter(|referrer| !referrer.is_empty()),
+        )
+        .await
+        .map_err(|e| format!("cannot add transactiont to database: {e}"))?;
+        let change = Change {
+            action: "transaction.create",
+            authority: Some(&authority),
+            clients: Some(&names),
+            after: Some(TransactionStatus::Created.as_str()),
+            ..Default::default()
+        };
+        audit::record(db, &self.actor, &change).await;
+
+        self.webhooks
+            .enqueue(db, PaymentEvent::Created, &authority, &names, price, None)
+            .await;
+        self.notify.send(Event::PaymentCreated {
+            authority: authority.clone(),
+            names,
+            amount: price,
+        });
+        Ok(NewPayment {
+            authority,
+            config_token,
+            price,
+        })
+    }
+
+    pub async fn verify(
+        &self,
+        db: &mut SqliteConnection,
+        authority: &str,
+    ) -> Result<Verified, String> {
+        self.verify_as(db, authority, "transaction.verify").await
+    }
+
+    /// verifies the payment and records changes of its status as `action`
+    async fn verify_as(
+        &self,
+        db: &mut SqliteConnection,
+        authority: &str,
+        action: &str,
+    ) -> Result<Verified, String> {
+        Span::current().record("authority", authority);
+        let transaction = db_find_transaction(db, authority)
+            .await
+            .map_err(|e| format!("cannot find authority in db: {e}"))?;
+        let (names, amount) = (transaction.names, transaction.amount);
+        Span::current().record("names", names.as_str());
+        if transaction.kind == TransactionKind::Clients
+            && transaction.status == TransactionStatus::Fulfilled
+        {
+            return Ok(Verified::Clients(names));
+        }
+        if transaction.status == TransactionStatus::Resolved {
+            return Err(format!("payment '{authority}' is resolved by an admin"));
+        }
+        if transaction.status == TransactionStatus::Refunded {
+            return Err(format!("payment '{authority}' is given back to wallet"));
+        }
+        if transaction.status == TransactionStatus::Expired {
+            return Err(EXPIRED_ERROR.to_string());
+        }
+        // gateway answers that a verified payment is already verified, so only
+        // runner is run again with what gateway gave the first time
+        if transaction.kind == TransactionKind::Clients
+            && transaction.status == TransactionStatus::Verified
+        {
+            let stored = db_transaction(db, authority).await?;
+            let payment_info = PaymentInfo {
+                authority: authority.to_string(),
+                ref_id: stored.ref_id.unwrap_or_default(),
+                amount,
+                gateway: stored.gateway.unwrap_or_default(),
+                date: Utc::now(),
+            };
+            return self
+                .pay_clients(db, transaction.status, names, &payment_info, action)
+                .await;
+        }
+        // gateway is asked even after authority is expired, in case it's paid
+        // right before that
+        let expired = transaction.status == TransactionStatus::Created
+            && Utc::now() > transaction.date + *AUTHORITY_TTL;
+
+        let receipt = match self.payment.verify(authority, amount).await {
+            Ok(receipt) => receipt,
+            Err(error) if expired => {
+                db_set_status(db, authority, TransactionStatus::Expired)
+                    .await
+                    .map_err(|e| format!("cannot update transaction status: {e}"))?;
+                let change = Change {
+                    action,
+                    authority: Some(authority),
+                    clients: Some(names.as_str()).filter(|names| !names.is_empty()),
+                    before: Some(transaction.status.as_str()),
+                    after: Some(TransactionStatus::Expired.as_str()),
+                    note: Some(&error),
+                    ..Default::default()
+                };
+                audit::record(db, &self.actor, &change).await;
+                info!("payment is expired: {error}");
+                return Err(EXPIRED_ERROR.to_string());
+            }
+            Err(error) => {
+                if self.first_failure(db, authority, transaction.status).await {
+                    self.webhooks
+                        .enqueue(
+                            db,
+                            PaymentEvent::Failed,
+                            authority,
+                            &names,
+                            amount,
+                            Some(&error),
+                        )
+                        .await;
+                    self.notify.send(Event::VerificationFailed {
+                        authority: authority.to_string(),
+                        error: error.clone(),
+                    });
+                }
+                return Err(format!("cannot verify payment: {error}"));
+            }
+        };
+
+        let (credited, discount) = match transaction.kind {
+            TransactionKind::Clients => (
+                Account::Revenue,
+                clients_price(names.split(',').count()).saturating_sub(amount),
+            ),
+            TransactionKind::TopUp => (Account::Wallets, 0),
+        };
+        // payment is left unverified until ledger has it, verifying again
+        // records it only once
+        let movement =
+            Movement::gateway_payment(authority, amount, receipt.fee, discount, credited);
+        ledger::record(db, &movement)
+            .await
+            .map_err(|e| format!("cannot record payment in ledger: {e}"))?;
+
+        if transaction.kind == TransactionKind::TopUp {
+            let credited = db_credit_top_up(db, authority, &receipt)
+                .await
+                .map_err(|e| format!("cannot credit wallet: {e}"))?;
+            if credited {
+                info!(amount, "wallet is topped up");
+                let change = Change {
+                    action,
+                    authority: Some(authority),
+                    before: Some(transaction.status.as_str()),
+                    after: Some(TransactionStatus::Fulfilled.as_str()),
+                    ..Default::default()
+                };
+                audit::record(db, &self.actor, &change).await;
+                for event in [PaymentEvent::Verified, PaymentEvent::Fulfilled] {
+                    self.webhooks
+                        .enqueue(db, event, authority, &names, amount, None)
+                        .await;
+                }
+                self.notify.send(Event::PaymentVerified {
+                    authority: authority.to_string(),
+                    names: String::new(),
+                    amount,
+                });
+            }
+            return Ok(Verified::TopUp);
+        }
+
+        db_set_verified(db, authority, &receipt)
+            .await
+            .map_err(|e| format!("cannot update transaction status: {e}"))?;
+        self.webhooks
+            .enqueue(db, PaymentEvent::Verified, authority, &names, amount, None)
+            .await;
+        self.notify.send(Event::PaymentVerified {
+            authority: authority.to_string(),
+            names: names.clone(),
+            amount,
+        });
+
+        let payment_info = PaymentInfo {
+            authority: authority.to_string(),
+            ref_id: receipt.ref_id,
+            amount,
+            gateway: receipt.gateway,
+            date: Utc::now(),
+        };
+        self.pay_clients(db, transaction.status, names, &payment_info, action)
+            .await
+    }
+
+    /// makes every client of a verified payment paid, status is recorded as
+    /// `action` when it changes from `before`
+    async fn pay_clients(
+        &self,
+        db: &mut SqliteConnection,
+        before: TransactionStatus,
+        names: String,
+        payment_info: &PaymentInfo,
+        action: &str,
+    ) -> Result<Verified, String> {
+        let authority = payment_info.authority.as_str();
+        let result = self.make_clients_paid(db, &names, &[], payment_info).await;
+        let after = match result {
+            Ok(status) => status,
+            Err(_) => TransactionStatus::Verified,
+        };
+        if after != before {
+            let change = Change {
+                action,
+                authority: Some(authority),
+                clients: Some(&names),
+                before: Some(before.as_str()),
+                after: Some(after.as_str()),
+                note: result.as_ref().err().map(String::as_str),
+                ..Default::default()
+            };
+            audit::record(db, &self.actor, &change).await;
+        }
+        result?;
+        Ok(Verified::Clients(names))
+    }
+
+    /// verifies the payment again for an admin, the attempt is recorded in
+    /// audit log even when it changes nothing
+    pub async fn reverify(
+        &self,
+        db: &mut SqliteConnection,
+        authority: &str,
+    ) -> Result<Verified, String> {
+        let before = db_transaction(db, authority).await?.status;
+        let result = self.verify_as(db, authority, "transaction.reverify").await;
+        let after = db_transaction(db, authority).await?.status;
+        if before == after {
+            let change = Change {
+                action: "transaction.reverify",
+                authority: Some(authority),
+                before: Some(before.as_str()),
+                after: Some(after.as_str()),
+                note: result.as_ref().err().map(String::as_str),
+                ..Default::default()
+            };
+            audit::record(db, &self.actor, &change).await;
+        }
+        result
+    }
+
+    /// makes clients of a verified payment paid, for payments that runner
+    /// failed on while they were verified, only `clients` of it are made paid
+    /// when they are given and payment is fulfilled once every client of it
+    /// is paid, every attempt is recorded in audit log
+    pub async fn fulfill(
+        &self,
+        db: &mut SqliteConnection,
+        authority: &str,
+        clients: &[String],
+    ) -> Result<String, String> {
+        Span::current().record("authority", authority);
+        let transaction = db_transaction(db, authority).await?;
+        Span::current().record("names", transaction.names.as_str());
+        let paying = match clients.is_empty() {
+            true => transaction.names.clone(),
+            false => clients.join(","),
+        };
+        let result = self.fulfill_transaction(db, &transaction, clients).await;
+        let after = match result {
+            Ok(status) => status,
+            Err(_) => transaction.status,
+        };
+        let change = Change {
+            action: "transaction.fulfill",
+            authority: Some(authority),
+            clients: Some(&paying),
+            before: Some(transaction.status.as_str()),
+            after: Some(after.as_str()),
+            note: result.as_ref().err().map(String::as_str),
+            ..Default::default()
+        };
+        audit::record(db, &self.actor, &change).await;
+        result?;
+        Ok(paying)
+    }
+
+    async fn fulfill_transaction(
+        &self,
+        db: &mut SqliteConnection,
+        transaction: &Transaction,
+        clients: &[String],
+    ) -> Result<TransactionStatus, String> {
+        let (authority, names) = (&transaction.authority, &transaction.names);
+        if transaction.kind != TransactionKind::Clients {
+            return Err(format!("payment '{authority}' is not for clients"));
+        }
+        if transaction.status != TransactionStatus::Verified {
+            return Err(format!(
+                "payment '{authority}' is {}, only verified payments can be fulfilled",
+                transaction.status.as_str()
+            ));
+        }
+        if let Some(name) = clients
+            .iter()
+            .find(|name| !names.split(',').any(|n| n == name.as_str()))
+        {
+            return Err(format!("client '{name}' is not in payment '{authority}'"));
+        }
+
+        let payment_info = PaymentInfo {
+            authority: authority.to_string(),
+            ref_id: transaction.ref_id.clone().unwrap_or_default(),
+            amount: transaction.amount,
+            gateway: transaction.gateway.clone().unwrap_or_default(),
+            date: Utc::now(),
+        };
+        self.make_clients_paid(db, names, clients, &payment_info)
+            .await
+    }
+
+    /// failures are reported once for each status of a payment, so retrying
+    /// verify doesn't flood webhooks and admins
+    async fn first_failure(
+        &self,
+        db: &mut SqliteConnection,
+        authority: &str,
+        status: TransactionStatus,
+    ) -> bool {
+        db_report_failure(db, authority, status)
+            .await
+            .unwrap_or_else(|e| {
+                error!("cannot record failure of '{authority}': {e}");
+                true
+            })
+    }
+
+    /// `names` are every client of the payment, only `clients` of them are
+    /// made paid unless it's empty, clients that are already paid are skipped
+    /// and a failed client doesn't stop the others. payment is fulfilled once
+    /// every client of it is paid, otherwise it stays verified
+    async fn make_clients_paid(
+        &self,
+        db: &mut SqliteConnection,
+        names: &str,
+        clients: &[String],
+        payment_info: &PaymentInfo,
+    ) -> Result<TransactionStatus, String> {
+        let (authority, amount) = (payment_info.authority.as_str(), payment_info.amount);
+        let mut paid = db_paid_clients(db, authority)
+            .await
+            .map_err(|e| format!("cannot find paid clients: {e}"))?;
+        let paying: Vec<&str> = names
+            .split(',')
+            .filter(|name| clients.is_empty() || clients.iter().any(|client| client == name))
+            .filter(|name| !paid.iter().any(|paid| paid == name))
+            .collect();
+        let mut errors = Vec::new();
+        for name in paying {
+            match self.runner.make_client_paid(name, payment_info).await {
+                Ok(()) => {
+                    db_add_paid_client(db, authority, name)
+                        .await
+                        .map_err(|e| format!("cannot record paid client '{name}': {e}"))?;
+                    paid.push(name.to_string());
+                }
+                Err(error) => errors.push(format!("'{name}': {error}")),
+            }
+        }
+
+        if !errors.is_empty() {
+            let error = errors.join(", ");
+            if self
+                .first_failure(db, authority, TransactionStatus::Verified)
+                .await
+            {
+                self.webhooks
+                    .enqueue(
+                        db,
+                        PaymentEvent::Failed,
+                        authority,
+                        names,
+                        amount,
+                        Some(&error),
+                    )
+                    .await;
+                self.notify.send(Event::RunnerFailed {
+                    authority: authority.to_string(),
+                    names: names.to_string(),
+                    error: error.clone(),
+                });
+            }
+            return Err(format!(
+                "CRITICAL: runner failed on names '{names}': {error}"
+            ));
+        }
+        if !names
+            .split(',')
+            .all(|name| paid.iter().any(|paid| paid == name))
+        {
+            return Ok(TransactionStatus::Verified);
+        }
+
+        db_set_status(db, authority, TransactionStatus::Fulfilled)
+            .await
+            .map_err(|e| format!("cannot update transaction status: {e}"))?;
+        self.webhooks
+            .enqueue(db, PaymentEvent::Fulfilled, authority, names, amount, None)
+            .await;
+        Ok(TransactionStatus::Fulfilled)
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Checkout<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let payment = try_outcome!(request.guard::<&State<Arc<dyn Payment>>>().await);
+        let runner = try_outcome!(request.guard::<&State<Arc<dyn Runner>>>().await);
+        let notify = try_outcome!(request.guard::<&State<Notify>>().await);
+        let webhooks = try_outcome!(request.guard::<&State<Webhooks>>().await);
+        let actor = Actor::of(request).await;
+        Outcome::Success(Checkout {
+            payment: payment.as_ref(),
+            runner: runner.as_ref(),
+            notify,
+            webhooks,
+            actor,
+        })
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{
+    audit::{self, Actor, Change},
+    checkout::{Checkout, Verified},
+    db::{
+        db_flush_journal, db_list_transactions, db_transaction, Db, Transaction, TransactionStatus,
+    },
+    notifier::Notify,
+    payment::Payment,
+    reports::csv_field,
+    runner::Runner,
+    token::{issue_token, list_tokens, parse_scopes, revoke_token, rotate_token, Scope},
+    webhook::Webhooks,
+};
+use chrono::{Duration, Utc};
+use rocket::{Phase, Rocket};
+use rocket_db_pools::{
+    sqlx::{pool::PoolConnection, Sqlite, SqlitePool},
+    Database,
+};
+use std::sync::Arc;
+use tracing::{error, field::Empty, info_span, Instrument};
+
+const DEFAULT_LIST_COUNT: u32 = 20;
+const USAGE: &str = "usage:
+    manjaliof-backend [serve]                           start the server
+    manjaliof-backend migrate                           create or update tables of database
+    manjaliof-backend list-transactions [status] [count]
+                                                        newest transactions, status is created, verified,
+                                                        fulfilled, resolved, expired or refunded, count
+                                                        is 20 by default
+    manjaliof-backend show <authority>                  show everything of a transaction
+    manjaliof-backend re-verify <authority>             verify the payment with gateway again and make
+                                                        its clients paid
+    manjaliof-backend fulfill <authority>               make clients of a verified payment paid, for when
+                                                        runner failed on them
+    manjaliof-backend export                            write every transaction as csv
+    manjaliof-backend issue-token <name> <scopes> [days] same as token issue
+    manjaliof-backend token issue <name> <scopes> [days] issue a token, scopes are comma separated
+                                                        from create_payment, admin_read, admin_write, refunds
+    manjaliof-backend token rotate <name>               give the token a new secret
+    manjaliof-backend token revoke <name>               stop the token from working
+    manjaliof-backend token list                        show every token";
+
+/// runs an operator command with the same database the server uses,
+/// `re-verify` and `fulfill` also get its gateway and runner. notifications of
+/// them are not sent but webhooks are delivered by the server
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if matches!(args.as_slice(), ["help"] | ["--help"] | ["-h"]) {
+        println!("{USAGE}");
+        return Ok(());
+    }
+
+    // other commands only need database, so a broken runner or webhook
+    // config doesn't stop them
+    let (rocket, journal) = match args.as_slice() {
+        ["re-verify", _] | ["fulfill", _] => {
+            let (rocket, journal) = crate::app()?;
+            (rocket, Some(journal))
+        }
+        _ => (rocket::build().attach(Db::stage()), None),
+    };
+    // database is migrated while rocket ignites
+    let rocket = rocket
+        .ignite()
+        .await
+        .map_err(|e| format!("cannot set up rocket: {e}"))?;
+    let result = command(&rocket, &args).await;
+
+    // server writes the journal in background, here it's written once the command is done
+    if let Some(mut journal) = journal {
+        let db = Db::fetch(&rocket).ok_or("database is not set up")?;
+        if let Err(error) = db_flush_journal(db, &mut journal).await {
+            error!("cannot write runner journal: {error}");
+        }
+    }
+    let output = result?;
+    if !output.is_empty() {
+        println!("{output}");
+    }
+    Ok(())
+}
+
+/// runs the command and returns what should be printed
+pub async fn command<P: Phase>(rocket: &Rocket<P>, args: &[&str]) -> Result<String, String> {
+    let db = Db::fetch(rocket).ok_or("database is not set up")?;
+    match args {
+        ["migrate"] => Ok("database is migrated".to_string()),
+        ["list-transactions"] | ["list-transactions", _] | ["list-transactions", _, _] => {
+            let status = args
+                .get(1)
+                .map(|s| TransactionStatus::parse(s))
+                .transpose()?;
+            let count = match args.get(2) {
+                Some(count) => count
+                    .parse()
+                    .map_err(|_| format!("'{count}' is not a number"))?,
+                None => DEFAULT_LIST_COUNT,
+            };
+            let mut db = acquire(db).await?;
+            let lines: Vec<String> = db_list_transactions(&mut db, status, Some(count))
+                .await?
+                .iter()
+                .map(|t| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        t.date,
+                        t.authority,
+                        t.status.as_str(),
+                        t.kind.as_str(),
+                        t.amount,
+                        t.names
+                    )
+                })
+                .collect();
+            Ok(lines.join("\n"))
+        }
+        ["show", authority] => {
+            let mut db = acquire(db).await?;
+            Ok(show(&db_transaction(&mut db, authority).await?))
+        }
+        ["re-verify", authority] => {
+            let mut db = acquire(db).await?;
+            let verified = checkout(rocket)?
+                .reverify(&mut db, authority)
+                .instrument(span("re-verify"))
+                .await?;
+            Ok(match verified {
+                Verified::Clients(names) => {
+                    format!("payment '{authority}' is verified and '{names}' are paid")
+                }
+                Verified::TopUp => {
+                    format!("payment '{authority}' is verified and wallet is topped up")
+                }
+            })
+        }
+        ["fulfill", authority] => {
+            let mut db = acquire(db).await?;
+            let names = checkout(rocket)?
+                .fulfill(&mut db, authority, &[])
+                .instrument(span("fulfill"))
+                .await?;
+            Ok(format!("'{names}' of payment '{authority}' are paid"))
+        }
+        ["export"] => {
+            let mut db = acquire(db).await?;
+            let transactions = db_list_transactions(&mut db, None, None).await?;
+            Ok(transactions_csv(&transactions))
+        }
+        ["issue-token", name, scopes] | ["issue-token", name, scopes, _] => {
+            let secret = issue(db, name, scopes, args.get(3)).await?;
+            audit_token(db, "token.issue", name).await;
+            Ok(secret)
+        }
+        ["token", "issue", name, scopes] | ["token", "issue", name, scopes, _] => {
+            let secret = issue(db, name, scopes, args.get(4)).await?;
+            audit_token(db, "token.issue", name).await;
+            Ok(secret)
+        }
+        ["token", "rotate", name] => {
+            let secret = rotate_token(db, name).await?;
+            audit_token(db, "token.rotate", name).await;
+            Ok(secret)
+        }
+        ["token", "revoke", name] => {
+            revoke_token(db, name).await?;
+            audit_token(db, "token.revoke", name).await;
+            Ok(format!("token '{name}' is revoked"))
+        }
+        ["token", "list"] => {
+            let mut lines = Vec::new();
+            for token in list_tokens(db).await? {
+                let scopes: Vec<&str> = token.scopes.iter().map(Scope::as_str).collect();
+                let state = match (token.revoked_at, token.expires_at) {
+                    (Some(revoked_at), _) => format!("revoked at {revoked_at}"),
+                    (None, Some(expires_at)) => format!("expires at {expires_at}"),
+                    (None, None) => "never expires".to_string(),
+                };
+                lines.push(format!(
+                    "{}\t{}.*\t{}\tcreated at {}\t{state}",
+                    token.name,
+                    token.key_id,
+                    scopes.join(","),
+                    token.created_at
+                ));
+            }
+            Ok(lines.join("\n"))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+async fn acquire(db: &SqlitePool) -> Result<PoolConnection<Sqlite>, String> {
+    db.acquire()
+        .await
+        .map_err(|e| format!("cannot connect to database: {e}"))
+}
+
+fn checkout<P: Phase>(rocket: &Rocket<P>) -> Result<Checkout<'_>, String> {
+    match (
+        rocket.state::<Arc<dyn Payment>>(),
+        rocket.state::<Arc<dyn Runner>>(),
+        rocket.state::<Notify>(),
+        rocket.state::<Webhooks>(),
+    ) {
+        (Some(payment), Some(runner), Some(notify), Some(webhooks)) => Ok(Checkout {
+            payment: payment.as_ref(),
+            runner: runner.as_ref(),
+            notify,
+            webhooks,
+            actor: Actor::named("cli"),
+        }),
+        _ => Err("payment flow is not set up".to_string()),
+    }
+}
+
+fn span(command: &str) -> tracing::Span {
+    info_span!("cli", command, authority = Empty, names = Empty)
+}
+
+async fn issue(
+    db: &SqlitePool,
+    name: &str,
+    scopes: &str,
+    days: Option<&&str>,
+) -> Result<String, String> {
+    let scopes = parse_scopes(scopes)?;
+    let expires_at = match days {
+        Some(days) => {
+            let days: i64 = days
+                .parse()
+                .map_err(|_| format!("'{days}' is not number of days"))?;
+            Some(Utc::now() + Duration::days(days))
+        }
+        None => None,
+    };
+    issue_token(db, name, &scopes, expires_at).await
+}
+
+/// secrets of tokens are not recorded, only their names
+async fn audit_token(db: &SqlitePool, action: &str, name: &str) {
+    let mut db = match acquire(db).await {
+        Ok(db) => db,
+        Err(error) => return error!("cannot record '{action}' in audit log: {error}"),
+    };
+    let change = Change {
+        action,
+        target: Some(&format!("token:{name}")),
+        ..Default::default()
+    };
+    audit::record(&mut db, &Actor::named("cli"), &change).await;
+}
+
+fn show(transaction: &Transaction) -> String {
+    let optional = |field: &Option<String>| field.clone().unwrap_or_else(|| "-".to_string());
+    [
+        ("authority", transaction.authority.clone()),
+        ("names", transaction.names.clone()),
+        ("amount", transaction.amount.to_string()),
+        ("kind", transaction.kind.as_str().to_string()),
+        ("status", transaction.status.as_str().to_string()),
+        ("ref_id", optional(&transaction.ref_id)),
+        ("gateway", optional(&transaction.gateway)),
+        (
+            "customer_id",
+            optional(&transaction.customer_id.map(|id| id.to_string())),
+        ),
+        ("referrer", optional(&transaction.referrer)),
+        ("date", transaction.date.to_string()),
+    ]
+    .iter()
+    .map(|(field, value)| format!("{field}\t{value}"))
+    .collect::<Vec<String>>()
+    .join("\n")
+}
+
+fn transactions_csv(transactions: &[Transaction]) -> String {
+    let mut csv =
+        "authority,names,amount,kind,status,ref_id,gateway,customer_id,referrer,date\n".to_string();
+    for t in transactions {
+        csv += &format!(
+            "{},{},{},{},{},{},{},{},{},{}\n",
+            csv_field(&t.authority),
+            csv_field(&t.names),
+            t.amount,
+            t.kind.as_str(),
+            t.status.as_str(),
+            csv_field(t.ref_id.as_deref().unwrap_or_default()),
+            csv_field(t.gateway.as_deref().unwrap_or_default()),
+            t.customer_id.map(|id| id.to_string()).unwrap_or_default(),
+            csv_field(t.referrer.as_deref().unwrap_or_default()),
+            t.date.to_rfc3339()
+        );
+    }
+    csv
+}
@@ -0,0 +1,78 @@
+use crate::runner::ClientConfig;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::{render::svg, QrCode};
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::serde::Serialize;
+
+const CONFIG_TOKEN_LENGTH: usize = 32;
+const QR_CODE_SIZE: u32 = 256;
+
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QrFormat {
+    Svg,
+    Png,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SharedLink {
+    link: String,
+    qr_code: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ClientConfigResult {
+    name: String,
+    links: Vec<SharedLink>,
+}
+
+impl ClientConfigResult {
+    pub fn from(config: ClientConfig, format: QrFormat) -> Result<Self, String> {
+        let links = config
+            .links
+            .into_iter()
+            .map(|link| {
+                let qr_code = render_qr_code(&link, format)?;
+                Ok(SharedLink { link, qr_code })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(ClientConfigResult {
+            name: config.name,
+            links,
+        })
+    }
+}
+
+pub fn generate_config_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CONFIG_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// svg is returned as is, png is returned as a base64 data url so both
+/// can be put directly in an `<img>` tag by the frontend
+pub fn render_qr_code(link: &str, format: QrFormat) -> Result<String, String> {
+    let code = QrCode::new(link.as_bytes()).map_err(|e| format!("cannot make qr code: {e}"))?;
+    match format {
+        QrFormat::Svg => Ok(code
+            .render::<svg::Color>()
+            .min_dimensions(QR_CODE_SIZE, QR_CODE_SIZE)
+            .build()),
+        QrFormat::Png => {
+            let image = code
+                .render::<Luma<u8>>()
+                .min_dimensions(QR_CODE_SIZE, QR_CODE_SIZE)
+                .build();
+            let mut png = Vec::new();
+            DynamicImage::ImageLuma8(image)
+                .write_to(&mut png, ImageOutputFormat::Png)
+                .map_err(|e| format!("cannot encode qr code: {e}"))?;
+            Ok(format!("data:image/png;base64,{}", BASE64.encode(png)))
+        }
+    }
+}
@@ -1,28 +1,125 @@
 use async_trait::async_trait;
 use rocket::{
-    fairing::{Fairing, Info, Kind},
-    http::Header,
-    Request, Response,
+    fairing::{self, Fairing, Info, Kind},
+    http::{Header, Method, Status},
+    Build, Request, Response, Rocket,
 };
+use std::env;
 
-pub struct Cors;
+const DEFAULT_ORIGINS: &str = "https://manjaliof.ts22.ir";
+const DEFAULT_METHODS: &str = "GET, POST, PUT, DELETE";
+const DEFAULT_HEADERS: &str = "Content-Type, Authorization, auth_token";
+const DEFAULT_MAX_AGE: u64 = 86400;
+
+/// answers browsers from allowed origins, listed origins are sent back with
+/// credentials allowed, others allowed by `*` get `*` and no credentials so
+/// they cannot use cookies of customers
+pub struct Cors {
+    /// `*` allows every origin
+    origins: Vec<String>,
+    methods: String,
+    headers: String,
+    max_age: u64,
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+impl Cors {
+    pub fn new(origins: &[&str], methods: &str, headers: &str, max_age: u64) -> Self {
+        Cors {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: methods.to_string(),
+            headers: headers.to_string(),
+            max_age,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let origins = env_or("MANJALIOF_CORS_ORIGINS", DEFAULT_ORIGINS);
+        let origins: Vec<&str> = origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/'))
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        let max_age = env::var("MANJALIOF_CORS_MAX_AGE")
+            .map(|max_age| {
+                max_age
+                    .parse()
+                    .expect("environment variable 'MANJALIOF_CORS_MAX_AGE' is not a number")
+            })
+            .unwrap_or(DEFAULT_MAX_AGE);
+        Self::new(
+            &origins,
+            &env_or("MANJALIOF_CORS_METHODS", DEFAULT_METHODS),
+            &env_or("MANJALIOF_CORS_HEADERS", DEFAULT_HEADERS),
+            max_age,
+        )
+    }
+
+    /// `Access-Control-Allow-Origin` for `origin` and whether it can send
+    /// credentials, `None` when it's not allowed
+    fn allow(&self, origin: &str) -> Option<(String, bool)> {
+        if self.origins.iter().any(|allowed| allowed == origin) {
+            Some((origin.to_string(), true))
+        } else if self.origins.iter().any(|allowed| allowed == "*") {
+            Some(("*".to_string(), false))
+        } else {
+            None
+        }
+    }
+}
+
+/// preflight requests are answered by [`Cors`], this only makes sure they
+/// don't end up as 404
+#[options("/<_..>")]
+fn preflight() -> Status {
+    Status::NoContent
+}
 
 #[async_trait]
 impl Fairing for Cors {
     fn info(&self) -> Info {
         Info {
             name: "Add CORS headers to responses",
-            kind: Kind::Response,
+            kind: Kind::Ignite | Kind::Response,
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new(
-            "Access-Control-Allow-Origin",
-            "https://manjaliof.ts22.ir",
-        ));
-        response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET"));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        Ok(rocket.mount("/", routes![preflight]))
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.adjoin_header(Header::new("Vary", "Origin"));
+        let (origin, credentials) = match request
+            .headers()
+            .get_one("Origin")
+            .and_then(|origin| self.allow(origin))
+        {
+            Some(allowed) => allowed,
+            None => return,
+        };
+
+        response.set_header(Header::new("Access-Control-Allow-Origin", origin));
+        if credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+        response.set_header(Header::new("Access-Control-Expose-Headers", "Retry-After"));
+        if request.method() == Method::Options {
+            response.set_header(Header::new(
+                "Access-Control-Allow-Methods",
+                self.methods.clone(),
+            ));
+            response.set_header(Header::new(
+                "Access-Control-Allow-Headers",
+                self.headers.clone(),
+            ));
+            response.set_header(Header::new(
+                "Access-Control-Max-Age",
+                self.max_age.to_string(),
+            ));
+        }
     }
 }
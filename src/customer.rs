@@ -0,0 +1,391 @@
+use crate::{
+    audit::{self, Actor, Change},
+    db::{
+        db_add_customer, db_add_session, db_client_owner, db_customer_clients,
+        db_customer_transactions, db_delete_session, db_find_customer_login,
+        db_find_session_customer, db_link_client, db_unlink_client, Db, TransactionRecord,
+    },
+    rate_limit::RateLimit,
+    response::RequestResult,
+    token::{hash_secret, random_string, AdminToken, AdminWrite, CustomerToken},
+};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use rocket::{
+    fairing::AdHoc,
+    http::{Cookie, CookieJar, SameSite, Status},
+    request::{FromRequest, Outcome, Request},
+    serde::{json::Json, Deserialize, Serialize},
+};
+use rocket_db_pools::{sqlx::SqliteConnection, Connection, Database};
+use std::{env, fmt};
+use tracing::{error, info};
+
+pub const SESSION_COOKIE: &str = "customer_session";
+const SESSION_LENGTH: usize = 32;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+lazy_static! {
+    static ref SESSION_DAYS: i64 = env::var("MANJALIOF_SESSION_DAYS")
+        .map(|days| days
+            .parse()
+            .expect("environment variable 'MANJALIOF_SESSION_DAYS' is not a number"))
+        .unwrap_or(30);
+    /// when set, payments can only be created by logged in customers
+    static ref CUSTOMER_REQUIRED: bool = env::var("MANJALIOF_CUSTOMER_REQUIRED")
+        .map(|required| required != "false")
+        .unwrap_or(true);
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Customer {
+    pub id: i64,
+    pub phone: Option<String>,
+    pub telegram_id: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Missing,
+    Invalid,
+    Database(String),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Missing => write!(f, "customer is not logged in"),
+            SessionError::Invalid => write!(f, "session is expired or not valid"),
+            SessionError::Database(error) => write!(f, "cannot check session: {error}"),
+        }
+    }
+}
+
+/// customer that is logged in, its session is sent in `customer_session` cookie
+#[async_trait]
+impl<'r> FromRequest<'r> for Customer {
+    type Error = SessionError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let session = match request.cookies().get(SESSION_COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => return Outcome::Failure((Status::Unauthorized, SessionError::Missing)),
+        };
+        let db = match Db::fetch(request.rocket()) {
+            Some(db) => db,
+            None => {
+                let error = SessionError::Database("database is not set up".to_string());
+                return Outcome::Failure((Status::InternalServerError, error));
+            }
+        };
+
+        match db_find_session_customer(db, &hash_secret(&session), Utc::now()).await {
+            Ok(Some(customer)) => Outcome::Success(customer),
+            Ok(None) => Outcome::Failure((Status::Unauthorized, SessionError::Invalid)),
+            Err(error) => {
+                let error = SessionError::Database(error);
+                error!("{error}");
+                Outcome::Failure((Status::InternalServerError, error))
+            }
+        }
+    }
+}
+
+/// checks that `names` are linked to the customer, payments without a
+/// customer are only allowed when `MANJALIOF_CUSTOMER_REQUIRED` is `false`
+/// and none of `names` is linked to a customer, returns id of the customer
+pub async fn authorize_clients(
+    db: &mut SqliteConnection,
+    customer: Result<Customer, SessionError>,
+    names: &[String],
+) -> Result<Option<i64>, String> {
+    let customer = match customer {
+        Ok(customer) => customer,
+        Err(SessionError::Missing) if !*CUSTOMER_REQUIRED => {
+            for name in names {
+                if db_client_owner(db, name).await?.is_some() {
+                    return Err(format!(
+                        "client '{name}' belongs to a customer, log in first"
+                    ));
+                }
+            }
+            return Ok(None);
+        }
+        Err(error) => return Err(error.to_string()),
+    };
+
+    let linked = db_customer_clients(db, customer.id).await?;
+    if let Some(name) = names.iter().find(|name| !linked.contains(name)) {
+        return Err(format!("client '{name}' is not linked to customer"));
+    }
+    Ok(Some(customer.id))
+}
+
+fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| format!("cannot make salt: {e}"))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("cannot hash password: {e}"))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// mounts customer endpoints, they all need the storefront token too
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("customers", |rocket| async {
+        rocket
+            .mount(
+                "/customer",
+                routes![register, login, logout, me, transactions],
+            )
+            .mount("/admin", routes![link_client, unlink_client])
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Credentials {
+    phone: Option<String>,
+    telegram_id: Option<String>,
+    password: String,
+}
+
+impl Credentials {
+    fn login(&self) -> Result<(Option<&str>, Option<&str>), String> {
+        let phone = self.phone.as_deref().filter(|phone| !phone.is_empty());
+        let telegram_id = self.telegram_id.as_deref().filter(|id| !id.is_empty());
+        match (phone, telegram_id) {
+            (None, None) => Err("phone or telegram id is required".to_string()),
+            login => Ok(login),
+        }
+    }
+}
+
+#[post("/register", data = "<args>")]
+async fn register(
+    _limit: RateLimit,
+    _token: CustomerToken,
+    actor: Actor,
+    mut db: Connection<Db>,
+    args: Json<Credentials>,
+) -> Json<RequestResult<Customer>> {
+    let (phone, telegram_id) = try_in_request!(args.login());
+    try_in_request!((args.password.chars().count() >= MIN_PASSWORD_LENGTH)
+        .then_some(())
+        .ok_or(format!(
+            "password should be at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+
+    let password_hash = try_in_request!(hash_password(&args.password));
+    let id = try_in_request!(db_add_customer(&mut db, phone, telegram_id, &password_hash)
+        .await
+        .map_err(|e| format!("cannot add customer: {e}")));
+    let change = Change {
+        action: "customer.register",
+        target: Some(&format!("customer:{id}")),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(Customer {
+            id,
+            phone: phone.map(str::to_string),
+            telegram_id: telegram_id.map(str::to_string),
+        }),
+    })
+}
+
+#[post("/login", data = "<args>")]
+async fn login(
+    _limit: RateLimit,
+    _token: CustomerToken,
+    actor: Actor,
+    mut db: Connection<Db>,
+    cookies: &CookieJar<'_>,
+    args: Json<Credentials>,
+) -> Json<RequestResult<()>> {
+    let (phone, telegram_id) = try_in_request!(args.login());
+    let found = try_in_request!(db_find_customer_login(&mut db, phone, telegram_id)
+        .await
+        .map_err(|e| format!("cannot find customer: {e}")));
+
+    let customer = match found {
+        Some((customer, hash)) if verify_password(&args.password, &hash) => customer,
+        _ => try_in_request!(Err("login or password is wrong".to_string())),
+    };
+
+    let session = random_string(SESSION_LENGTH);
+    let expires_at = Utc::now() + Duration::days(*SESSION_DAYS);
+    try_in_request!(
+        db_add_session(&mut db, customer.id, &hash_secret(&session), expires_at)
+            .await
+            .map_err(|e| format!("cannot add session: {e}"))
+    );
+    let change = Change {
+        action: "customer.login",
+        target: Some(&format!("customer:{}", customer.id)),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+
+    cookies.add(
+        Cookie::build(SESSION_COOKIE, session)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(rocket::time::Duration::days(*SESSION_DAYS))
+            .finish(),
+    );
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
+
+#[post("/logout")]
+async fn logout(
+    _token: CustomerToken,
+    actor: Actor,
+    customer: Result<Customer, SessionError>,
+    mut db: Connection<Db>,
+    cookies: &CookieJar<'_>,
+) -> Json<RequestResult> {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        let hash = hash_secret(cookie.value());
+        try_in_request!(db_delete_session(&mut db, &hash)
+            .await
+            .map_err(|e| format!("cannot delete session: {e}")));
+    }
+    if let Ok(customer) = customer {
+        let change = Change {
+            action: "customer.logout",
+            target: Some(&format!("customer:{}", customer.id)),
+            ..Default::default()
+        };
+        audit::record(&mut db, &actor, &change).await;
+    }
+    cookies.remove(Cookie::named(SESSION_COOKIE));
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CustomerData {
+    #[serde(flatten)]
+    customer: Customer,
+    clients: Vec<String>,
+}
+
+#[get("/me")]
+async fn me(
+    _token: CustomerToken,
+    customer: Customer,
+    mut db: Connection<Db>,
+) -> Json<RequestResult<CustomerData>> {
+    let clients = try_in_request!(db_customer_clients(&mut db, customer.id)
+        .await
+        .map_err(|e| format!("cannot find clients: {e}")));
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(CustomerData { customer, clients }),
+    })
+}
+
+#[get("/transactions")]
+async fn transactions(
+    _token: CustomerToken,
+    customer: Customer,
+    mut db: Connection<Db>,
+) -> Json<RequestResult<Vec<TransactionRecord>>> {
+    let transactions = try_in_request!(db_customer_transactions(&mut db, customer.id)
+        .await
+        .map_err(|e| format!("cannot find transactions: {e}")));
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(transactions),
+    })
+}
+
+#[put("/customers/<id>/clients/<name>")]
+async fn link_client(
+    token: AdminToken<AdminWrite>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    id: i64,
+    name: &str,
+) -> Json<RequestResult> {
+    try_in_request!(db_link_client(&mut db, id, name)
+        .await
+        .map_err(|e| format!("cannot link client: {e}")));
+    let change = Change {
+        action: "customer.link_client",
+        clients: Some(name),
+        target: Some(&format!("customer:{id}")),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+    info!(
+        "client '{name}' is linked to customer '{id}' by token '{}'",
+        token.caller.name
+    );
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
+
+#[delete("/customers/<id>/clients/<name>")]
+async fn unlink_client(
+    token: AdminToken<AdminWrite>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    id: i64,
+    name: &str,
+) -> Json<RequestResult> {
+    let unlinked = try_in_request!(db_unlink_client(&mut db, id, name)
+        .await
+        .map_err(|e| format!("cannot unlink client: {e}")));
+    try_in_request!(unlinked
+        .then_some(())
+        .ok_or(format!("client '{name}' is not linked to customer '{id}'")));
+    let change = Change {
+        action: "customer.unlink_client",
+        clients: Some(name),
+        target: Some(&format!("customer:{id}")),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+    info!(
+        "client '{name}' is unlinked from customer '{id}' by token '{}'",
+        token.caller.name
+    );
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
@@ -1,30 +1,123 @@
-use chrono::Utc;
+use crate::{
+    audit::{Actor, AuditFilter, Change},
+    customer::Customer,
+    ledger::{self, Movement},
+    payment::{PaymentReceipt, AUTHORITY_TTL},
+    runner::{
+        journal::{JournalEntry, JournalReceiver},
+        router::{ServerSpec, Transport},
+    },
+    token::{parse_scopes, Scope},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use rocket::{
     fairing::{self, AdHoc},
     Build, Rocket,
 };
 use rocket_db_pools::{
-    sqlx::{self, Executor, Row},
+    sqlx::{self, sqlite::SqliteRow, Acquire, Executor, Row, SqliteConnection, SqlitePool},
     Connection, Database,
 };
+use serde::Serialize;
+use tracing::error;
 
 const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format(DATETIME_FORMAT).to_string()
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    NaiveDateTime::parse_from_str(date, DATETIME_FORMAT)
+        .map(|date| DateTime::from_utc(date, Utc))
+        .map_err(|e| format!("date '{date}' is not valid: {e}"))
+}
+
+/// returns sql errors and times the query for metrics
 macro_rules! try_sql {
-    ($expr:expr) => {
-        match $expr {
+    ($expr:expr) => {{
+        let started = std::time::Instant::now();
+        let result = $expr;
+        crate::metrics::observe_db_query(started.elapsed());
+        match result {
             Ok(smth) => smth,
             Err(error) => return Err(format!("sql error: {error}")),
         }
-    };
+    }};
 }
 
 #[derive(Database)]
 #[database("sqlitedb")]
 pub struct Db(sqlx::SqlitePool);
 
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Created,
+    Verified,
+    Fulfilled,
+    /// an admin took care of it outside of the payment flow
+    Resolved,
+    /// authority was not paid in time, a new payment should be created
+    Expired,
+    /// bought from wallet but none of its clients could be made paid, so
+    /// price is given back to wallet
+    Refunded,
+}
+
+impl TransactionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionStatus::Created => "created",
+            TransactionStatus::Verified => "verified",
+            TransactionStatus::Fulfilled => "fulfilled",
+            TransactionStatus::Resolved => "resolved",
+            TransactionStatus::Expired => "expired",
+            TransactionStatus::Refunded => "refunded",
+        }
+    }
+
+    pub fn parse(status: &str) -> Result<Self, String> {
+        match status {
+            "created" => Ok(TransactionStatus::Created),
+            "verified" => Ok(TransactionStatus::Verified),
+            "fulfilled" => Ok(TransactionStatus::Fulfilled),
+            "resolved" => Ok(TransactionStatus::Resolved),
+            "expired" => Ok(TransactionStatus::Expired),
+            "refunded" => Ok(TransactionStatus::Refunded),
+            _ => Err(format!("transaction status '{status}' is not valid")),
+        }
+    }
+}
+
+/// what is bought with a transaction
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    Clients,
+    /// credits the wallet of its customer
+    TopUp,
+}
+
+impl TransactionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::Clients => "clients",
+            TransactionKind::TopUp => "top_up",
+        }
+    }
+
+    fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "clients" => Ok(TransactionKind::Clients),
+            "top_up" => Ok(TransactionKind::TopUp),
+            _ => Err(format!("transaction kind '{kind}' is not valid")),
+        }
+    }
+}
+
 impl Db {
-    pub fn new() -> AdHoc {
+    pub fn stage() -> AdHoc {
         AdHoc::on_ignite("transaction setup", |rocket| async {
             rocket
                 .attach(Self::init())
@@ -35,19 +128,8 @@ impl Db {
     async fn setup_tables(rocket: Rocket<Build>) -> fairing::Result {
         match Db::fetch(&rocket) {
             Some(db) => {
-                let res = db
-                    .execute(
-                        "CREATE TABLE IF NOT EXISTS transactions (
-                            authority TEXT PRIMARY KEY,
-                            name TEXT NOT NULL,
-                            amount UNSIGNED INTEGER NOT NULL,
-                            date TEXT NOT NULL
-                        )",
-                    )
-                    .await;
-
-                if let Err(error) = res {
-                    error!("cannot create tables: sql error: {error}");
+                if let Err(error) = Self::migrate(db).await {
+                    error!("cannot create tables: {error}");
                     return Err(rocket);
                 }
                 Ok(rocket)
@@ -55,39 +137,1507 @@ impl Db {
             None => Err(rocket),
         }
     }
+
+    async fn migrate(db: &SqlitePool) -> Result<(), String> {
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS transactions (
+                    authority TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    amount UNSIGNED INTEGER NOT NULL,
+                    date TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+
+        add_column(
+            db,
+            "transactions",
+            "status",
+            "TEXT NOT NULL DEFAULT 'created'",
+        )
+        .await?;
+        add_column(db, "transactions", "config_token", "TEXT").await?;
+        add_column(db, "transactions", "ref_id", "TEXT").await?;
+        add_column(db, "transactions", "customer_id", "INTEGER").await?;
+        add_column(
+            db,
+            "transactions",
+            "kind",
+            "TEXT NOT NULL DEFAULT 'clients'",
+        )
+        .await?;
+        add_column(db, "transactions", "gateway", "TEXT").await?;
+        add_column(db, "transactions", "referrer", "TEXT").await?;
+        add_column(db, "transactions", "paid_names", "TEXT NOT NULL DEFAULT ''").await?;
+        add_column(db, "transactions", "failed_status", "TEXT").await?;
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS runner_journal (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    command TEXT NOT NULL,
+                    args TEXT NOT NULL,
+                    exit_code INTEGER,
+                    stdout TEXT NOT NULL,
+                    stderr TEXT NOT NULL,
+                    duration_ms UNSIGNED INTEGER NOT NULL,
+                    date TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS servers (
+                    name TEXT PRIMARY KEY,
+                    transport TEXT NOT NULL,
+                    region TEXT,
+                    capacity UNSIGNED INTEGER
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS tokens (
+                    name TEXT PRIMARY KEY,
+                    key_id TEXT NOT NULL UNIQUE,
+                    hash TEXT NOT NULL,
+                    scopes TEXT NOT NULL,
+                    expires_at TEXT,
+                    revoked_at TEXT,
+                    created_at TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS customers (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    phone TEXT UNIQUE,
+                    telegram_id TEXT UNIQUE,
+                    password_hash TEXT NOT NULL,
+                    created_at TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+
+        // a client belongs to at most one customer
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS customer_clients (
+                    name TEXT PRIMARY KEY,
+                    customer_id INTEGER NOT NULL REFERENCES customers(id)
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS customer_sessions (
+                    hash TEXT PRIMARY KEY,
+                    customer_id INTEGER NOT NULL REFERENCES customers(id),
+                    expires_at TEXT NOT NULL,
+                    created_at TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS wallets (
+                    customer_id INTEGER PRIMARY KEY REFERENCES customers(id),
+                    balance INTEGER NOT NULL DEFAULT 0 CHECK (balance >= 0)
+                )",
+            )
+            .await
+        );
+
+        // every change of a wallet, credits are positive and debits negative
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS wallet_ledger (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    customer_id INTEGER NOT NULL REFERENCES customers(id),
+                    amount INTEGER NOT NULL,
+                    balance INTEGER NOT NULL,
+                    kind TEXT NOT NULL,
+                    reference TEXT NOT NULL,
+                    date TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS ledger_entries (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    kind TEXT NOT NULL,
+                    reference TEXT NOT NULL,
+                    date TEXT NOT NULL,
+                    UNIQUE (kind, reference)
+                )",
+            )
+            .await
+        );
+
+        // debits are positive and credits negative, postings of an entry add up to zero
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS ledger_postings (
+                    entry_id INTEGER NOT NULL REFERENCES ledger_entries(id),
+                    account TEXT NOT NULL,
+                    amount INTEGER NOT NULL
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    url TEXT NOT NULL,
+                    event TEXT NOT NULL,
+                    payload TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    attempts UNSIGNED INTEGER NOT NULL DEFAULT 0,
+                    next_attempt_at TEXT NOT NULL,
+                    last_error TEXT,
+                    created_at TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+
+        try_sql!(
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS audit_log (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    actor TEXT NOT NULL,
+                    ip TEXT,
+                    action TEXT NOT NULL,
+                    authority TEXT,
+                    clients TEXT,
+                    before TEXT,
+                    after TEXT,
+                    note TEXT,
+                    created_at TEXT NOT NULL
+                )",
+            )
+            .await
+        );
+        add_column(db, "audit_log", "target", "TEXT").await?;
+        for action in ["update", "delete"] {
+            let trigger = format!(
+                "CREATE TRIGGER IF NOT EXISTS audit_log_no_{action} BEFORE {action} ON audit_log
+                    BEGIN SELECT RAISE(ABORT, 'audit log is append only'); END"
+            );
+            try_sql!(db.execute(trigger.as_str()).await);
+        }
+
+        for table in ["ledger_entries", "ledger_postings"] {
+            for action in ["update", "delete"] {
+                let trigger = format!(
+                    "CREATE TRIGGER IF NOT EXISTS {table}_no_{action} BEFORE {action} ON {table}
+                        BEGIN SELECT RAISE(ABORT, 'ledger is append only'); END"
+                );
+                try_sql!(db.execute(trigger.as_str()).await);
+            }
+        }
+        Ok(())
+    }
+
+    /// drains the runner journal into `runner_journal` table for as long as
+    /// rocket is running
+    pub fn journal_writer(mut journal: JournalReceiver) -> AdHoc {
+        AdHoc::on_liftoff("runner journal writer", |rocket| {
+            Box::pin(async move {
+                let db = match Db::fetch(rocket) {
+                    Some(db) => db.0.clone(),
+                    None => return error!("cannot write runner journal: database is not set up"),
+                };
+
+                rocket::tokio::spawn(async move {
+                    while let Some(entry) = journal.recv().await {
+                        if let Err(error) = db_add_journal_entry(&db, &entry).await {
+                            error!("cannot write runner journal {entry:?}: {error}");
+                        }
+                    }
+                });
+            })
+        })
+    }
+}
+
+async fn add_column(
+    db: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), String> {
+    let query = sqlx::query("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name=?")
+        .bind(table)
+        .bind(column);
+    let row = try_sql!(db.fetch_one(query).await);
+    let exists: i64 = row.get(0);
+
+    if exists == 0 {
+        let statement = format!("ALTER TABLE {table} ADD COLUMN {column} {definition}");
+        try_sql!(db.execute(statement.as_str()).await);
+    }
+    Ok(())
 }
 
 pub async fn db_add_transaction(
-    db: &mut Connection<Db>,
+    db: &mut SqliteConnection,
     authority: &str,
     name: &str,
     amount: u32,
+    config_token: &str,
+    customer_id: Option<i64>,
+    referrer: Option<&str>,
 ) -> Result<(), String> {
     let now_date = Utc::now().format(DATETIME_FORMAT).to_string();
+    let query = sqlx::query(
+        "INSERT INTO transactions
+            (authority, name, amount, date, status, config_token, customer_id, referrer)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(authority)
+    .bind(name)
+    .bind(amount)
+    .bind(now_date)
+    .bind(TransactionStatus::Created.as_str())
+    .bind(config_token)
+    .bind(customer_id)
+    .bind(referrer);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+pub struct PendingTransaction {
+    pub names: String,
+    pub amount: u32,
+    pub kind: TransactionKind,
+    pub status: TransactionStatus,
+    /// when authority is requested
+    pub date: DateTime<Utc>,
+}
+
+pub async fn db_find_transaction(
+    db: &mut SqliteConnection,
+    authority: &str,
+) -> Result<PendingTransaction, String> {
+    let query = sqlx::query(
+        "SELECT name, amount, kind, status, date FROM transactions WHERE authority=? LIMIT 1",
+    )
+    .bind(authority);
+    let rows = try_sql!(db.fetch_all(query).await);
+
+    if rows.is_empty() {
+        return Err("authority not exists".to_string());
+    }
+
+    let row = rows.first().unwrap();
+    let (kind, status, date): (String, String, String) = (row.get(2), row.get(3), row.get(4));
+    Ok(PendingTransaction {
+        names: row.get(0),
+        amount: row.get(1),
+        kind: TransactionKind::parse(&kind)?,
+        status: TransactionStatus::parse(&status)?,
+        date: parse_date(&date)?,
+    })
+}
+
+/// everything that is kept of a transaction, for operators
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub authority: String,
+    pub names: String,
+    pub amount: u32,
+    pub kind: TransactionKind,
+    pub status: TransactionStatus,
+    pub ref_id: Option<String>,
+    pub gateway: Option<String>,
+    pub customer_id: Option<i64>,
+    pub referrer: Option<String>,
+    pub date: DateTime<Utc>,
+}
+
+/// status of a transaction, `created` ones that cannot be paid anymore are
+/// shown `expired` before anyone verifies them, the bound value is
+/// `payable_since()`
+const STATUS_SQL: &str = "CASE WHEN status='created' AND date<? THEN 'expired' ELSE status END";
+
+/// columns that `transaction_from_row` reads, status is `STATUS_SQL`
+fn transaction_columns() -> String {
+    format!(
+        "authority, name, amount, kind, {STATUS_SQL}, ref_id, gateway, customer_id, referrer, date"
+    )
+}
+
+/// oldest date of a `created` transaction that can still be paid
+fn payable_since() -> String {
+    format_date(Utc::now() - *AUTHORITY_TTL)
+}
+
+fn transaction_from_row(row: &SqliteRow) -> Result<Transaction, String> {
+    let (kind, status, date): (String, String, String) = (row.get(3), row.get(4), row.get(9));
+    Ok(Transaction {
+        authority: row.get(0),
+        names: row.get(1),
+        amount: row.get(2),
+        kind: TransactionKind::parse(&kind)?,
+        status: TransactionStatus::parse(&status)?,
+        ref_id: row.get(5),
+        gateway: row.get(6),
+        customer_id: row.get(7),
+        referrer: row.get(8),
+        date: parse_date(&date)?,
+    })
+}
+
+pub async fn db_transaction(
+    db: &mut SqliteConnection,
+    authority: &str,
+) -> Result<Transaction, String> {
+    let query = format!(
+        "SELECT {} FROM transactions WHERE authority=?",
+        transaction_columns()
+    );
+    let query = sqlx::query(&query).bind(payable_since()).bind(authority);
+    match try_sql!(db.fetch_optional(query).await) {
+        Some(row) => transaction_from_row(&row),
+        None => Err(format!("authority '{authority}' doesn't exist")),
+    }
+}
+
+/// newest transactions first, all of them when `limit` is `None`
+pub async fn db_list_transactions(
+    db: &mut SqliteConnection,
+    status: Option<TransactionStatus>,
+    limit: Option<u32>,
+) -> Result<Vec<Transaction>, String> {
+    let query = format!(
+        "SELECT {} FROM transactions
+            WHERE ? IS NULL OR {STATUS_SQL}=? ORDER BY date DESC, rowid DESC LIMIT ?",
+        transaction_columns()
+    );
+    let status = status.map(|status| status.as_str());
+    let query = sqlx::query(&query)
+        .bind(payable_since())
+        .bind(status)
+        .bind(payable_since())
+        .bind(status)
+        .bind(limit.map_or(-1, i64::from));
+    let rows = try_sql!(db.fetch_all(query).await);
+    rows.iter().map(transaction_from_row).collect()
+}
+
+pub async fn db_set_status(
+    db: &mut SqliteConnection,
+    authority: &str,
+    status: TransactionStatus,
+) -> Result<(), String> {
+    let query = sqlx::query("UPDATE transactions SET status=? WHERE authority=?")
+        .bind(status.as_str())
+        .bind(authority);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+/// clients of the transaction that runner has made paid
+pub async fn db_paid_clients(
+    db: &mut SqliteConnection,
+    authority: &str,
+) -> Result<Vec<String>, String> {
     let query =
-        sqlx::query("INSERT INTO transactions (authority, name, amount, date) VALUES (?, ?, ?, ?)")
-            .bind(authority)
-            .bind(name)
-            .bind(amount)
-            .bind(now_date);
+        sqlx::query("SELECT paid_names FROM transactions WHERE authority=?").bind(authority);
+    let paid: String = match try_sql!(db.fetch_optional(query).await) {
+        Some(row) => row.get(0),
+        None => return Err(format!("authority '{authority}' doesn't exist")),
+    };
+    Ok(paid
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+pub async fn db_add_paid_client(
+    db: &mut SqliteConnection,
+    authority: &str,
+    name: &str,
+) -> Result<(), String> {
+    let query = sqlx::query(
+        "UPDATE transactions
+            SET paid_names = CASE WHEN paid_names='' THEN ?1 ELSE paid_names || ',' || ?1 END
+            WHERE authority=?2",
+    )
+    .bind(name)
+    .bind(authority);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+/// remembers that failure of the transaction in `status` is reported, returns
+/// false when it's already reported in the same status
+pub async fn db_report_failure(
+    db: &mut SqliteConnection,
+    authority: &str,
+    status: TransactionStatus,
+) -> Result<bool, String> {
+    let query = sqlx::query(
+        "UPDATE transactions SET failed_status=?1
+            WHERE authority=?2 AND (failed_status IS NULL OR failed_status!=?1)",
+    )
+    .bind(status.as_str())
+    .bind(authority);
+    Ok(try_sql!(db.execute(query).await).rows_affected() > 0)
+}
+
+pub async fn db_set_verified(
+    db: &mut SqliteConnection,
+    authority: &str,
+    receipt: &PaymentReceipt,
+) -> Result<(), String> {
+    let query =
+        sqlx::query("UPDATE transactions SET status=?, ref_id=?, gateway=? WHERE authority=?")
+            .bind(TransactionStatus::Verified.as_str())
+            .bind(&receipt.ref_id)
+            .bind(&receipt.gateway)
+            .bind(authority);
     try_sql!(db.execute(query).await);
     Ok(())
 }
 
-pub async fn db_find_name(
+pub async fn db_find_fulfilled_names(
     db: &mut Connection<Db>,
     authority: &str,
-) -> Result<(String, u32), String> {
-    let query = sqlx::query("SELECT name, amount FROM transactions WHERE authority=? LIMIT 1")
+    config_token: &str,
+) -> Result<String, String> {
+    let query = sqlx::query(
+        "SELECT name, status FROM transactions WHERE authority=? AND config_token=? LIMIT 1",
+    )
+    .bind(authority)
+    .bind(config_token);
+    let rows = try_sql!(db.fetch_all(query).await);
+
+    let row = match rows.first() {
+        Some(row) => row,
+        None => return Err("authority or token is wrong".to_string()),
+    };
+
+    let name: String = row.get(0);
+    let status: String = row.get(1);
+    if status != TransactionStatus::Fulfilled.as_str() {
+        return Err(format!("transaction is not fulfilled yet, it's '{status}'"));
+    }
+    Ok(name)
+}
+
+/// writes entries that are already in `journal`, for when there is no
+/// [`Db::journal_writer`] running
+pub async fn db_flush_journal(
+    db: &SqlitePool,
+    journal: &mut JournalReceiver,
+) -> Result<(), String> {
+    while let Ok(entry) = journal.try_recv() {
+        db_add_journal_entry(db, &entry).await?;
+    }
+    Ok(())
+}
+
+async fn db_add_journal_entry(db: &SqlitePool, entry: &JournalEntry) -> Result<(), String> {
+    let args = serde_json::to_string(&entry.args).unwrap();
+    let query = sqlx::query(
+        "INSERT INTO runner_journal (command, args, exit_code, stdout, stderr, duration_ms, date)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&entry.command)
+    .bind(args)
+    .bind(entry.exit_code)
+    .bind(&entry.stdout)
+    .bind(&entry.stderr)
+    .bind(entry.duration.as_millis() as i64)
+    .bind(entry.date.format(DATETIME_FORMAT).to_string());
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+/// runs a query that touches no table, to see if database answers
+pub async fn db_ping(db: &SqlitePool) -> Result<(), String> {
+    try_sql!(db.execute("SELECT 1").await);
+    Ok(())
+}
+
+/// adds servers that don't exist yet, servers changed by admins are kept as is
+pub async fn db_seed_servers(db: &SqlitePool, servers: &[ServerSpec]) -> Result<(), String> {
+    for server in servers {
+        let query = sqlx::query(
+            "INSERT OR IGNORE INTO servers (name, transport, region, capacity) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&server.name)
+        .bind(serde_json::to_string(&server.transport).unwrap())
+        .bind(&server.region)
+        .bind(server.capacity);
+        try_sql!(db.execute(query).await);
+    }
+    Ok(())
+}
+
+pub async fn db_list_servers(db: &SqlitePool) -> Result<Vec<ServerSpec>, String> {
+    let query = sqlx::query("SELECT name, transport, region, capacity FROM servers ORDER BY name");
+    let rows = try_sql!(db.fetch_all(query).await);
+
+    let mut servers = Vec::new();
+    for row in rows {
+        let name: String = row.get(0);
+        let transport: String = row.get(1);
+        let transport: Transport = serde_json::from_str(&transport)
+            .map_err(|e| format!("transport of server '{name}' is not valid: {e}"))?;
+        servers.push(ServerSpec {
+            name,
+            region: row.get(2),
+            capacity: row.get(3),
+            transport,
+        });
+    }
+    Ok(servers)
+}
+
+pub async fn db_save_server(db: &mut Connection<Db>, server: &ServerSpec) -> Result<(), String> {
+    let query = sqlx::query(
+        "INSERT OR REPLACE INTO servers (name, transport, region, capacity) VALUES (?, ?, ?, ?)",
+    )
+    .bind(&server.name)
+    .bind(serde_json::to_string(&server.transport).unwrap())
+    .bind(&server.region)
+    .bind(server.capacity);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+pub async fn db_delete_server(db: &mut Connection<Db>, name: &str) -> Result<bool, String> {
+    let query = sqlx::query("DELETE FROM servers WHERE name=?").bind(name);
+    let result = try_sql!(db.execute(query).await);
+    Ok(result.rows_affected() > 0)
+}
+
+#[derive(Clone, Debug)]
+pub struct StoredToken {
+    pub name: String,
+    pub key_id: String,
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+const TOKEN_COLUMNS: &str = "name, key_id, hash, scopes, expires_at, revoked_at, created_at";
+
+fn token_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<StoredToken, String> {
+    let scopes: String = row.get(3);
+    let expires_at: Option<String> = row.get(4);
+    let revoked_at: Option<String> = row.get(5);
+    let created_at: String = row.get(6);
+    Ok(StoredToken {
+        name: row.get(0),
+        key_id: row.get(1),
+        hash: row.get(2),
+        scopes: parse_scopes(&scopes)?,
+        expires_at: expires_at.as_deref().map(parse_date).transpose()?,
+        revoked_at: revoked_at.as_deref().map(parse_date).transpose()?,
+        created_at: parse_date(&created_at)?,
+    })
+}
+
+pub async fn db_add_token(
+    db: &SqlitePool,
+    name: &str,
+    key_id: &str,
+    hash: &str,
+    scopes: &[Scope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), String> {
+    let scopes: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+    let query = sqlx::query(
+        "INSERT INTO tokens (name, key_id, hash, scopes, expires_at, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(name)
+    .bind(key_id)
+    .bind(hash)
+    .bind(scopes.join(","))
+    .bind(expires_at.map(format_date))
+    .bind(format_date(Utc::now()));
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+pub async fn db_find_token(db: &SqlitePool, key_id: &str) -> Result<Option<StoredToken>, String> {
+    let query = format!("SELECT {TOKEN_COLUMNS} FROM tokens WHERE key_id=? LIMIT 1");
+    let query = sqlx::query(&query).bind(key_id);
+    let row = try_sql!(db.fetch_optional(query).await);
+    row.as_ref().map(token_from_row).transpose()
+}
+
+pub async fn db_list_tokens(db: &SqlitePool) -> Result<Vec<StoredToken>, String> {
+    let query = format!("SELECT {TOKEN_COLUMNS} FROM tokens ORDER BY created_at");
+    let rows = try_sql!(db.fetch_all(sqlx::query(&query)).await);
+    rows.iter().map(token_from_row).collect()
+}
+
+pub async fn db_rotate_token(
+    db: &SqlitePool,
+    name: &str,
+    key_id: &str,
+    hash: &str,
+) -> Result<(), String> {
+    let query =
+        sqlx::query("UPDATE tokens SET key_id=?, hash=? WHERE name=? AND revoked_at IS NULL")
+            .bind(key_id)
+            .bind(hash)
+            .bind(name);
+    let result = try_sql!(db.execute(query).await);
+    if result.rows_affected() == 0 {
+        return Err(format!("token '{name}' doesn't exist or is revoked"));
+    }
+    Ok(())
+}
+
+pub async fn db_revoke_token(
+    db: &SqlitePool,
+    name: &str,
+    revoked_at: DateTime<Utc>,
+) -> Result<(), String> {
+    let query = sqlx::query("UPDATE tokens SET revoked_at=? WHERE name=? AND revoked_at IS NULL")
+        .bind(format_date(revoked_at))
+        .bind(name);
+    let result = try_sql!(db.execute(query).await);
+    if result.rows_affected() == 0 {
+        return Err(format!(
+            "token '{name}' doesn't exist or is already revoked"
+        ));
+    }
+    Ok(())
+}
+
+/// returns id of the new customer
+pub async fn db_add_customer(
+    db: &mut Connection<Db>,
+    phone: Option<&str>,
+    telegram_id: Option<&str>,
+    password_hash: &str,
+) -> Result<i64, String> {
+    let query = sqlx::query("SELECT COUNT(*) FROM customers WHERE phone=? OR telegram_id=?")
+        .bind(phone)
+        .bind(telegram_id);
+    let row = try_sql!(db.fetch_one(query).await);
+    let exists: i64 = row.get(0);
+    if exists > 0 {
+        return Err("customer already exists".to_string());
+    }
+
+    let query = sqlx::query(
+        "INSERT INTO customers (phone, telegram_id, password_hash, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(phone)
+    .bind(telegram_id)
+    .bind(password_hash)
+    .bind(format_date(Utc::now()));
+    let result = try_sql!(db.execute(query).await);
+    Ok(result.last_insert_rowid())
+}
+
+/// finds customer by phone or telegram id, returns it with its password hash
+pub async fn db_find_customer_login(
+    db: &mut SqliteConnection,
+    phone: Option<&str>,
+    telegram_id: Option<&str>,
+) -> Result<Option<(Customer, String)>, String> {
+    let query = sqlx::query(
+        "SELECT id, phone, telegram_id, password_hash FROM customers
+            WHERE (phone=? AND phone IS NOT NULL) OR (telegram_id=? AND telegram_id IS NOT NULL)
+            LIMIT 1",
+    )
+    .bind(phone)
+    .bind(telegram_id);
+    let row = try_sql!(db.fetch_optional(query).await);
+    Ok(row.map(|row| {
+        let customer = Customer {
+            id: row.get(0),
+            phone: row.get(1),
+            telegram_id: row.get(2),
+        };
+        (customer, row.get(3))
+    }))
+}
+
+pub async fn db_add_session(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+    hash: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), String> {
+    let query = sqlx::query(
+        "INSERT INTO customer_sessions (hash, customer_id, expires_at, created_at)
+            VALUES (?, ?, ?, ?)",
+    )
+    .bind(hash)
+    .bind(customer_id)
+    .bind(format_date(expires_at))
+    .bind(format_date(Utc::now()));
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+/// customer of a session that is not expired yet
+pub async fn db_find_session_customer(
+    db: &SqlitePool,
+    hash: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<Customer>, String> {
+    let query = sqlx::query(
+        "SELECT customers.id, customers.phone, customers.telegram_id FROM customer_sessions
+            JOIN customers ON customers.id = customer_sessions.customer_id
+            WHERE customer_sessions.hash=? AND customer_sessions.expires_at > ? LIMIT 1",
+    )
+    .bind(hash)
+    .bind(format_date(now));
+    let row = try_sql!(db.fetch_optional(query).await);
+    Ok(row.map(|row| Customer {
+        id: row.get(0),
+        phone: row.get(1),
+        telegram_id: row.get(2),
+    }))
+}
+
+pub async fn db_delete_session(db: &mut Connection<Db>, hash: &str) -> Result<(), String> {
+    let query = sqlx::query("DELETE FROM customer_sessions WHERE hash=?").bind(hash);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+pub async fn db_customer_clients(
+    db: &mut SqliteConnection,
+    customer_id: i64,
+) -> Result<Vec<String>, String> {
+    let query = sqlx::query("SELECT name FROM customer_clients WHERE customer_id=? ORDER BY name")
+        .bind(customer_id);
+    let rows = try_sql!(db.fetch_all(query).await);
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// customer that `name` is linked to
+pub async fn db_client_owner(db: &mut SqliteConnection, name: &str) -> Result<Option<i64>, String> {
+    let query = sqlx::query("SELECT customer_id FROM customer_clients WHERE name=?").bind(name);
+    let row = try_sql!(db.fetch_optional(query).await);
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn db_link_client(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+    name: &str,
+) -> Result<(), String> {
+    let query = sqlx::query("SELECT COUNT(*) FROM customers WHERE id=?").bind(customer_id);
+    let row = try_sql!(db.fetch_one(query).await);
+    let exists: i64 = row.get(0);
+    if exists == 0 {
+        return Err(format!("customer '{customer_id}' doesn't exist"));
+    }
+
+    let query = sqlx::query("SELECT customer_id FROM customer_clients WHERE name=?").bind(name);
+    if let Some(row) = try_sql!(db.fetch_optional(query).await) {
+        let owner: i64 = row.get(0);
+        if owner != customer_id {
+            return Err(format!(
+                "client '{name}' is already linked to customer '{owner}'"
+            ));
+        }
+        return Ok(());
+    }
+
+    let query = sqlx::query("INSERT INTO customer_clients (name, customer_id) VALUES (?, ?)")
+        .bind(name)
+        .bind(customer_id);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+pub async fn db_unlink_client(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+    name: &str,
+) -> Result<bool, String> {
+    let query = sqlx::query("DELETE FROM customer_clients WHERE name=? AND customer_id=?")
+        .bind(name)
+        .bind(customer_id);
+    let result = try_sql!(db.execute(query).await);
+    Ok(result.rows_affected() > 0)
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TransactionRecord {
+    pub authority: String,
+    pub clients: Vec<String>,
+    pub amount: u32,
+    pub status: String,
+    pub ref_id: Option<String>,
+    pub date: DateTime<Utc>,
+}
+
+/// newest transactions first
+pub async fn db_customer_transactions(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+) -> Result<Vec<TransactionRecord>, String> {
+    let query = format!(
+        "SELECT authority, name, amount, {STATUS_SQL}, ref_id, date FROM transactions
+            WHERE customer_id=? ORDER BY date DESC"
+    );
+    let query = sqlx::query(&query).bind(payable_since()).bind(customer_id);
+    let rows = try_sql!(db.fetch_all(query).await);
+
+    let mut transactions = Vec::new();
+    for row in rows {
+        let names: String = row.get(1);
+        let date: String = row.get(5);
+        transactions.push(TransactionRecord {
+            authority: row.get(0),
+            clients: names
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+            amount: row.get(2),
+            status: row.get(3),
+            ref_id: row.get(4),
+            date: parse_date(&date)?,
+        });
+    }
+    Ok(transactions)
+}
+
+pub async fn db_add_top_up(
+    db: &mut Connection<Db>,
+    authority: &str,
+    amount: u32,
+    customer_id: i64,
+) -> Result<(), String> {
+    let query = sqlx::query(
+        "INSERT INTO transactions (authority, name, amount, date, status, kind, customer_id)
+            VALUES (?, '', ?, ?, ?, ?, ?)",
+    )
+    .bind(authority)
+    .bind(amount)
+    .bind(format_date(Utc::now()))
+    .bind(TransactionStatus::Created.as_str())
+    .bind(TransactionKind::TopUp.as_str())
+    .bind(customer_id);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LedgerEntry {
+    pub id: i64,
+    pub amount: i64,
+    /// balance right after this entry
+    pub balance: i64,
+    pub kind: String,
+    /// authority of the transaction that made the entry
+    pub reference: String,
+    pub date: DateTime<Utc>,
+}
+
+/// credits the wallet once for a verified top up, returns false when it's
+/// already credited
+pub async fn db_credit_top_up(
+    db: &mut SqliteConnection,
+    authority: &str,
+    receipt: &PaymentReceipt,
+) -> Result<bool, String> {
+    let mut tx = try_sql!(db.begin().await);
+    let query = sqlx::query(
+        "UPDATE transactions SET status=?, ref_id=?, gateway=?
+            WHERE authority=? AND kind=? AND status!=?",
+    )
+    .bind(TransactionStatus::Fulfilled.as_str())
+    .bind(&receipt.ref_id)
+    .bind(&receipt.gateway)
+    .bind(authority)
+    .bind(TransactionKind::TopUp.as_str())
+    .bind(TransactionStatus::Fulfilled.as_str());
+    if try_sql!(tx.execute(query).await).rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let query = sqlx::query("SELECT customer_id, amount FROM transactions WHERE authority=?")
+        .bind(authority);
+    let row = try_sql!(tx.fetch_one(query).await);
+    let customer_id: Option<i64> = row.get(0);
+    let customer_id = customer_id.ok_or("top up doesn't have a customer")?;
+    let amount: i64 = row.get(1);
+
+    let query = sqlx::query(
+        "INSERT INTO wallets (customer_id, balance) VALUES (?, ?)
+            ON CONFLICT(customer_id) DO UPDATE SET balance=balance+excluded.balance",
+    )
+    .bind(customer_id)
+    .bind(amount);
+    try_sql!(tx.execute(query).await);
+    add_ledger_entry(&mut tx, customer_id, amount, "top_up", authority).await?;
+
+    try_sql!(tx.commit().await);
+    Ok(true)
+}
+
+/// debits the wallet, adds a verified transaction for the clients and records
+/// the purchase in ledger, returns id of the wallet ledger entry. it's rejected
+/// when any of the clients is in a verified payment or a created one since
+/// `pending_since`, so they are not bought twice at the same time
+pub async fn db_debit_wallet(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+    authority: &str,
+    names: &str,
+    amount: u32,
+    config_token: &str,
+    pending_since: DateTime<Utc>,
+) -> Result<i64, String> {
+    let mut tx = try_sql!((&mut **db).begin().await);
+    // transaction is added first so concurrent purchases wait for each other
+    let query = sqlx::query(
+        "INSERT INTO transactions
+            (authority, name, amount, date, status, config_token, customer_id, gateway)
+            VALUES (?, ?, ?, ?, ?, ?, ?, 'wallet')",
+    )
+    .bind(authority)
+    .bind(names)
+    .bind(amount)
+    .bind(format_date(Utc::now()))
+    .bind(TransactionStatus::Verified.as_str())
+    .bind(config_token)
+    .bind(customer_id);
+    try_sql!(tx.execute(query).await);
+
+    for name in names.split(',') {
+        let query = sqlx::query(
+            "SELECT 1 FROM transactions WHERE authority!=?1
+                AND (status=?2 OR (status=?3 AND date>=?4))
+                AND (instr(',' || name || ',', ',' || ?5 || ',') > 0
+                    OR instr(',' || paid_names || ',', ',' || ?5 || ',') > 0)
+                LIMIT 1",
+        )
+        .bind(authority)
+        .bind(TransactionStatus::Verified.as_str())
+        .bind(TransactionStatus::Created.as_str())
+        .bind(format_date(pending_since))
+        .bind(name);
+        if try_sql!(tx.fetch_optional(query).await).is_some() {
+            return Err(format!("'{name}' already have a payment in progress"));
+        }
+    }
+
+    let query =
+        sqlx::query("UPDATE wallets SET balance=balance-? WHERE customer_id=? AND balance>=?")
+            .bind(amount)
+            .bind(customer_id)
+            .bind(amount);
+    if try_sql!(tx.execute(query).await).rows_affected() == 0 {
+        return Err("not enough balance in wallet".to_string());
+    }
+    let id = add_ledger_entry(
+        &mut tx,
+        customer_id,
+        -(amount as i64),
+        "purchase",
+        authority,
+    )
+    .await?;
+    let query = sqlx::query("UPDATE transactions SET ref_id=? WHERE authority=?")
+        .bind(id.to_string())
         .bind(authority);
+    try_sql!(tx.execute(query).await);
+    ledger::record(&mut tx, &Movement::wallet_purchase(authority, amount)).await?;
+
+    try_sql!(tx.commit().await);
+    Ok(id)
+}
+
+/// gives `amount` of a wallet purchase back for clients that runner couldn't
+/// make paid, the transaction is left with `paid` clients and is fulfilled,
+/// or is refunded when none of them are paid, returns its final status
+pub async fn db_refund_wallet_purchase(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+    authority: &str,
+    paid: &str,
+    amount: u32,
+) -> Result<TransactionStatus, String> {
+    let mut tx = try_sql!((&mut **db).begin().await);
+    let query = sqlx::query("UPDATE wallets SET balance=balance+? WHERE customer_id=?")
+        .bind(amount)
+        .bind(customer_id);
+    try_sql!(tx.execute(query).await);
+    add_ledger_entry(&mut tx, customer_id, amount as i64, "refund", authority).await?;
+    let movement = Movement::wallet_purchase_reversal(authority, amount);
+    ledger::record(&mut tx, &movement).await?;
+
+    // names are kept when nothing is paid, so it's still known what was bought
+    let (status, names) = match paid.is_empty() {
+        true => (TransactionStatus::Refunded, None),
+        false => (TransactionStatus::Fulfilled, Some(paid)),
+    };
+    let query = sqlx::query(
+        "UPDATE transactions SET name=coalesce(?, name), amount=amount-?, status=?
+            WHERE authority=?",
+    )
+    .bind(names)
+    .bind(amount)
+    .bind(status.as_str())
+    .bind(authority);
+    try_sql!(tx.execute(query).await);
+
+    try_sql!(tx.commit().await);
+    Ok(status)
+}
+
+/// records the refund in ledger and gives it back to wallet of the customer,
+/// returns false when the transaction is already refunded
+pub async fn db_refund_to_wallet(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+    movement: &Movement,
+    amount: u32,
+) -> Result<bool, String> {
+    let mut tx = try_sql!((&mut **db).begin().await);
+    if !ledger::record(&mut tx, movement).await? {
+        return Ok(false);
+    }
+    let query = sqlx::query("UPDATE wallets SET balance=balance+? WHERE customer_id=?")
+        .bind(amount)
+        .bind(customer_id);
+    try_sql!(tx.execute(query).await);
+    add_ledger_entry(
+        &mut tx,
+        customer_id,
+        amount as i64,
+        "refund",
+        &movement.reference,
+    )
+    .await?;
+
+    try_sql!(tx.commit().await);
+    Ok(true)
+}
+
+async fn add_ledger_entry(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    customer_id: i64,
+    amount: i64,
+    kind: &str,
+    reference: &str,
+) -> Result<i64, String> {
+    let query = sqlx::query(
+        "INSERT INTO wallet_ledger (customer_id, amount, balance, kind, reference, date)
+            SELECT ?, ?, balance, ?, ?, ? FROM wallets WHERE customer_id=?",
+    )
+    .bind(customer_id)
+    .bind(amount)
+    .bind(kind)
+    .bind(reference)
+    .bind(format_date(Utc::now()))
+    .bind(customer_id);
+    let result = try_sql!(tx.execute(query).await);
+    Ok(result.last_insert_rowid())
+}
+
+/// balance of the wallet and its ledger, newest entries first
+pub async fn db_wallet(
+    db: &mut Connection<Db>,
+    customer_id: i64,
+) -> Result<(i64, Vec<LedgerEntry>), String> {
+    let query = sqlx::query("SELECT balance FROM wallets WHERE customer_id=?").bind(customer_id);
+    let balance = try_sql!(db.fetch_optional(query).await).map_or(0, |row| row.get(0));
+
+    let query = sqlx::query(
+        "SELECT id, amount, balance, kind, reference, date FROM wallet_ledger
+            WHERE customer_id=? ORDER BY id DESC",
+    )
+    .bind(customer_id);
     let rows = try_sql!(db.fetch_all(query).await);
 
-    if rows.len() == 0 {
-        return Err("authority not exists".to_string());
+    let mut entries = Vec::new();
+    for row in rows {
+        let date: String = row.get(5);
+        entries.push(LedgerEntry {
+            id: row.get(0),
+            amount: row.get(1),
+            balance: row.get(2),
+            kind: row.get(3),
+            reference: row.get(4),
+            date: parse_date(&date)?,
+        });
     }
+    Ok((balance, entries))
+}
 
-    let row = rows.get(0).unwrap();
-    let name: String = row.get(0);
-    let amount: u32 = row.get(1);
-    Ok((name, amount))
+/// adds the entry with its postings, returns false when an entry with the
+/// same kind and reference already exists
+pub async fn db_add_ledger_entry(
+    db: &mut SqliteConnection,
+    kind: &str,
+    reference: &str,
+    postings: &[(&str, i64)],
+) -> Result<bool, String> {
+    let mut tx = try_sql!(db.begin().await);
+    let query = sqlx::query(
+        "INSERT OR IGNORE INTO ledger_entries (kind, reference, date) VALUES (?, ?, ?)",
+    )
+    .bind(kind)
+    .bind(reference)
+    .bind(format_date(Utc::now()));
+    let result = try_sql!(tx.execute(query).await);
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let entry_id = result.last_insert_rowid();
+    for (account, amount) in postings {
+        let query =
+            sqlx::query("INSERT INTO ledger_postings (entry_id, account, amount) VALUES (?, ?, ?)")
+                .bind(entry_id)
+                .bind(account)
+                .bind(amount);
+        try_sql!(tx.execute(query).await);
+    }
+    try_sql!(tx.commit().await);
+    Ok(true)
+}
+
+/// sum of postings of each account in each period, `period_length` is how
+/// many characters of date make a period, like 10 for days and 7 for months
+pub async fn db_ledger_totals(
+    db: &mut Connection<Db>,
+    period_length: usize,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<(String, String, i64)>, String> {
+    let query = sqlx::query(
+        "SELECT substr(ledger_entries.date, 1, ?) AS period, ledger_postings.account,
+            SUM(ledger_postings.amount)
+            FROM ledger_postings JOIN ledger_entries ON ledger_entries.id = ledger_postings.entry_id
+            WHERE (? IS NULL OR substr(ledger_entries.date, 1, 10) >= ?)
+                AND (? IS NULL OR substr(ledger_entries.date, 1, 10) <= ?)
+            GROUP BY period, ledger_postings.account ORDER BY period",
+    )
+    .bind(period_length as i64)
+    .bind(from)
+    .bind(from)
+    .bind(to)
+    .bind(to);
+    let rows = try_sql!(db.fetch_all(query).await);
+    Ok(rows
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect())
+}
+
+/// how many clients are in `name` column of transactions
+pub const CLIENT_COUNT_SQL: &str = "(length(name) - length(replace(name, ',', '')) + 1)";
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SalesRow {
+    pub key: String,
+    pub created: i64,
+    /// verified or fulfilled
+    pub paid: i64,
+    pub conversion_rate: f64,
+    pub revenue: i64,
+    pub clients: i64,
+}
+
+/// sales of clients grouped by `group`, which is an sql expression over
+/// columns of `transactions`, `from` and `to` are included dates
+pub async fn db_sales_report(
+    db: &mut Connection<Db>,
+    group: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<SalesRow>, String> {
+    let paid = format!(
+        "status IN ('{}', '{}')",
+        TransactionStatus::Verified.as_str(),
+        TransactionStatus::Fulfilled.as_str()
+    );
+    let query = format!(
+        "SELECT CAST({group} AS TEXT) AS key, COUNT(*), SUM({paid}),
+            SUM(CASE WHEN {paid} THEN amount ELSE 0 END),
+            SUM(CASE WHEN {paid} THEN {CLIENT_COUNT_SQL} ELSE 0 END)
+            FROM transactions
+            WHERE kind=? AND (? IS NULL OR substr(date, 1, 10) >= ?)
+                AND (? IS NULL OR substr(date, 1, 10) <= ?)
+            GROUP BY key ORDER BY key"
+    );
+    let query = sqlx::query(&query)
+        .bind(TransactionKind::Clients.as_str())
+        .bind(from)
+        .bind(from)
+        .bind(to)
+        .bind(to);
+    let rows = try_sql!(db.fetch_all(query).await);
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let (created, paid): (i64, i64) = (row.get(1), row.get(2));
+            SalesRow {
+                key: row.get(0),
+                created,
+                paid,
+                conversion_rate: paid as f64 / created as f64,
+                revenue: row.get(3),
+                clients: row.get(4),
+            }
+        })
+        .collect())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromFormField)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// gave up after too many attempts, can be redelivered by admins
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Delivery {
+    pub id: i64,
+    pub url: String,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+const DELIVERY_COLUMNS: &str =
+    "id, url, event, payload, status, attempts, next_attempt_at, last_error, created_at";
+
+fn delivery_from_row(row: &SqliteRow) -> Result<Delivery, String> {
+    let next_attempt_at: String = row.get(6);
+    let created_at: String = row.get(8);
+    Ok(Delivery {
+        id: row.get(0),
+        url: row.get(1),
+        event: row.get(2),
+        payload: row.get(3),
+        status: row.get(4),
+        attempts: row.get(5),
+        next_attempt_at: parse_date(&next_attempt_at)?,
+        last_error: row.get(7),
+        created_at: parse_date(&created_at)?,
+    })
+}
+
+/// adds a pending delivery of the payload for each url
+pub async fn db_add_deliveries(
+    db: &mut SqliteConnection,
+    urls: &[String],
+    event: &str,
+    payload: &str,
+) -> Result<(), String> {
+    let now = format_date(Utc::now());
+    for url in urls {
+        let query = sqlx::query(
+            "INSERT INTO webhook_deliveries (url, event, payload, status, next_attempt_at, created_at)
+                VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(url)
+        .bind(event)
+        .bind(payload)
+        .bind(DeliveryStatus::Pending.as_str())
+        .bind(&now)
+        .bind(&now);
+        try_sql!(db.execute(query).await);
+    }
+    Ok(())
+}
+
+/// pending deliveries that should be attempted until `now`, oldest first
+pub async fn db_due_deliveries(
+    db: &SqlitePool,
+    now: DateTime<Utc>,
+    limit: u32,
+) -> Result<Vec<Delivery>, String> {
+    let query = format!(
+        "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries
+            WHERE status=? AND next_attempt_at<=? ORDER BY id LIMIT ?"
+    );
+    let query = sqlx::query(&query)
+        .bind(DeliveryStatus::Pending.as_str())
+        .bind(format_date(now))
+        .bind(limit);
+    let rows = try_sql!(db.fetch_all(query).await);
+    rows.iter().map(delivery_from_row).collect()
+}
+
+pub async fn db_find_delivery(db: &mut SqliteConnection, id: i64) -> Result<Delivery, String> {
+    let query = format!("SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries WHERE id=?");
+    let query = sqlx::query(&query).bind(id);
+    match try_sql!(db.fetch_optional(query).await) {
+        Some(row) => delivery_from_row(&row),
+        None => Err(format!("delivery '{id}' doesn't exist")),
+    }
+}
+
+pub async fn db_list_deliveries(
+    db: &mut SqliteConnection,
+    status: Option<DeliveryStatus>,
+) -> Result<Vec<Delivery>, String> {
+    let query = format!(
+        "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries
+            WHERE ? IS NULL OR status=? ORDER BY id DESC LIMIT 100"
+    );
+    let status = status.map(|status| status.as_str());
+    let query = sqlx::query(&query).bind(status).bind(status);
+    let rows = try_sql!(db.fetch_all(query).await);
+    rows.iter().map(delivery_from_row).collect()
+}
+
+/// records an attempt of the delivery, `error` is `None` when it's delivered
+pub async fn db_set_delivery_attempt(
+    db: &mut SqliteConnection,
+    id: i64,
+    status: DeliveryStatus,
+    attempts: u32,
+    error: Option<&str>,
+    next_attempt_at: DateTime<Utc>,
+) -> Result<(), String> {
+    let query = sqlx::query(
+        "UPDATE webhook_deliveries
+            SET status=?, attempts=?, last_error=?, next_attempt_at=? WHERE id=?",
+    )
+    .bind(status.as_str())
+    .bind(attempts)
+    .bind(error)
+    .bind(format_date(next_attempt_at))
+    .bind(id);
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+pub async fn db_add_audit_entry(
+    db: &mut SqliteConnection,
+    actor: &Actor,
+    change: &Change<'_>,
+) -> Result<(), String> {
+    let query = sqlx::query(
+        "INSERT INTO audit_log
+            (actor, ip, action, authority, clients, target, before, after, note, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&actor.name)
+    .bind(&actor.ip)
+    .bind(change.action)
+    .bind(change.authority)
+    .bind(change.clients)
+    .bind(change.target)
+    .bind(change.before)
+    .bind(change.after)
+    .bind(change.note)
+    .bind(format_date(Utc::now()));
+    try_sql!(db.execute(query).await);
+    Ok(())
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: String,
+    pub ip: Option<String>,
+    pub action: String,
+    pub authority: Option<String>,
+    pub clients: Option<String>,
+    pub target: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// entries that match every given filter, newest first, `client` matches
+/// any client of an entry and `from` and `to` are included dates
+pub async fn db_list_audit(
+    db: &mut SqliteConnection,
+    filter: &AuditFilter,
+    limit: u32,
+) -> Result<Vec<AuditEntry>, String> {
+    let query = sqlx::query(
+        "SELECT id, actor, ip, action, authority, clients, target, before, after, note, created_at
+            FROM audit_log
+            WHERE (?1 IS NULL OR actor=?1) AND (?2 IS NULL OR action=?2)
+                AND (?3 IS NULL OR authority=?3)
+                AND (?4 IS NULL OR instr(',' || clients || ',', ',' || ?4 || ',') > 0)
+                AND (?5 IS NULL OR target=?5)
+                AND (?6 IS NULL OR substr(created_at, 1, 10) >= ?6)
+                AND (?7 IS NULL OR substr(created_at, 1, 10) <= ?7)
+            ORDER BY id DESC LIMIT ?8",
+    )
+    .bind(&filter.actor)
+    .bind(&filter.action)
+    .bind(&filter.authority)
+    .bind(&filter.client)
+    .bind(&filter.target)
+    .bind(&filter.from)
+    .bind(&filter.to)
+    .bind(limit);
+    let rows = try_sql!(db.fetch_all(query).await);
+
+    let mut entries = Vec::new();
+    for row in rows {
+        let created_at: String = row.get(10);
+        entries.push(AuditEntry {
+            id: row.get(0),
+            actor: row.get(1),
+            ip: row.get(2),
+            action: row.get(3),
+            authority: row.get(4),
+            clients: row.get(5),
+            target: row.get(6),
+            before: row.get(7),
+            after: row.get(8),
+            note: row.get(9),
+            created_at: parse_date(&created_at)?,
+        });
+    }
+    Ok(entries)
 }
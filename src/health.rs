@@ -0,0 +1,228 @@
+use crate::{
+    db::{db_ping, Db},
+    payment::Payment,
+    response::RequestResult,
+    runner::{
+        router::{Health, Router},
+        Runner,
+    },
+};
+use chrono::Utc;
+use rocket::{
+    fairing::AdHoc,
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    response::status::Custom,
+    serde::{json::Json, Serialize},
+    tokio::{net::lookup_host, sync::Mutex, time::timeout},
+    State,
+};
+use std::{
+    convert::Infallible,
+    env,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+/// each component that doesn't answer in this time is counted as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// `/ready` answers from the last check for this long, so calling it doesn't
+/// keep runner busy
+const CACHE_FOR: Duration = Duration::from_secs(3);
+
+pub struct ReadyConfig {
+    pub check_gateway: bool,
+}
+
+impl ReadyConfig {
+    /// gateway is only checked when `MANJALIOF_READY_CHECK_GATEWAY` is `true`,
+    /// so a dns hiccup doesn't take the backend out of rotation
+    pub fn from_env() -> Self {
+        ReadyConfig {
+            check_gateway: env::var("MANJALIOF_READY_CHECK_GATEWAY")
+                .map(|check| check == "true")
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Components {
+    pub database: Health,
+    pub runner: Health,
+    /// left unchecked unless it's enabled
+    pub gateway: Health,
+}
+
+impl Components {
+    fn is_ready(&self) -> bool {
+        [&self.database, &self.runner, &self.gateway]
+            .iter()
+            .all(|health| health.healthy != Some(false))
+    }
+
+    /// errors can have paths and addresses, they are only logged
+    fn without_messages(mut self) -> Self {
+        for health in [&mut self.database, &mut self.runner, &mut self.gateway] {
+            health.message.clear();
+        }
+        self
+    }
+}
+
+/// router of `remote` runner, which is only managed with that runner so a
+/// state guard of it would stop other runners from launching
+struct Servers(Option<Arc<Router>>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Servers {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Servers(request.rocket().state::<Arc<Router>>().cloned()))
+    }
+}
+
+/// last checked components and when they were checked
+#[derive(Default)]
+struct ReadyCache(Mutex<Option<(Instant, Components)>>);
+
+/// mounts `/health` and `/ready`, they need no token so docker and uptime
+/// monitors can call them
+pub fn stage(config: ReadyConfig) -> AdHoc {
+    AdHoc::on_ignite("health checks", |rocket| async {
+        rocket
+            .manage(config)
+            .manage(ReadyCache::default())
+            .mount("/", routes![health, ready])
+    })
+}
+
+async fn check<F>(component: F) -> Health
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let started = Instant::now();
+    let result = match timeout(CHECK_TIMEOUT, component).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("didn't answer in {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    Health {
+        healthy: Some(result.is_ok()),
+        message: result.err().unwrap_or_default(),
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+        checked_at: Some(Utc::now()),
+    }
+}
+
+/// a runner is ready when it can list its clients, for manjaliof binary it
+/// means binary is executable and `list` succeeds
+pub async fn check_runner(runner: &dyn Runner) -> Health {
+    check(async { runner.find_clients(&[]).await.map(|_| ()) }).await
+}
+
+/// servers of a router are checked one by one, it's ready while any of them
+/// is and every server that fails is named in the message
+pub async fn check_servers(router: &Router) -> Health {
+    let started = Instant::now();
+    let mut answered = false;
+    let mut failures = Vec::new();
+    for server in router.servers().await {
+        let health = check_runner(server.runner.as_ref()).await;
+        match health.healthy {
+            Some(true) => answered = true,
+            _ => failures.push(format!("server '{}': {}", server.name(), health.message)),
+        }
+    }
+    if failures.is_empty() && !answered {
+        failures.push("no server is set".to_string());
+    }
+    Health {
+        healthy: Some(answered),
+        message: failures.join(", "),
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+        checked_at: Some(Utc::now()),
+    }
+}
+
+pub async fn check_gateway(payment: &dyn Payment) -> Health {
+    let host = payment.api_host();
+    check(async {
+        let mut addrs = lookup_host((host.as_str(), 443))
+            .await
+            .map_err(|e| format!("cannot resolve '{host}': {e}"))?;
+        match addrs.next() {
+            Some(_) => Ok(()),
+            None => Err(format!("'{host}' has no address")),
+        }
+    })
+    .await
+}
+
+/// the process is up and answers requests
+#[get("/health")]
+fn health() -> Json<RequestResult> {
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
+
+/// answers `503` when any checked component fails, components are checked
+/// again only when the last check is older than [`CACHE_FOR`]
+#[get("/ready")]
+async fn ready(
+    db: &State<Db>,
+    runner: &State<Arc<dyn Runner>>,
+    servers: Servers,
+    payment: &State<Arc<dyn Payment>>,
+    config: &State<ReadyConfig>,
+    cache: &State<ReadyCache>,
+) -> Custom<Json<RequestResult<Components>>> {
+    // lock is held while checking, so concurrent calls wait for one check
+    let mut cached = cache.0.lock().await;
+    let components = match &*cached {
+        Some((checked, components)) if checked.elapsed() < CACHE_FOR => components.clone(),
+        _ => {
+            let gateway = match config.check_gateway {
+                true => check_gateway(payment.as_ref()).await,
+                false => Health::default(),
+            };
+            let runner = match &servers.0 {
+                Some(router) => check_servers(router).await,
+                None => check_runner(runner.as_ref()).await,
+            };
+            let components = Components {
+                database: check(db_ping(db)).await,
+                runner,
+                gateway,
+            };
+            if !components.is_ready() {
+                error!("backend is not ready: {components:?}");
+            } else if !components.runner.message.is_empty() {
+                error!("some servers are down: {}", components.runner.message);
+            }
+            let components = components.without_messages();
+            *cached = Some((Instant::now(), components.clone()));
+            components
+        }
+    };
+    drop(cached);
+
+    let (status, message) = match components.is_ready() {
+        true => (Status::Ok, String::new()),
+        false => (Status::ServiceUnavailable, "not ready".to_string()),
+    };
+    Custom(
+        status,
+        Json(RequestResult {
+            success: status == Status::Ok,
+            message,
+            data: Some(components),
+        }),
+    )
+}
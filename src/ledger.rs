@@ -0,0 +1,273 @@
+use crate::{
+    audit::{self, Actor, Change},
+    db::{
+        db_add_ledger_entry, db_ledger_totals, db_refund_to_wallet, db_transaction, Db,
+        TransactionStatus,
+    },
+    response::RequestResult,
+    token::{AdminRead, AdminToken, Refunds},
+    wallet::GATEWAY_NAME as WALLET_GATEWAY,
+};
+use rocket::{
+    fairing::AdHoc,
+    serde::{json::Json, Deserialize, Serialize},
+};
+use rocket_db_pools::{sqlx::SqliteConnection, Connection};
+use tracing::info;
+
+/// where money is, every movement takes from some accounts and gives to others
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Account {
+    /// money received by gateway after its fee
+    Gateway,
+    Revenue,
+    GatewayFees,
+    /// what is owed to customers as balance of their wallet
+    Wallets,
+    Refunds,
+    /// what clients were sold for less than their price
+    Discounts,
+}
+
+impl Account {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Account::Gateway => "gateway",
+            Account::Revenue => "revenue",
+            Account::GatewayFees => "gateway_fees",
+            Account::Wallets => "wallets",
+            Account::Refunds => "refunds",
+            Account::Discounts => "discounts",
+        }
+    }
+}
+
+/// a balanced set of postings, debits are positive and credits negative
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movement {
+    pub kind: &'static str,
+    /// authority of the transaction that moved the money
+    pub reference: String,
+    pub postings: Vec<(Account, i64)>,
+}
+
+impl Movement {
+    /// money received through a gateway, `credited` is what it was paid for,
+    /// revenue for clients and wallets for top ups. `discount` is how much
+    /// less than the price of clients is paid, revenue gets the full price
+    pub fn gateway_payment(
+        reference: &str,
+        amount: u32,
+        fee: u32,
+        discount: u32,
+        credited: Account,
+    ) -> Self {
+        let (amount, fee, discount) = (amount as i64, fee as i64, discount as i64);
+        let mut postings = vec![
+            (Account::Gateway, amount - fee),
+            (Account::GatewayFees, fee),
+        ];
+        if discount > 0 {
+            postings.push((Account::Discounts, discount));
+        }
+        postings.push((credited, -(amount + discount)));
+        Movement {
+            kind: "gateway_payment",
+            reference: reference.to_string(),
+            postings,
+        }
+    }
+
+    pub fn wallet_purchase(reference: &str, amount: u32) -> Self {
+        Movement {
+            kind: "wallet_purchase",
+            reference: reference.to_string(),
+            postings: vec![
+                (Account::Wallets, amount as i64),
+                (Account::Revenue, -(amount as i64)),
+            ],
+        }
+    }
+
+    /// part of a wallet purchase that is given back since clients couldn't be
+    /// made paid
+    pub fn wallet_purchase_reversal(reference: &str, amount: u32) -> Self {
+        Movement {
+            kind: "wallet_purchase_reversal",
+            reference: reference.to_string(),
+            postings: vec![
+                (Account::Revenue, amount as i64),
+                (Account::Wallets, -(amount as i64)),
+            ],
+        }
+    }
+
+    /// `from` is where the money is given back from, gateway for gateway
+    /// payments and wallets for wallet purchases
+    pub fn refund(reference: &str, amount: u32, from: Account) -> Self {
+        Movement {
+            kind: "refund",
+            reference: reference.to_string(),
+            postings: vec![(Account::Refunds, amount as i64), (from, -(amount as i64))],
+        }
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.postings.iter().map(|(_, amount)| amount).sum::<i64>() == 0
+    }
+}
+
+/// adds the movement to ledger once, recording it again does nothing,
+/// returns false when it's already recorded
+pub async fn record(db: &mut SqliteConnection, movement: &Movement) -> Result<bool, String> {
+    if !movement.is_balanced() {
+        return Err(format!("movement is not balanced: {movement:?}"));
+    }
+    let postings: Vec<(&str, i64)> = movement
+        .postings
+        .iter()
+        .map(|(account, amount)| (account.as_str(), *amount))
+        .collect();
+    db_add_ledger_entry(db, movement.kind, &movement.reference, &postings).await
+}
+
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("ledger", |rocket| async {
+        rocket.mount("/admin", routes![revenue_report, add_refund])
+    })
+}
+
+#[derive(FromFormField, Clone, Copy)]
+enum ReportPeriod {
+    Day,
+    Month,
+}
+
+impl ReportPeriod {
+    /// how many characters of a date make the period
+    fn date_length(&self) -> usize {
+        match self {
+            ReportPeriod::Day => 10,
+            ReportPeriod::Month => 7,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct RevenueRow {
+    period: String,
+    revenue: i64,
+    fees: i64,
+    refunds: i64,
+    discounts: i64,
+    /// revenue after fees, refunds and discounts
+    net: i64,
+}
+
+/// `from` and `to` are dates like `2023-01-31`, both are included
+#[get("/reports/revenue?<period>&<from>&<to>")]
+async fn revenue_report(
+    _token: AdminToken<AdminRead>,
+    mut db: Connection<Db>,
+    period: Option<ReportPeriod>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Json<RequestResult<Vec<RevenueRow>>> {
+    let period = period.unwrap_or(ReportPeriod::Day);
+    let totals = try_in_request!(db_ledger_totals(&mut db, period.date_length(), from, to)
+        .await
+        .map_err(|e| format!("cannot read ledger: {e}")));
+
+    let mut rows: Vec<RevenueRow> = Vec::new();
+    for (period, account, amount) in totals {
+        if rows.last().map_or(true, |row| row.period != period) {
+            rows.push(RevenueRow {
+                period,
+                ..Default::default()
+            });
+        }
+        let row = rows.last_mut().unwrap();
+        if account == Account::Revenue.as_str() {
+            // revenue is credited, so it's negative in ledger
+            row.revenue = -amount;
+        } else if account == Account::GatewayFees.as_str() {
+            row.fees = amount;
+        } else if account == Account::Refunds.as_str() {
+            row.refunds = amount;
+        } else if account == Account::Discounts.as_str() {
+            row.discounts = amount;
+        }
+        row.net = row.revenue - row.fees - row.refunds - row.discounts;
+    }
+
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(rows),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RefundArgs {
+    authority: String,
+    amount: u32,
+}
+
+/// records money that is given back, each transaction can be refunded once.
+/// refunds of gateway payments are given outside of backend, refunds of
+/// wallet purchases go back to the wallet
+#[post("/ledger/refunds", data = "<args>")]
+async fn add_refund(
+    token: AdminToken<Refunds>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    args: Json<RefundArgs>,
+) -> Json<RequestResult> {
+    let transaction = try_in_request!(db_transaction(&mut db, &args.authority)
+        .await
+        .map_err(|e| format!("cannot find transaction: {e}")));
+    try_in_request!((!matches!(
+        transaction.status,
+        TransactionStatus::Created | TransactionStatus::Expired | TransactionStatus::Refunded
+    ))
+    .then_some(())
+    .ok_or("cannot find transaction: transaction is not paid".to_string()));
+    let paid = transaction.amount;
+    try_in_request!((args.amount > 0 && args.amount <= paid)
+        .then_some(())
+        .ok_or(format!("refund should be between 1 and {paid}")));
+
+    let recorded = match (transaction.gateway.as_deref(), transaction.customer_id) {
+        (Some(WALLET_GATEWAY), Some(customer_id)) => {
+            let movement = Movement::refund(&args.authority, args.amount, Account::Wallets);
+            db_refund_to_wallet(&mut db, customer_id, &movement, args.amount).await
+        }
+        _ => {
+            let movement = Movement::refund(&args.authority, args.amount, Account::Gateway);
+            record(&mut db, &movement).await
+        }
+    };
+    let recorded = try_in_request!(recorded.map_err(|e| format!("cannot record refund: {e}")));
+    try_in_request!(recorded
+        .then_some(())
+        .ok_or(format!("'{}' is already refunded", args.authority)));
+    let change = Change {
+        action: "ledger.refund",
+        authority: Some(&args.authority),
+        note: Some(&format!("amount {}", args.amount)),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+
+    info!(
+        "'{}' is refunded '{}' by token '{}'",
+        args.authority, args.amount, token.caller.name
+    );
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
@@ -0,0 +1,120 @@
+use crate::token::random_string;
+use async_trait::async_trait;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    request::{FromRequest, Outcome},
+    Data, Request, Response,
+};
+use std::{env, time::Instant};
+use tracing::{field::Empty, info, info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// logs to stderr, `MANJALIOF_LOG` filters logs like `info,sqlx=warn` and
+/// `MANJALIOF_LOG_FORMAT=json` makes each line a json object
+pub fn init() -> Result<(), String> {
+    let filter = EnvFilter::try_from_env("MANJALIOF_LOG")
+        .or_else(|_| EnvFilter::try_new("info"))
+        .map_err(|e| format!("log filter is not valid: {e}"))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match env::var("MANJALIOF_LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().with_current_span(true).try_init(),
+        Ok("text") | Err(_) => builder.try_init(),
+        Ok(other) => {
+            return Err(format!(
+                "unknown log format '{other}', use 'text' or 'json'"
+            ))
+        }
+    };
+    result.map_err(|e| format!("cannot set up logging: {e}"))
+}
+
+/// span of a request, payments fill `authority` and `names` of it once they
+/// are known, so every log of a payment can be found by its authority
+#[derive(Clone)]
+pub struct RequestSpan(pub Span);
+
+struct RequestStart {
+    id: String,
+    span: Span,
+    started: Instant,
+}
+
+fn request_id(request: &Request<'_>) -> String {
+    match request.headers().get_one(REQUEST_ID_HEADER) {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.chars().all(|c| c.is_ascii_graphic()) =>
+        {
+            id.to_string()
+        }
+        _ => random_string(16),
+    }
+}
+
+fn request_start<'r>(request: &'r Request<'_>) -> &'r RequestStart {
+    request.local_cache(|| {
+        let id = request_id(request);
+        let span = info_span!(
+            "request",
+            request_id = %id,
+            method = %request.method(),
+            uri = %request.uri().path(),
+            route = Empty,
+            authority = Empty,
+            names = Empty,
+        );
+        RequestStart {
+            id,
+            span,
+            started: Instant::now(),
+        }
+    })
+}
+
+/// gives each request an id and a span, id is taken from `X-Request-Id` when
+/// it's sent and is sent back with response
+pub struct RequestTracing;
+
+#[async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Trace requests",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request_start(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request_start(request);
+        if let Some(route) = request.route() {
+            start.span.record("route", route.uri.as_str());
+        }
+        response.set_header(Header::new(REQUEST_ID_HEADER, start.id.clone()));
+        info!(
+            parent: &start.span,
+            status = response.status().code,
+            elapsed_ms = start.started.elapsed().as_millis() as u64,
+            "request is answered"
+        );
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for RequestSpan {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestSpan(request_start(request).span.clone()))
+    }
+}
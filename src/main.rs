@@ -3,115 +3,205 @@ extern crate rocket;
 #[macro_use]
 extern crate lazy_static;
 
+#[macro_use]
+mod response;
+
+#[allow(unused_imports)]
+mod audit;
+mod bot;
+mod checkout;
+mod cli;
+mod client_config;
+// rocket re-exports a uri macro for each route, which goes unused in modules
+// other than crate root
+#[allow(unused_imports)]
 mod cors;
+#[allow(unused_imports)]
+mod customer;
 mod db;
+#[allow(unused_imports)]
+mod health;
+#[allow(unused_imports)]
+mod ledger;
+mod logging;
+#[allow(unused_imports)]
+mod metrics;
+mod notifier;
 mod payment;
+mod rate_limit;
+#[allow(unused_imports)]
+mod reports;
 mod runner;
+#[allow(unused_imports)]
+mod servers;
 #[cfg(test)]
 mod tests;
 mod token;
+#[allow(unused_imports)]
+mod transactions;
+#[allow(unused_imports)]
+mod wallet;
+#[allow(unused_imports)]
+mod webhook;
 
+use bot::BotConfig;
+use checkout::{Checkout, NewPayment};
+use client_config::{ClientConfigResult, QrFormat};
 use cors::Cors;
-use db::{db_add_transaction, db_find_name, Db};
+use customer::{authorize_clients, Customer, SessionError};
+use db::{db_find_fulfilled_names, Db};
+use health::ReadyConfig;
+use logging::{RequestSpan, RequestTracing};
+use notifier::Notifier;
 use payment::{zarinpal::Zarinpal, Payment};
+use rate_limit::{RateLimit, RateLimiter};
+use response::RequestResult;
 use rocket::{
     serde::{json::Json, Deserialize, Serialize},
     Build, State,
 };
 use rocket_db_pools::Connection;
-use runner::{manjaliof::Manjaliof, Runner};
-use std::sync::Arc;
-use token::Token;
-
-macro_rules! try_in_request {
-    ($expr:expr) => {
-        match $expr {
-            Ok(smth) => smth,
-            Err(error) => {
-                eprintln!("ERROR: {error}");
-                return Json(RequestResult {
-                    success: false,
-                    message: error,
-                });
-            }
-        }
-    };
-}
+use runner::{
+    journal::JournalReceiver, manjaliof::Manjaliof, native::NativeManjaliof, router::Router, Runner,
+};
+use std::{env, sync::Arc};
+use token::CustomerToken;
+use tracing::{info, Instrument};
+use webhook::Webhooks;
 
-type PaymentState = State<Arc<dyn Payment>>;
 type RunnerState = State<Arc<dyn Runner>>;
 
 #[rocket::main]
 async fn main() -> Result<(), String> {
-    let payment = Zarinpal::new();
-    let runner = Manjaliof::new();
-    let _rocket = rocket(payment, runner)
+    logging::init()?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !matches!(args.first().map(String::as_str), None | Some("serve")) {
+        return cli::run(&args).await;
+    }
+
+    let (rocket, journal_receiver) = app()?;
+    let _rocket = rocket
+        .attach(Db::journal_writer(journal_receiver))
         .launch()
         .await
         .map_err(|e| e.to_string())?;
     Ok(())
 }
 
+/// rocket with zarinpal and the runner picked by `MANJALIOF_RUNNER`, commands
+/// of runner are sent to the returned journal, settings that are read lazily
+/// are checked here so wrong ones stop it from starting
+fn app() -> Result<(rocket::Rocket<Build>, JournalReceiver), String> {
+    payment::authority_ttl()?;
+    let payment = Zarinpal::new();
+    let (journal, journal_receiver) = runner::journal::channel();
+    let cli = Manjaliof::new();
+    let rocket = match env::var("MANJALIOF_RUNNER").as_deref() {
+        Ok("native") => rocket(
+            payment,
+            NativeManjaliof::from_env(cli).with_journal(journal),
+        ),
+        Ok("remote") => {
+            let router = Arc::new(Router::new(cli.config().clone(), Some(journal)));
+            rocket(payment, router.clone()).attach(servers::stage(router))
+        }
+        Ok("cli") | Err(_) => rocket(payment, cli.with_journal(journal)),
+        Ok(other) => {
+            return Err(format!(
+                "unknown runner '{other}', use 'cli', 'native' or 'remote'"
+            ))
+        }
+    };
+    Ok((rocket, journal_receiver))
+}
+
 fn rocket<T, R>(payment: T, runner: R) -> rocket::Rocket<Build>
 where
     T: Payment,
     R: Runner,
 {
-    let db = Db::new();
+    let db = Db::stage();
     let shared_payment: Arc<dyn Payment> = Arc::new(payment);
     let shared_runner: Arc<dyn Runner> = Arc::new(runner);
     rocket::build()
         .attach(db)
-        .attach(Cors)
+        .attach(RequestTracing)
+        .attach(metrics::Metrics)
+        .attach(Cors::from_env())
+        .attach(customer::stage())
+        .attach(wallet::stage())
+        .attach(ledger::stage())
+        .attach(reports::stage())
+        .attach(transactions::stage())
+        .attach(audit::stage())
+        .attach(rate_limit::stage(RateLimiter::from_env()))
+        .attach(notifier::stage(Notifier::from_env()))
+        .attach(webhook::stage(Webhooks::from_env()))
+        .attach(bot::stage(BotConfig::from_env()))
+        .attach(health::stage(ReadyConfig::from_env()))
         .manage(shared_payment)
         .manage(shared_runner)
-        .mount("/", routes![create_payment, verify_payment])
+        .mount("/", routes![create_payment, verify_payment, client_configs])
 }
 
-#[derive(Serialize)]
-#[serde(crate = "rocket::serde")]
-struct RequestResult {
-    success: bool,
-    message: String,
+/// price of buying `count` clients in rials
+pub fn clients_price(count: usize) -> u32 {
+    count as u32 * 55 * 10000
 }
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct CreatePaymentArgs {
     clients: Vec<String>,
+    /// frontend has always sent it as `reffer`
+    #[serde(default, alias = "reffer")]
+    referrer: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CreatePaymentData {
+    config_token: String,
 }
 
 #[post("/create_payment", data = "<args>")]
 async fn create_payment(
-    _token: Token,
+    span: RequestSpan,
+    _limit: RateLimit,
+    token: CustomerToken,
+    customer: Result<Customer, SessionError>,
     mut db: Connection<Db>,
     args: Json<CreatePaymentArgs>,
-    payment: &PaymentState,
-    runner: &RunnerState,
-) -> Json<RequestResult> {
-    try_in_request!((!args.clients.is_empty())
-        .then_some(())
-        .ok_or("at least provide one client".to_string()));
-
-    try_in_request!(runner
-        .validate_clients(&args.clients)
-        .await
-        .map_err(|e| format!("cannot validate clients: {e}")));
-
-    let price = args.clients.len() as u32 * 55 * 10000;
-    let names = args.clients.join(",");
-    let authority = try_in_request!(payment
-        .request_payment_authority(&names, price)
-        .await
-        .map_err(|e| format!("cannot request payment: {e}")));
+    checkout: Checkout<'_>,
+) -> Json<RequestResult<CreatePaymentData>> {
+    async move {
+        let customer_id = try_in_request!(authorize_clients(&mut db, customer, &args.clients)
+            .await
+            .map_err(|e| format!("cannot authorize clients: {e}")));
 
-    try_in_request!(db_add_transaction(&mut db, &authority, &names, price)
-        .await
-        .map_err(|e| format!("cannot add transactiont to database: {e}")));
-    Json(RequestResult {
-        success: true,
-        message: authority,
-    })
+        let NewPayment {
+            authority,
+            config_token,
+            ..
+        } = try_in_request!(
+            checkout
+                .create(
+                    &mut db,
+                    &args.clients,
+                    customer_id,
+                    args.referrer.as_deref()
+                )
+                .await
+        );
+        info!(caller = %token.caller.name, "payment is created");
+        Json(RequestResult {
+            success: true,
+            message: authority,
+            data: Some(CreatePaymentData { config_token }),
+        })
+    }
+    .instrument(span.0)
+    .await
 }
 
 #[derive(Deserialize)]
@@ -122,29 +212,49 @@ struct VerifyPaymentArgs {
 
 #[post("/verify_payment", data = "<args>")]
 async fn verify_payment(
+    span: RequestSpan,
+    _limit: RateLimit,
     mut db: Connection<Db>,
     args: Json<VerifyPaymentArgs>,
-    payment: &PaymentState,
-    runner: &RunnerState,
+    checkout: Checkout<'_>,
 ) -> Json<RequestResult> {
-    let (names, amount) = try_in_request!(db_find_name(&mut db, &args.authority)
-        .await
-        .map_err(|e| format!("cannot find authority in db: {e}")));
+    async move {
+        try_in_request!(checkout.verify(&mut db, &args.authority).await);
+        Json(RequestResult {
+            success: true,
+            message: String::new(),
+            data: None,
+        })
+    }
+    .instrument(span.0)
+    .await
+}
 
-    try_in_request!(payment
-        .verify(&args.authority, amount)
+#[get("/client_configs/<authority>?<token>&<format>")]
+async fn client_configs(
+    mut db: Connection<Db>,
+    authority: &str,
+    token: &str,
+    format: Option<QrFormat>,
+    runner: &RunnerState,
+) -> Json<RequestResult<Vec<ClientConfigResult>>> {
+    let names = try_in_request!(db_find_fulfilled_names(&mut db, authority, token)
         .await
-        .map_err(|e| format!("cannot verify payment: {e}")));
+        .map_err(|e| format!("cannot find transaction: {e}")));
 
-    for name in names.split(",") {
-        try_in_request!(runner
-            .make_client_paid(name)
+    let format = format.unwrap_or(QrFormat::Svg);
+    let mut configs = Vec::new();
+    for name in names.split(',') {
+        let config = try_in_request!(runner
+            .get_client_config(name)
             .await
-            .map_err(|e| format!("CRITICAL: runner failed on names '{names}': {e}")))
+            .map_err(|e| format!("cannot get config of client '{name}': {e}")));
+        configs.push(try_in_request!(ClientConfigResult::from(config, format)));
     }
 
     Json(RequestResult {
         success: true,
         message: String::new(),
+        data: Some(configs),
     })
 }
@@ -0,0 +1,127 @@
+use crate::token::{AdminRead, AdminToken};
+use async_trait::async_trait;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, Encoder, Histogram,
+    HistogramVec, IntCounterVec, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::ContentType,
+    Build, Data, Request, Response, Rocket,
+};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "manjaliof_http_requests_total",
+        "answered requests",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "manjaliof_http_request_duration_seconds",
+        "time it took to answer requests",
+        &["method", "route"]
+    )
+    .unwrap();
+    static ref PAYMENT_CALLS: IntCounterVec = register_int_counter_vec!(
+        "manjaliof_payment_calls_total",
+        "calls to payment gateways, code is what gateway answered or 'error' when it didn't",
+        &["gateway", "method", "code"]
+    )
+    .unwrap();
+    static ref PAYMENT_DURATION: HistogramVec = register_histogram_vec!(
+        "manjaliof_payment_call_duration_seconds",
+        "time it took for payment gateways to answer",
+        &["gateway", "method"]
+    )
+    .unwrap();
+    static ref RUNNER_COMMANDS: IntCounterVec = register_int_counter_vec!(
+        "manjaliof_runner_commands_total",
+        "commands run by runners, status is exit code of manjaliof or status of agent",
+        &["runner", "command", "status"]
+    )
+    .unwrap();
+    static ref RUNNER_DURATION: HistogramVec = register_histogram_vec!(
+        "manjaliof_runner_command_duration_seconds",
+        "time it took to run runner commands",
+        &["runner", "command"]
+    )
+    .unwrap();
+    static ref DB_QUERY_DURATION: Histogram = register_histogram!(
+        "manjaliof_db_query_duration_seconds",
+        "time it took to run database queries"
+    )
+    .unwrap();
+}
+
+pub fn observe_payment_call(gateway: &str, method: &str, code: &str, duration: Duration) {
+    PAYMENT_CALLS
+        .with_label_values(&[gateway, method, code])
+        .inc();
+    PAYMENT_DURATION
+        .with_label_values(&[gateway, method])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_runner_command(runner: &str, command: &str, status: &str, duration: Duration) {
+    RUNNER_COMMANDS
+        .with_label_values(&[runner, command, status])
+        .inc();
+    RUNNER_DURATION
+        .with_label_values(&[runner, command])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_db_query(duration: Duration) {
+    DB_QUERY_DURATION.observe(duration.as_secs_f64());
+}
+
+/// counts requests and times them, and mounts `/metrics`
+pub struct Metrics;
+
+struct RequestStarted(Instant);
+
+#[async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        Ok(rocket.mount("/", routes![metrics]))
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStarted(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let started = request.local_cache(|| RequestStarted(Instant::now()));
+        // unmatched requests are counted together, so random paths don't add labels
+        let route = request
+            .route()
+            .map(|route| route.uri.as_str())
+            .unwrap_or("unmatched");
+        let method = request.method().as_str();
+        HTTP_REQUESTS
+            .with_label_values(&[method, route, &response.status().code.to_string()])
+            .inc();
+        HTTP_DURATION
+            .with_label_values(&[method, route])
+            .observe(started.0.elapsed().as_secs_f64());
+    }
+}
+
+#[get("/metrics")]
+fn metrics(_token: AdminToken<AdminRead>) -> (ContentType, String) {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("metrics are always encodable");
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, String::from_utf8(buffer).unwrap())
+}
@@ -0,0 +1,190 @@
+use reqwest::Client;
+use rocket::{fairing::AdHoc, serde::Serialize};
+use std::{env, fmt, time::Duration};
+use tokio::sync::mpsc;
+use tracing::error;
+
+pub const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// something admins should know about
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde", tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PaymentCreated {
+        authority: String,
+        names: String,
+        amount: u32,
+    },
+    /// payment is paid and verified with gateway, `names` is empty for top ups
+    PaymentVerified {
+        authority: String,
+        names: String,
+        amount: u32,
+    },
+    VerificationFailed {
+        authority: String,
+        error: String,
+    },
+    /// payment is verified but clients are not made paid
+    RunnerFailed {
+        authority: String,
+        names: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::PaymentCreated {
+                authority,
+                names,
+                amount,
+            } => write!(f, "new payment '{authority}' of '{amount}' for '{names}'"),
+            Event::PaymentVerified {
+                authority,
+                names,
+                amount,
+            } if names.is_empty() => {
+                write!(f, "wallet top up '{authority}' of '{amount}' is paid")
+            }
+            Event::PaymentVerified {
+                authority,
+                names,
+                amount,
+            } => write!(f, "payment '{authority}' of '{amount}' for '{names}' is paid"),
+            Event::VerificationFailed { authority, error } => {
+                write!(f, "cannot verify payment '{authority}': {error}")
+            }
+            Event::RunnerFailed {
+                authority,
+                names,
+                error,
+            } => write!(
+                f,
+                "CRITICAL: payment '{authority}' is verified but runner failed on names '{names}': {error}"
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// message is sent to `chat_id` with `sendMessage` of bot api
+    Telegram {
+        api_url: String,
+        bot_token: String,
+        chat_id: String,
+    },
+    /// event is posted as json with a `text` field beside it
+    Webhook(String),
+}
+
+pub struct Notifier {
+    targets: Vec<Target>,
+    client: Client,
+}
+
+impl Notifier {
+    pub fn new(targets: Vec<Target>) -> Self {
+        let client = Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .expect("cannot build http client");
+        Notifier { targets, client }
+    }
+
+    /// telegram is used when both `MANJALIOF_TELEGRAM_BOT_TOKEN` and
+    /// `MANJALIOF_TELEGRAM_CHAT_ID` are set, webhook when `MANJALIOF_NOTIFY_WEBHOOK` is
+    pub fn from_env() -> Self {
+        let mut targets = Vec::new();
+        if let (Ok(bot_token), Ok(chat_id)) = (
+            env::var("MANJALIOF_TELEGRAM_BOT_TOKEN"),
+            env::var("MANJALIOF_TELEGRAM_CHAT_ID"),
+        ) {
+            targets.push(Target::Telegram {
+                api_url: env::var("MANJALIOF_TELEGRAM_API_URL")
+                    .unwrap_or_else(|_| TELEGRAM_API_URL.to_string()),
+                bot_token,
+                chat_id,
+            });
+        }
+        if let Ok(url) = env::var("MANJALIOF_NOTIFY_WEBHOOK") {
+            targets.push(Target::Webhook(url));
+        }
+        Self::new(targets)
+    }
+
+    /// sends event to every target, a failing target doesn't stop others
+    pub async fn send(&self, event: &Event) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for target in &self.targets {
+            if let Err(error) = self.send_to(target, event).await {
+                errors.push(error);
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join(", ")),
+        }
+    }
+
+    async fn send_to(&self, target: &Target, event: &Event) -> Result<(), String> {
+        let text = event.to_string();
+        let request = match target {
+            Target::Telegram {
+                api_url,
+                bot_token,
+                chat_id,
+            } => self
+                .client
+                .post(format!("{api_url}/bot{bot_token}/sendMessage"))
+                .json(&serde_json::json!({ "chat_id": chat_id, "text": text })),
+            Target::Webhook(url) => {
+                let mut body = serde_json::to_value(event).map_err(|e| e.to_string())?;
+                body["text"] = text.into();
+                self.client.post(url).json(&body)
+            }
+        };
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| format!("send failed: {e}"))?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("got status '{}'", resp.status())),
+        }
+    }
+}
+
+/// queues events for the notifier, so requests don't wait for them to be sent
+#[derive(Clone)]
+pub struct Notify(mpsc::UnboundedSender<Event>);
+
+impl Notify {
+    pub fn send(&self, event: Event) {
+        // receiver only goes away when rocket is shutting down
+        let _ = self.0.send(event);
+    }
+}
+
+/// manages [`Notify`] and sends its events in background after liftoff
+pub fn stage(notifier: Notifier) -> AdHoc {
+    AdHoc::on_ignite("notifier", |rocket| async {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Event>();
+        rocket
+            .manage(Notify(sender))
+            .attach(AdHoc::on_liftoff("notifier sender", |_| {
+                Box::pin(async move {
+                    rocket::tokio::spawn(async move {
+                        while let Some(event) = receiver.recv().await {
+                            if let Err(error) = notifier.send(&event).await {
+                                error!("cannot send notification '{event}': {error}");
+                            }
+                        }
+                    });
+                })
+            }))
+    })
+}
@@ -1,8 +1,48 @@
 use async_trait::async_trait;
+use chrono::Duration;
+use std::env;
 
 #[cfg(test)]
 use mockall::automock;
 
+const DEFAULT_AUTHORITY_TTL_MINUTES: i64 = 30;
+
+lazy_static! {
+    /// how long an authority can be paid, gateway is asked to expire it after
+    /// that too, `app` refuses to start when it's not valid
+    pub static ref AUTHORITY_TTL: Duration =
+        authority_ttl().unwrap_or_else(|_| Duration::minutes(DEFAULT_AUTHORITY_TTL_MINUTES));
+}
+
+/// `MANJALIOF_AUTHORITY_TTL_MINUTES`, 30 minutes by default
+pub fn authority_ttl() -> Result<Duration, String> {
+    parse_authority_ttl(env::var("MANJALIOF_AUTHORITY_TTL_MINUTES").ok().as_deref())
+}
+
+/// minutes should be a positive number
+pub fn parse_authority_ttl(minutes: Option<&str>) -> Result<Duration, String> {
+    let minutes = match minutes {
+        Some(minutes) => minutes
+            .parse()
+            .ok()
+            .filter(|minutes| *minutes > 0)
+            .ok_or_else(|| {
+                "environment variable 'MANJALIOF_AUTHORITY_TTL_MINUTES' is not a positive number"
+                    .to_string()
+            })?,
+        None => DEFAULT_AUTHORITY_TTL_MINUTES,
+    };
+    Ok(Duration::minutes(minutes))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentReceipt {
+    pub gateway: String,
+    pub ref_id: String,
+    /// what gateway keeps from the amount, zero when payer pays the fee
+    pub fee: u32,
+}
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Payment: Send + Sync + 'static {
@@ -11,7 +51,11 @@ pub trait Payment: Send + Sync + 'static {
         description: &str,
         amount: u32,
     ) -> Result<String, String>;
-    async fn verify(&self, authority: &str, amount: u32) -> Result<(), String>;
+    async fn verify(&self, authority: &str, amount: u32) -> Result<PaymentReceipt, String>;
+    /// where payer goes to pay the authority
+    fn start_url(&self, authority: &str) -> String;
+    /// host of gateway api, readiness checks that it resolves
+    fn api_host(&self) -> String;
 }
 
 pub mod zarinpal;
@@ -2,14 +2,23 @@ mod code;
 mod request;
 mod verify;
 
-use super::Payment;
+use super::{Payment, PaymentReceipt};
+use crate::metrics::observe_payment_call;
 use async_trait::async_trait;
+use code::HasCode;
 use request::{ZarinpalRequestPayment, ZarinpalRequestPaymentResult};
 use reqwest::Client;
-use std::env;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{env, time::Instant};
+use tracing::info;
 use verify::{ZarinpalVerifyPayment, ZarinpalVerifyPaymentResult};
 
+const GATEWAY_NAME: &str = "zarinpal";
+/// `fee_type` when fee is taken from merchant, otherwise payer has paid it
+const MERCHANT_FEE_TYPE: &str = "Merchant";
+const ZARINPAL_API_HOST: &str = "api.zarinpal.com";
 const ZARINPAL_API_URL: &str = "https://api.zarinpal.com/pg/v4/payment";
+const ZARINPAL_START_PAY_URL: &str = "https://www.zarinpal.com/pg/StartPay";
 
 lazy_static! {
     pub static ref ZARINPAL_MERCHANT_ID: String = env::var("ZARINPAL_MERCHANT_ID")
@@ -22,6 +31,38 @@ impl Zarinpal {
     pub fn new() -> Self {
         Zarinpal {}
     }
+
+    /// posts `args` to `{method}.json` and counts the call by the code that
+    /// zarinpal answered
+    async fn call<A, T>(&self, method: &str, args: &A) -> Result<T, String>
+    where
+        A: Serialize,
+        T: DeserializeOwned + HasCode,
+    {
+        let started = Instant::now();
+        let result = Self::post::<A, T>(method, args).await;
+        let code = match &result {
+            Ok(result) => result.code().value().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        observe_payment_call(GATEWAY_NAME, method, &code, started.elapsed());
+        info!(gateway = GATEWAY_NAME, method, code, "zarinpal is called");
+        result
+    }
+
+    async fn post<A: Serialize, T: DeserializeOwned>(method: &str, args: &A) -> Result<T, String> {
+        let resp = Client::new()
+            .post(format!("{ZARINPAL_API_URL}/{method}.json"))
+            .json(args)
+            .send()
+            .await
+            .map_err(|e| format!("send failed: {e}"))?
+            .text()
+            .await
+            .map_err(|e| format!("receiving failed: {e}"))?;
+
+        serde_json::from_str(&resp).map_err(|e| format!("desrializing '{resp}' failed: {e}"))
+    }
 }
 
 #[async_trait]
@@ -32,18 +73,12 @@ impl Payment for Zarinpal {
         amount: u32,
     ) -> Result<String, String> {
         let description = description.replace("ip", "server");
-        let resp = Client::new()
-            .post(format!("{ZARINPAL_API_URL}/request.json"))
-            .json(&ZarinpalRequestPayment::from(amount, description))
-            .send()
-            .await
-            .map_err(|e| format!("send failed: {e}"))?
-            .text()
-            .await
-            .map_err(|e| format!("receiving failed: {e}"))?;
-
-        let result: ZarinpalRequestPaymentResult = serde_json::from_str(&resp)
-            .map_err(|e| format!("desrializing '{resp}' failed: {e}"))?;
+        let result: ZarinpalRequestPaymentResult = self
+            .call(
+                "request",
+                &ZarinpalRequestPayment::from(amount, description),
+            )
+            .await?;
 
         let code = result.data.code;
         if code.is_success() {
@@ -53,25 +88,35 @@ impl Payment for Zarinpal {
         }
     }
 
-    async fn verify(&self, authority: &str, amount: u32) -> Result<(), String> {
-        let resp = Client::new()
-            .post(format!("{ZARINPAL_API_URL}/verify.json"))
-            .json(&ZarinpalVerifyPayment::from(authority.to_string(), amount))
-            .send()
-            .await
-            .map_err(|e| format!("send failed: {e}"))?
-            .text()
-            .await
-            .map_err(|e| format!("receiving failed: {e}"))?;
-
-        let result: ZarinpalVerifyPaymentResult = serde_json::from_str(&resp)
-            .map_err(|e| format!("desrializing '{resp}' failed: {e}"))?;
+    async fn verify(&self, authority: &str, amount: u32) -> Result<PaymentReceipt, String> {
+        let result: ZarinpalVerifyPaymentResult = self
+            .call(
+                "verify",
+                &ZarinpalVerifyPayment::from(authority.to_string(), amount),
+            )
+            .await?;
 
         let code = result.data.code;
-        if code.is_success() {
-            Ok(())
+        if code.is_verified() {
+            let fee = match result.data.fee_type.eq_ignore_ascii_case(MERCHANT_FEE_TYPE) {
+                true => result.data.fee,
+                false => 0,
+            };
+            Ok(PaymentReceipt {
+                gateway: GATEWAY_NAME.to_string(),
+                ref_id: result.data.ref_id.to_string(),
+                fee,
+            })
         } else {
             Err(code.to_string())
         }
     }
+
+    fn start_url(&self, authority: &str) -> String {
+        format!("{ZARINPAL_START_PAY_URL}/{authority}")
+    }
+
+    fn api_host(&self) -> String {
+        ZARINPAL_API_HOST.to_string()
+    }
 }
@@ -9,9 +9,9 @@ macro_rules! s {
 #[derive(Serialize, Deserialize)]
 pub struct ZarinpalCode(i32);
 
-impl std::string::ToString for ZarinpalCode {
-    fn to_string(&self) -> String {
-        match self.0 {
+impl std::fmt::Display for ZarinpalCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self.0 {
             -9 => s!("خطای اعتبار سنجی"),
             -10 => s!("ای پی و يا مرچنت كد پذيرنده صحيح نيست"),
             -11 => s!("مرچنت کد فعال نیست لطفا با تیم پشتیبانی ما تماس بگیرید"),
@@ -33,7 +33,8 @@ impl std::string::ToString for ZarinpalCode {
             -54 => s!("اتوریتی نامعتبر است"),
             101 => s!("تراکنش وریفای شده"),
             _ => s!("Unknown error"),
-        }
+        };
+        write!(f, "{message}")
     }
 }
 
@@ -41,4 +42,18 @@ impl ZarinpalCode {
     pub fn is_success(&self) -> bool {
         self.0 == 100
     }
+
+    /// verifying a payment again is answered with 101, which is a success too
+    pub fn is_verified(&self) -> bool {
+        self.0 == 100 || self.0 == 101
+    }
+
+    pub fn value(&self) -> i32 {
+        self.0
+    }
+}
+
+/// results of zarinpal that have a code
+pub trait HasCode {
+    fn code(&self) -> &ZarinpalCode;
 }
@@ -1,4 +1,8 @@
-use super::{code::ZarinpalCode, ZARINPAL_MERCHANT_ID};
+use super::{
+    code::{HasCode, ZarinpalCode},
+    ZARINPAL_MERCHANT_ID,
+};
+use crate::payment::AUTHORITY_TTL;
 use serde::{Deserialize, Serialize};
 
 const CALLBACK_URL: &str = "https://manjaliof.ts22.ir/verify";
@@ -9,6 +13,13 @@ pub struct ZarinpalRequestPayment {
     amount: u32,
     callback_url: &'static str,
     description: String,
+    metadata: ZarinpalRequestMetadata,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ZarinpalRequestMetadata {
+    /// seconds that authority can be paid in
+    expire_in: i64,
 }
 
 impl ZarinpalRequestPayment {
@@ -18,6 +29,9 @@ impl ZarinpalRequestPayment {
             amount,
             callback_url: CALLBACK_URL,
             description,
+            metadata: ZarinpalRequestMetadata {
+                expire_in: AUTHORITY_TTL.num_seconds(),
+            },
         }
     }
 }
@@ -28,6 +42,12 @@ pub struct ZarinpalRequestPaymentResult {
     pub errors: Vec<String>,
 }
 
+impl HasCode for ZarinpalRequestPaymentResult {
+    fn code(&self) -> &ZarinpalCode {
+        &self.data.code
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ZarinpalRequestPaymentResultData {
     pub code: ZarinpalCode,
@@ -1,4 +1,7 @@
-use super::{code::ZarinpalCode, ZARINPAL_MERCHANT_ID};
+use super::{
+    code::{HasCode, ZarinpalCode},
+    ZARINPAL_MERCHANT_ID,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -24,6 +27,12 @@ pub struct ZarinpalVerifyPaymentResult {
     pub errors: Vec<String>,
 }
 
+impl HasCode for ZarinpalVerifyPaymentResult {
+    fn code(&self) -> &ZarinpalCode {
+        &self.data.code
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ZarinpalVerifyPaymentResultData {
     pub code: ZarinpalCode,
@@ -0,0 +1,259 @@
+use crate::token::hash_secret;
+use async_trait::async_trait;
+use rocket::{
+    fairing::AdHoc,
+    http::{Header, Status},
+    request::{FromRequest, Outcome, Request},
+};
+use std::{
+    collections::HashMap,
+    env,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// `<route>:<ip|token|chat>=<requests>/<seconds>` separated by commas, `chat`
+/// is for telegram chats of the bot
+const DEFAULT_LIMITS: &str = "create_payment:ip=10/60,create_payment:token=120/60,\
+    verify_payment:ip=20/60,login:ip=10/60,register:ip=5/60,top_up:ip=10/60,bot_pay:chat=5/60";
+/// buckets are swept when there are more than this many of them
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LimitKey {
+    Ip,
+    Token,
+    Chat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    fn refill_per_sec(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// token buckets for each route and caller, a caller is its ip or its token
+pub struct RateLimiter {
+    quotas: HashMap<(String, LimitKey), Quota>,
+    buckets: Mutex<HashMap<(String, LimitKey, String), Bucket>>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+pub fn parse_limits(limits: &str) -> Result<HashMap<(String, LimitKey), Quota>, String> {
+    let mut quotas = HashMap::new();
+    for limit in limits.split(',').map(str::trim).filter(|l| !l.is_empty()) {
+        let error = || format!("limit '{limit}' is not like 'route:ip=10/60'");
+        let (route, quota) = limit.split_once('=').ok_or_else(error)?;
+        let (route, key) = route.split_once(':').ok_or_else(error)?;
+        let key = match key {
+            "ip" => LimitKey::Ip,
+            "token" => LimitKey::Token,
+            "chat" => LimitKey::Chat,
+            _ => return Err(error()),
+        };
+        let (requests, secs) = quota.split_once('/').ok_or_else(error)?;
+        let requests: u32 = requests.parse().map_err(|_| error())?;
+        let secs: u64 = secs.parse().map_err(|_| error())?;
+        if requests == 0 || secs == 0 {
+            return Err(error());
+        }
+
+        let quota = Quota {
+            requests,
+            period: Duration::from_secs(secs),
+        };
+        quotas.insert((route.to_string(), key), quota);
+    }
+    Ok(quotas)
+}
+
+impl RateLimiter {
+    pub fn new(quotas: HashMap<(String, LimitKey), Quota>) -> Self {
+        RateLimiter {
+            quotas,
+            buckets: Mutex::new(HashMap::new()),
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    /// `X-Real-IP` header is only believed when it's set by one of `proxies`
+    pub fn with_trusted_proxies(mut self, proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// limits in `MANJALIOF_RATE_LIMITS`, they replace the defaults, and
+    /// proxies in `MANJALIOF_TRUSTED_PROXIES` separated by commas
+    pub fn from_env() -> Self {
+        let limits =
+            env::var("MANJALIOF_RATE_LIMITS").unwrap_or_else(|_| DEFAULT_LIMITS.to_string());
+        let quotas = parse_limits(&limits).unwrap_or_else(|e| {
+            panic!("environment variable 'MANJALIOF_RATE_LIMITS' is not valid: {e}")
+        });
+        let proxies = env::var("MANJALIOF_TRUSTED_PROXIES").unwrap_or_default();
+        let proxies = proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy.parse().unwrap_or_else(|_| {
+                    panic!("'{proxy}' in 'MANJALIOF_TRUSTED_PROXIES' is not an ip")
+                })
+            })
+            .collect();
+        Self::new(quotas).with_trusted_proxies(proxies)
+    }
+
+    /// takes one token from bucket of `caller`, returns how long it should
+    /// wait when the bucket is empty
+    pub fn check(
+        &self,
+        route: &str,
+        key: LimitKey,
+        caller: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let quota = match self.quotas.get(&(route.to_string(), key)) {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+        let rate = quota.refill_per_sec();
+        let capacity = quota.requests as f64;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            // full buckets are the same as missing ones
+            buckets.retain(|(route, key, _), bucket| {
+                match self.quotas.get(&(route.clone(), *key)) {
+                    Some(quota) => {
+                        let elapsed = now.saturating_duration_since(bucket.updated);
+                        bucket.tokens + elapsed.as_secs_f64() * quota.refill_per_sec()
+                            < quota.requests as f64
+                    }
+                    None => false,
+                }
+            });
+        }
+
+        let bucket = buckets
+            .entry((route.to_string(), key, caller.to_string()))
+            .or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// ip of the connection, or `X-Real-IP` header when the connection is from a
+/// trusted proxy, so callers cannot pick their own ip
+pub fn caller_ip(request: &Request) -> Option<IpAddr> {
+    let remote = request.remote()?.ip();
+    let trusted = request
+        .rocket()
+        .state::<Arc<RateLimiter>>()
+        .map_or(false, |limiter| limiter.trusted_proxies.contains(&remote));
+    match trusted {
+        true => request.real_ip().or(Some(remote)),
+        false => Some(remote),
+    }
+}
+
+/// set by [`RateLimit`] so 429 catcher knows what to put in `Retry-After`
+struct RetryAfter(Option<Duration>);
+
+/// counts the request against limits of its route, routes without a quota
+/// are not limited, fails with 429 when caller has used up its quota
+pub struct RateLimit;
+
+#[async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = Duration;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (limiter, route) = match (
+            request.rocket().state::<Arc<RateLimiter>>(),
+            request.route(),
+        ) {
+            (Some(limiter), Some(route)) => (limiter, route),
+            _ => return Outcome::Success(RateLimit),
+        };
+        let route = match &route.name {
+            Some(name) => name.as_ref(),
+            None => return Outcome::Success(RateLimit),
+        };
+
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .or_else(|| request.headers().get_one("auth_token"));
+        let callers = [
+            (LimitKey::Ip, caller_ip(request).map(|ip| ip.to_string())),
+            // tokens are secrets, they shouldn't be kept around as they are
+            (LimitKey::Token, token.map(hash_secret)),
+        ];
+
+        let now = Instant::now();
+        for (key, caller) in callers {
+            let caller = match caller {
+                Some(caller) => caller,
+                None => continue,
+            };
+            if let Err(retry_after) = limiter.check(route, key, &caller, now) {
+                info!("'{caller}' is rate limited on '{route}'");
+                request.local_cache(|| RetryAfter(Some(retry_after)));
+                return Outcome::Failure((Status::TooManyRequests, retry_after));
+            }
+        }
+        Outcome::Success(RateLimit)
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 429, content_type = "json")]
+struct TooManyRequests {
+    body: &'static str,
+    retry_after: Header<'static>,
+}
+
+#[catch(429)]
+fn too_many_requests(request: &Request) -> TooManyRequests {
+    let retry_after = request
+        .local_cache(|| RetryAfter(None))
+        .0
+        .map_or(1, |retry_after| retry_after.as_secs_f64().ceil() as u64);
+    TooManyRequests {
+        body: r#"{"success":false,"message":"too many requests"}"#,
+        retry_after: Header::new("Retry-After", retry_after.max(1).to_string()),
+    }
+}
+
+pub fn stage(limiter: RateLimiter) -> AdHoc {
+    AdHoc::on_ignite("rate limiting", |rocket| async {
+        rocket
+            .manage(Arc::new(limiter))
+            .register("/", catchers![too_many_requests])
+    })
+}
@@ -0,0 +1,110 @@
+use crate::{
+    db::{db_sales_report, Db, SalesRow, CLIENT_COUNT_SQL},
+    response::RequestResult,
+    token::{AdminRead, AdminToken},
+};
+use rocket::{fairing::AdHoc, http::ContentType, serde::json::Json};
+use rocket_db_pools::Connection;
+
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("reports", |rocket| async {
+        rocket.mount("/admin", routes![sales_report])
+    })
+}
+
+#[derive(FromFormField, Clone, Copy)]
+enum SalesGroup {
+    Day,
+    Week,
+    Month,
+    Gateway,
+    /// there is one plan which is priced per client, so purchases are told
+    /// apart by how many clients they have
+    Plan,
+    Referrer,
+}
+
+impl SalesGroup {
+    fn sql(&self) -> String {
+        match self {
+            SalesGroup::Day => "substr(date, 1, 10)".to_string(),
+            SalesGroup::Week => "strftime('%Y-W%W', date)".to_string(),
+            SalesGroup::Month => "substr(date, 1, 7)".to_string(),
+            // gateway is known once transaction is paid
+            SalesGroup::Gateway => "COALESCE(gateway, 'unpaid')".to_string(),
+            SalesGroup::Plan => format!("{CLIENT_COUNT_SQL} || ' clients'"),
+            SalesGroup::Referrer => "COALESCE(referrer, '')".to_string(),
+        }
+    }
+}
+
+#[derive(FromFormField, Clone, Copy)]
+enum ReportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Responder)]
+enum Report {
+    Json(Json<RequestResult<Vec<SalesRow>>>),
+    Csv((ContentType, String)),
+}
+
+/// fields that spreadsheets would run as formulas get a `'` in front
+pub fn csv_field(field: &str) -> String {
+    let field = match field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        true => format!("'{field}"),
+        false => field.to_string(),
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn sales_csv(rows: &[SalesRow]) -> String {
+    let mut csv = "key,created,paid,conversion_rate,revenue,clients\n".to_string();
+    for row in rows {
+        csv += &format!(
+            "{},{},{},{:.4},{},{}\n",
+            csv_field(&row.key),
+            row.created,
+            row.paid,
+            row.conversion_rate,
+            row.revenue,
+            row.clients
+        );
+    }
+    csv
+}
+
+/// `from` and `to` are dates like `2023-01-31`, both are included
+#[get("/reports/sales?<by>&<from>&<to>&<format>")]
+async fn sales_report(
+    _token: AdminToken<AdminRead>,
+    mut db: Connection<Db>,
+    by: Option<SalesGroup>,
+    from: Option<&str>,
+    to: Option<&str>,
+    format: Option<ReportFormat>,
+) -> Report {
+    let group = by.unwrap_or(SalesGroup::Day);
+    let rows = db_sales_report(&mut db, &group.sql(), from, to)
+        .await
+        .map_err(|e| format!("cannot make report: {e}"));
+
+    match (format, rows) {
+        (Some(ReportFormat::Csv), Ok(rows)) => Report::Csv((ContentType::CSV, sales_csv(&rows))),
+        (_, rows) => Report::Json(sales_json(rows)),
+    }
+}
+
+fn sales_json(rows: Result<Vec<SalesRow>, String>) -> Json<RequestResult<Vec<SalesRow>>> {
+    let rows = try_in_request!(rows);
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(rows),
+    })
+}
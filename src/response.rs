@@ -0,0 +1,26 @@
+use rocket::serde::Serialize;
+
+macro_rules! try_in_request {
+    ($expr:expr) => {
+        match $expr {
+            Ok(smth) => smth,
+            Err(error) => {
+                tracing::error!("{error}");
+                return rocket::serde::json::Json($crate::response::RequestResult {
+                    success: false,
+                    message: error,
+                    data: None,
+                });
+            }
+        }
+    };
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RequestResult<T = ()> {
+    pub success: bool,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+}
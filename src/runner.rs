@@ -1,13 +1,84 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 #[cfg(test)]
 use mockall::automock;
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClientConfig {
+    pub name: String,
+    pub links: Vec<String>,
+}
+
+/// the payment that made a client paid, runners can use it to leave a
+/// trace of the transaction on the client
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PaymentInfo {
+    pub authority: String,
+    pub ref_id: String,
+    pub amount: u32,
+    pub gateway: String,
+    pub date: DateTime<Utc>,
+}
+
+const NOTPAID_MARKER: &str = "NOTPAID";
+
+/// checks that every name is in `clients` as `(name, info)` and is not paid yet
+pub fn validate_client_infos<'a, I>(names: &[String], clients: I) -> Result<(), String>
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let mut valid_clients = 0;
+    for (name, info) in clients {
+        if names.iter().any(|n| n == name) {
+            if !info.starts_with(NOTPAID_MARKER) {
+                return Err(format!("client '{name}' is not notpaid, it's '{info}'"));
+            }
+
+            valid_clients += 1;
+        }
+    }
+
+    if valid_clients != names.len() {
+        Err("cannot find clients".to_string())
+    } else {
+        Ok(())
+    }
+}
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Runner: Send + Sync + 'static {
-    async fn validate_clients(&self, names: &Vec<String>) -> Result<(), String>;
-    async fn make_client_paid(&self, name: &str) -> Result<(), String>;
+    async fn validate_clients(&self, names: &[String]) -> Result<(), String>;
+    /// returns the ones in `names` that exist on this runner, no matter if paid or not
+    async fn find_clients(&self, names: &[String]) -> Result<Vec<String>, String>;
+    async fn make_client_paid(&self, name: &str, payment: &PaymentInfo) -> Result<(), String>;
+    async fn get_client_config(&self, name: &str) -> Result<ClientConfig, String>;
+}
+
+#[async_trait]
+impl<R: Runner + ?Sized> Runner for Arc<R> {
+    async fn validate_clients(&self, names: &[String]) -> Result<(), String> {
+        (**self).validate_clients(names).await
+    }
+
+    async fn find_clients(&self, names: &[String]) -> Result<Vec<String>, String> {
+        (**self).find_clients(names).await
+    }
+
+    async fn make_client_paid(&self, name: &str, payment: &PaymentInfo) -> Result<(), String> {
+        (**self).make_client_paid(name, payment).await
+    }
+
+    async fn get_client_config(&self, name: &str) -> Result<ClientConfig, String> {
+        (**self).get_client_config(name).await
+    }
 }
 
+pub mod journal;
 pub mod manjaliof;
+pub mod native;
+pub mod remote;
+pub mod router;
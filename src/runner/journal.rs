@@ -0,0 +1,24 @@
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// a command that changed client data, kept so we can audit and replay
+/// what backend did
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    pub command: String,
+    pub args: Vec<String>,
+    /// `None` when the command didn't exit by itself, like timeouts or spawn failures
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
+    pub date: DateTime<Utc>,
+}
+
+pub type JournalSender = mpsc::UnboundedSender<JournalEntry>;
+pub type JournalReceiver = mpsc::UnboundedReceiver<JournalEntry>;
+
+pub fn channel() -> (JournalSender, JournalReceiver) {
+    mpsc::unbounded_channel()
+}
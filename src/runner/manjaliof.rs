@@ -1,65 +1,237 @@
-use super::Runner;
-use std::ffi::OsStr;
-use tokio::process::Command;
+mod config;
+mod error;
+mod ssh;
+mod template;
 
-pub struct Manjaliof {}
+use super::{
+    journal::{JournalEntry, JournalSender},
+    validate_client_infos, ClientConfig, PaymentInfo, Runner,
+};
+use crate::metrics::observe_runner_command;
+use chrono::{DateTime, Utc};
+pub use config::ManjaliofConfig;
+pub use error::CommandError;
+pub use ssh::SshConfig;
+use std::{
+    process::{Output, Stdio},
+    time::{Duration, Instant},
+};
+pub use template::InfoTemplate;
+use tokio::{
+    process::Command,
+    sync::{Mutex, Semaphore},
+    time,
+};
+use tracing::{error, info};
+
+pub struct Manjaliof {
+    config: ManjaliofConfig,
+    permits: Semaphore,
+    /// manjaliof writes every client in a single data file, so commands that
+    /// change it must not run at the same time
+    mutation_lock: Mutex<()>,
+    journal: Option<JournalSender>,
+}
 
 impl Manjaliof {
     pub fn new() -> Self {
-        Manjaliof {}
+        Self::with_config(ManjaliofConfig::from_env())
+    }
+
+    pub fn with_config(config: ManjaliofConfig) -> Self {
+        let permits = Semaphore::new(config.max_concurrency);
+        Manjaliof {
+            config,
+            permits,
+            mutation_lock: Mutex::new(()),
+            journal: None,
+        }
+    }
+
+    pub fn config(&self) -> &ManjaliofConfig {
+        &self.config
+    }
+
+    pub fn with_journal(mut self, journal: JournalSender) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    async fn run_command(&self, args: &[&str]) -> Result<String, CommandError> {
+        let output = self.execute(args).await?;
+        into_stdout(output)
     }
 
-    async fn run_command<S, T>(&self, args: T) -> Result<String, String>
-    where
-        T: IntoIterator<Item = S>,
-        S: AsRef<OsStr>,
-    {
-        let output = Command::new("manjaliof")
-            .args(args)
-            .output()
+    async fn run_mutation(&self, args: &[&str]) -> Result<String, CommandError> {
+        let _guard = self.mutation_lock.lock().await;
+        let date = Utc::now();
+        let started = Instant::now();
+        let result = self.execute(args).await;
+        self.record(args, &result, started.elapsed(), date);
+        into_stdout(result?)
+    }
+
+    async fn execute(&self, args: &[&str]) -> Result<Output, CommandError> {
+        let started = Instant::now();
+        let result = self.spawn(args).await;
+        let exit_code = result.as_ref().ok().and_then(|output| output.status.code());
+        let command = args.first().copied().unwrap_or_default();
+        let status = exit_code.map_or("error".to_string(), |code| code.to_string());
+        observe_runner_command("cli", command, &status, started.elapsed());
+        info!(
+            command,
+            exit_code,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "manjaliof command is run"
+        );
+        result
+    }
+
+    async fn spawn(&self, args: &[&str]) -> Result<Output, CommandError> {
+        let _permit = self.permits.acquire().await.unwrap();
+
+        let mut command = match &self.config.ssh {
+            Some(ssh) => {
+                let mut command = Command::new(&ssh.program);
+                command
+                    .args(&ssh.options)
+                    // destination is never taken as an option, like `-oProxyCommand=...`
+                    .arg("--")
+                    .arg(&ssh.destination)
+                    .arg(ssh.remote_command(&self.config, args));
+                command
+            }
+            None => {
+                let mut command = Command::new(&self.config.binary);
+                command
+                    .args(&self.config.args)
+                    .args(args)
+                    .envs(self.config.envs.iter().map(|(k, v)| (k, v)));
+                if let Some(working_dir) = &self.config.working_dir {
+                    command.current_dir(working_dir);
+                }
+                command
+            }
+        };
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let program = match &self.config.ssh {
+            Some(ssh) => ssh.program.clone(),
+            None => self.config.binary.clone(),
+        };
+        let child = command.spawn().map_err(|error| CommandError::Spawn {
+            binary: program.clone(),
+            error,
+        })?;
+
+        // on timeout the child is dropped along with the future and gets killed
+        time::timeout(self.config.timeout, child.wait_with_output())
             .await
-            .map_err(|e| e.to_string())?;
-
-        if output.status.success() {
-            let stdout_output = String::from_utf8(output.stdout).unwrap();
-            Ok(stdout_output)
-        } else {
-            let stderr_output = String::from_utf8(output.stderr).unwrap();
-            Err(stderr_output)
+            .map_err(|_| CommandError::Timeout(self.config.timeout))?
+            .map_err(|error| CommandError::Spawn {
+                binary: program,
+                error,
+            })
+    }
+
+    fn record(
+        &self,
+        args: &[&str],
+        result: &Result<Output, CommandError>,
+        duration: Duration,
+        date: DateTime<Utc>,
+    ) {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        let (exit_code, stdout, stderr) = match result {
+            Ok(output) => (
+                output.status.code(),
+                String::from_utf8_lossy(&output.stdout).to_string(),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ),
+            Err(error) => (None, String::new(), error.to_string()),
+        };
+
+        let (command, args) = args.split_first().unwrap();
+        let entry = JournalEntry {
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            exit_code,
+            stdout,
+            stderr,
+            duration,
+            date,
+        };
+        if journal.send(entry).is_err() {
+            error!("journal is closed, '{command}' is not recorded");
         }
     }
 }
 
+/// yields `(name, info)` of each client in output of `list --trim-whitespace`
+fn parse_list(list: &str) -> impl Iterator<Item = (&str, &str)> {
+    list.lines().map(|line| {
+        let chunks: Vec<&str> = line.split(' ').collect();
+        (*chunks.first().unwrap(), *chunks.get(3).unwrap())
+    })
+}
+
+fn into_stdout(output: Output) -> Result<String, CommandError> {
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(CommandError::Exit {
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+}
+
 #[async_trait]
 impl Runner for Manjaliof {
-    async fn validate_clients(&self, names: &Vec<String>) -> Result<(), String> {
-        let mut valid_clients = 0;
-
+    async fn validate_clients(&self, names: &[String]) -> Result<(), String> {
         let list = self.run_command(&["list", "--trim-whitespace"]).await?;
-        for line in list.lines() {
-            let chunks: Vec<&str> = line.split(" ").collect();
-            let name = chunks.get(0).unwrap();
-            let info = chunks.get(3).unwrap();
-
-            if names.contains(&name.to_string()) {
-                if !info.starts_with("NOTPAID") {
-                    return Err(format!("client '{name}' is not notpaid, it's '{info}'"));
-                }
-
-                valid_clients += 1;
-            }
-        }
+        validate_client_infos(names, parse_list(&list))
+    }
 
-        if valid_clients != names.len() {
-            Err("cannot find clients".to_string())
-        } else {
-            Ok(())
-        }
+    async fn find_clients(&self, names: &[String]) -> Result<Vec<String>, String> {
+        let list = self.run_command(&["list", "--trim-whitespace"]).await?;
+        Ok(parse_list(&list)
+            .map(|(name, _)| name.to_string())
+            .filter(|name| names.contains(name))
+            .collect())
     }
 
-    async fn make_client_paid(&self, name: &str) -> Result<(), String> {
-        self.run_command(&["set-info", "--name", name, "--info", "HOSSOBBEED (site)"])
+    async fn make_client_paid(&self, name: &str, payment: &PaymentInfo) -> Result<(), String> {
+        let info = self.config.info_template.render(payment);
+        self.run_mutation(&["set-info", "--name", name, "--info", &info])
             .await?;
         Ok(())
     }
+
+    async fn get_client_config(&self, name: &str) -> Result<ClientConfig, String> {
+        let output = self.run_command(&["get-link", "--name", name]).await?;
+        let links: Vec<String> = output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        if links.is_empty() {
+            return Err(format!("client '{name}' has no links"));
+        }
+
+        Ok(ClientConfig {
+            name: name.to_string(),
+            links,
+        })
+    }
 }
@@ -0,0 +1,93 @@
+use super::{InfoTemplate, SshConfig};
+use std::{env, path::PathBuf, time::Duration};
+
+const DEFAULT_BINARY: &str = "manjaliof";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+const DEFAULT_PAID_MARKER: &str = "HOSSOBBEED";
+const DEFAULT_INFO_TEMPLATE: &str = "{marker} (site)";
+
+#[derive(Clone, Debug)]
+pub struct ManjaliofConfig {
+    pub binary: PathBuf,
+    /// passed before every command, useful when `binary` is a wrapper
+    pub args: Vec<String>,
+    /// set on top of the environment inherited from backend
+    pub envs: Vec<(String, String)>,
+    pub working_dir: Option<PathBuf>,
+    pub timeout: Duration,
+    pub max_concurrency: usize,
+    pub info_template: InfoTemplate,
+    /// when set, manjaliof is run on another host through ssh and every
+    /// option above except timeout and concurrency applies to the remote side
+    pub ssh: Option<SshConfig>,
+}
+
+impl Default for ManjaliofConfig {
+    fn default() -> Self {
+        ManjaliofConfig {
+            binary: PathBuf::from(DEFAULT_BINARY),
+            args: Vec::new(),
+            envs: Vec::new(),
+            working_dir: None,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            info_template: InfoTemplate::new(DEFAULT_PAID_MARKER, DEFAULT_INFO_TEMPLATE).unwrap(),
+            ssh: None,
+        }
+    }
+}
+
+impl ManjaliofConfig {
+    pub fn from_env() -> Self {
+        let mut config = ManjaliofConfig::default();
+
+        if let Ok(binary) = env::var("MANJALIOF_BIN") {
+            config.binary = PathBuf::from(binary);
+        }
+        if let Ok(args) = env::var("MANJALIOF_ARGS") {
+            config.args = args.split_whitespace().map(str::to_string).collect();
+        }
+        if let Ok(envs) = env::var("MANJALIOF_ENV") {
+            config.envs = parse_envs(&envs).unwrap_or_else(|e| {
+                panic!("environment variable 'MANJALIOF_ENV' is not valid: {e}")
+            });
+        }
+        if let Ok(working_dir) = env::var("MANJALIOF_WORKDIR") {
+            config.working_dir = Some(PathBuf::from(working_dir));
+        }
+        if let Ok(timeout) = env::var("MANJALIOF_TIMEOUT_SECS") {
+            let secs: u64 = timeout
+                .parse()
+                .expect("environment variable 'MANJALIOF_TIMEOUT_SECS' is not a number");
+            config.timeout = Duration::from_secs(secs);
+        }
+        if let Ok(max_concurrency) = env::var("MANJALIOF_MAX_CONCURRENCY") {
+            config.max_concurrency = max_concurrency.parse().ok().filter(|max| *max > 0).expect(
+                "environment variable 'MANJALIOF_MAX_CONCURRENCY' is not a positive number",
+            );
+        }
+
+        let marker =
+            env::var("MANJALIOF_PAID_MARKER").unwrap_or_else(|_| DEFAULT_PAID_MARKER.to_string());
+        let template = env::var("MANJALIOF_PAID_INFO_TEMPLATE")
+            .unwrap_or_else(|_| DEFAULT_INFO_TEMPLATE.to_string());
+        config.info_template = InfoTemplate::new(&marker, &template).unwrap_or_else(|e| {
+            panic!("environment variable 'MANJALIOF_PAID_INFO_TEMPLATE' is not valid: {e}")
+        });
+
+        config
+    }
+}
+
+/// parses `KEY=VALUE` pairs separated by commas, like `MANJALIOF_DATA=/data,LANG=C`
+fn parse_envs(envs: &str) -> Result<Vec<(String, String)>, String> {
+    envs.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(format!("'{pair}' is not in KEY=VALUE format")),
+        })
+        .collect()
+}
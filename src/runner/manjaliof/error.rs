@@ -0,0 +1,40 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+#[derive(Debug)]
+pub enum CommandError {
+    Spawn {
+        binary: PathBuf,
+        error: std::io::Error,
+    },
+    Timeout(Duration),
+    Exit {
+        code: Option<i32>,
+        stderr: String,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Spawn { binary, error } => {
+                write!(f, "cannot spawn '{}': {error}", binary.display())
+            }
+            CommandError::Timeout(timeout) => {
+                write!(f, "timed out after {} seconds", timeout.as_secs_f32())
+            }
+            CommandError::Exit {
+                code: Some(code),
+                stderr,
+            } => write!(f, "exited with code {code}: {stderr}"),
+            CommandError::Exit { code: None, stderr } => {
+                write!(f, "killed by signal: {stderr}")
+            }
+        }
+    }
+}
+
+impl From<CommandError> for String {
+    fn from(error: CommandError) -> Self {
+        error.to_string()
+    }
+}
@@ -0,0 +1,55 @@
+use super::ManjaliofConfig;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+fn default_program() -> PathBuf {
+    PathBuf::from("ssh")
+}
+
+/// runs manjaliof on another host, authentication is left to ssh itself
+/// (keys, agent, `~/.ssh/config`) so backend never sees a password
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SshConfig {
+    /// like `root@de1.example.com`
+    pub destination: String,
+    /// extra ssh options like `["-p", "2222", "-i", "/keys/de1"]`, they
+    /// and `program` can only be set in `MANJALIOF_SERVERS`
+    #[serde(default)]
+    pub options: Vec<String>,
+    #[serde(default = "default_program")]
+    pub program: PathBuf,
+}
+
+impl SshConfig {
+    /// `program` and `options` run commands on backend host, so servers that
+    /// are added through api cannot change them
+    pub fn is_customized(&self) -> bool {
+        !self.options.is_empty() || self.program != default_program()
+    }
+
+    /// ssh joins every argument into a single line that remote shell parses
+    /// again, so everything is quoted in here
+    pub fn remote_command(&self, config: &ManjaliofConfig, args: &[&str]) -> String {
+        let mut command = Vec::new();
+        if let Some(working_dir) = &config.working_dir {
+            command.push(format!(
+                "cd {} &&",
+                shell_quote(&working_dir.to_string_lossy())
+            ));
+        }
+        if !config.envs.is_empty() {
+            command.push("env".to_string());
+            for (key, value) in &config.envs {
+                command.push(shell_quote(&format!("{key}={value}")));
+            }
+        }
+        command.push(shell_quote(&config.binary.to_string_lossy()));
+        command.extend(config.args.iter().map(|arg| shell_quote(arg)));
+        command.extend(args.iter().map(|arg| shell_quote(arg)));
+        command.join(" ")
+    }
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
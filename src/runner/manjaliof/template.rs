@@ -0,0 +1,66 @@
+use crate::runner::PaymentInfo;
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const PLACEHOLDERS: [&str; 6] = ["marker", "authority", "ref_id", "date", "amount", "gateway"];
+
+/// info that is set on a client after it's paid, for example
+/// `{marker} {gateway}:{ref_id} {date}` becomes `HOSSOBBEED zarinpal:12345 2023-01-20`
+#[derive(Clone, Debug)]
+pub struct InfoTemplate {
+    marker: String,
+    template: String,
+}
+
+impl InfoTemplate {
+    pub fn new(marker: &str, template: &str) -> Result<Self, String> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("unclosed placeholder in '{template}'")),
+            };
+
+            let placeholder = &rest[start + 1..end];
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return Err(format!(
+                    "unknown placeholder '{{{placeholder}}}', valid ones are: {}",
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+            rest = &rest[end + 1..];
+        }
+
+        Ok(InfoTemplate {
+            marker: marker.to_string(),
+            template: template.to_string(),
+        })
+    }
+
+    /// placeholders are replaced in one pass, so values that look like
+    /// placeholders are kept as they are
+    pub fn render(&self, payment: &PaymentInfo) -> String {
+        let mut info = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            // template is checked in `new`, so every placeholder is closed
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+
+            info.push_str(&rest[..start]);
+            match &rest[start + 1..end] {
+                "marker" => info.push_str(&self.marker),
+                "authority" => info.push_str(&payment.authority),
+                "ref_id" => info.push_str(&payment.ref_id),
+                "date" => info.push_str(&payment.date.format(DATE_FORMAT).to_string()),
+                "amount" => info.push_str(&payment.amount.to_string()),
+                "gateway" => info.push_str(&payment.gateway),
+                _ => info.push_str(&rest[start..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        info.push_str(rest);
+        info
+    }
+}
@@ -0,0 +1,222 @@
+use super::{
+    journal::{JournalEntry, JournalSender},
+    manjaliof::{InfoTemplate, Manjaliof},
+    validate_client_infos, ClientConfig, PaymentInfo, Runner,
+};
+use crate::metrics::observe_runner_command;
+use chrono::Utc;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tokio::{sync::Mutex, task};
+use tracing::{error, info};
+
+/// file that manjaliof keeps its clients in, inside `MANJALIOF_DATA`
+const DATA_FILE_NAME: &str = "data.json";
+/// changes are written here first and then renamed over data file
+const TEMP_FILE_NAME: &str = "data.json.tmp";
+
+/// manjaliof data file is expected to look like
+/// `{ "clients": [{ "name": "...", "info": "...", ... }], ... }`, reading
+/// fails when it doesn't. only the fields that backend cares about are typed,
+/// everything else is kept as is so writing the file back doesn't lose anything
+#[derive(Serialize, Deserialize)]
+struct DataFile {
+    clients: Vec<DataClient>,
+    #[serde(flatten)]
+    rest: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DataClient {
+    name: String,
+    info: String,
+    #[serde(flatten)]
+    rest: Map<String, Value>,
+}
+
+/// reads and writes manjaliof data file directly instead of running the
+/// binary, the file is locked the same way manjaliof does so both can be
+/// used at the same time, getting client config still goes through the binary
+pub struct NativeManjaliof {
+    data_dir: PathBuf,
+    info_template: InfoTemplate,
+    cli: Manjaliof,
+    /// writers of this process wait for each other here before they wait for
+    /// the file lock, like commands of `Manjaliof`
+    mutation_lock: Mutex<()>,
+    journal: Option<JournalSender>,
+}
+
+impl NativeManjaliof {
+    pub fn new(data_dir: impl AsRef<Path>, cli: Manjaliof) -> Self {
+        NativeManjaliof {
+            data_dir: data_dir.as_ref().to_path_buf(),
+            info_template: cli.config().info_template.clone(),
+            cli,
+            mutation_lock: Mutex::new(()),
+            journal: None,
+        }
+    }
+
+    pub fn from_env(cli: Manjaliof) -> Self {
+        let data_dir =
+            env::var("MANJALIOF_DATA").expect("environment variable 'MANJALIOF_DATA' is not set");
+        Self::new(data_dir, cli)
+    }
+
+    pub fn with_journal(mut self, journal: JournalSender) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    async fn read(&self) -> Result<DataFile, String> {
+        let path = self.data_dir.join(DATA_FILE_NAME);
+        task::spawn_blocking(move || {
+            let file = File::open(&path).map_err(|e| format!("cannot open data file: {e}"))?;
+            // std has its own `lock_shared` on newer toolchains
+            FileExt::lock_shared(&file).map_err(|e| format!("cannot lock data file: {e}"))?;
+            serde_json::from_reader(&file).map_err(|e| format!("cannot parse data file: {e}"))
+        })
+        .await
+        .map_err(|e| format!("reading data file panicked: {e}"))?
+    }
+
+    async fn update<F>(&self, change: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut DataFile) -> Result<(), String> + Send + 'static,
+    {
+        let _guard = self.mutation_lock.lock().await;
+        let dir = self.data_dir.clone();
+        task::spawn_blocking(move || {
+            let path = dir.join(DATA_FILE_NAME);
+            let file = lock_data_file(&path)?;
+
+            let mut data: DataFile = serde_json::from_reader(&file)
+                .map_err(|e| format!("cannot parse data file: {e}"))?;
+            change(&mut data)?;
+
+            // a failed write leaves only the temp file broken, data file is
+            // replaced at once by rename
+            let content = serde_json::to_vec_pretty(&data).unwrap();
+            let temp_path = dir.join(TEMP_FILE_NAME);
+            File::create(&temp_path)
+                .and_then(|mut temp| temp.write_all(&content).and_then(|_| temp.sync_all()))
+                .and_then(|_| fs::rename(&temp_path, &path))
+                .and_then(|_| File::open(&dir).and_then(|dir| dir.sync_all()))
+                .map_err(|e| format!("cannot write data file: {e}"))
+        })
+        .await
+        .map_err(|e| format!("writing data file panicked: {e}"))?
+    }
+
+    fn record(&self, args: &[&str], result: &Result<(), String>, started: Instant) {
+        let command = args.first().copied().unwrap_or_default();
+        let status = match result {
+            Ok(()) => "0",
+            Err(_) => "error",
+        };
+        observe_runner_command("native", command, status, started.elapsed());
+        info!(
+            command,
+            success = result.is_ok(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "data file is changed"
+        );
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        let (command, args) = args.split_first().unwrap();
+        let entry = JournalEntry {
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            exit_code: result.as_ref().ok().map(|_| 0),
+            stdout: String::new(),
+            stderr: result.clone().err().unwrap_or_default(),
+            duration: started.elapsed(),
+            date: Utc::now(),
+        };
+        if journal.send(entry).is_err() {
+            error!("journal is closed, '{command}' is not recorded");
+        }
+    }
+}
+
+/// opens data file and locks it exclusively, the lock is kept until the new
+/// file is in place. a writer that waited for the lock may hold a file that is
+/// already renamed over, so it's opened again until the locked file is still
+/// the one at `path`
+fn lock_data_file(path: &Path) -> Result<File, String> {
+    loop {
+        let file = File::open(path).map_err(|e| format!("cannot open data file: {e}"))?;
+        file.lock_exclusive()
+            .map_err(|e| format!("cannot lock data file: {e}"))?;
+
+        let (locked, current) = file
+            .metadata()
+            .and_then(|locked| fs::metadata(path).map(|current| (locked, current)))
+            .map_err(|e| format!("cannot read data file metadata: {e}"))?;
+        if (locked.dev(), locked.ino()) == (current.dev(), current.ino()) {
+            return Ok(file);
+        }
+    }
+}
+
+#[async_trait]
+impl Runner for NativeManjaliof {
+    async fn validate_clients(&self, names: &[String]) -> Result<(), String> {
+        let data = self.read().await?;
+        let clients = data
+            .clients
+            .iter()
+            .map(|client| (client.name.as_str(), client.info.as_str()));
+        validate_client_infos(names, clients)
+    }
+
+    async fn find_clients(&self, names: &[String]) -> Result<Vec<String>, String> {
+        let data = self.read().await?;
+        Ok(data
+            .clients
+            .into_iter()
+            .map(|client| client.name)
+            .filter(|name| names.contains(name))
+            .collect())
+    }
+
+    async fn make_client_paid(&self, name: &str, payment: &PaymentInfo) -> Result<(), String> {
+        let info = self.info_template.render(payment);
+        let started = Instant::now();
+
+        let (client_name, client_info) = (name.to_string(), info.clone());
+        let result = self
+            .update(move |data| {
+                match data.clients.iter_mut().find(|c| c.name == client_name) {
+                    Some(client) => client.info = client_info,
+                    None => return Err(format!("client '{client_name}' doesn't exist")),
+                }
+                Ok(())
+            })
+            .await;
+
+        self.record(
+            &["set-info", "--name", name, "--info", &info],
+            &result,
+            started,
+        );
+        result
+    }
+
+    async fn get_client_config(&self, name: &str) -> Result<ClientConfig, String> {
+        self.cli.get_client_config(name).await
+    }
+}
@@ -0,0 +1,121 @@
+use super::{ClientConfig, PaymentInfo, Runner};
+use crate::metrics::observe_runner_command;
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// talks to a manjaliof agent running next to manjaliof on another server,
+/// every endpoint takes json and answers the same way backend itself does:
+/// `{ "success": bool, "message": string, "data": any }`
+pub struct HttpAgent {
+    url: String,
+    token: String,
+    client: Client,
+}
+
+#[derive(Deserialize)]
+struct AgentResult<T> {
+    success: bool,
+    message: String,
+    data: Option<T>,
+}
+
+#[derive(Serialize)]
+struct NamesArgs<'a> {
+    names: &'a [String],
+}
+
+#[derive(Serialize)]
+struct NameArgs<'a> {
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct MakeClientPaidArgs<'a> {
+    name: &'a str,
+    payment: &'a PaymentInfo,
+}
+
+impl HttpAgent {
+    pub fn new(url: &str, token: &str, timeout: Duration) -> Self {
+        let client = Client::builder().timeout(timeout).build().unwrap();
+        HttpAgent {
+            url: url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            client,
+        }
+    }
+
+    async fn call<A, T>(&self, endpoint: &str, args: &A) -> Result<Option<T>, String>
+    where
+        A: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let started = Instant::now();
+        let resp = self
+            .client
+            .post(format!("{}/{endpoint}", self.url))
+            .bearer_auth(&self.token)
+            .json(args)
+            .send()
+            .await
+            .map_err(|e| {
+                observe_runner_command("remote", endpoint, "error", started.elapsed());
+                format!("send failed: {e}")
+            })?;
+
+        let status = resp.status();
+        observe_runner_command("remote", endpoint, status.as_str(), started.elapsed());
+        info!(
+            url = %self.url,
+            endpoint,
+            status = status.as_u16(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "agent is called"
+        );
+        let resp = resp
+            .text()
+            .await
+            .map_err(|e| format!("receiving failed: {e}"))?;
+        if !status.is_success() {
+            return Err(format!("agent responded with '{status}': {resp}"));
+        }
+
+        let result: AgentResult<T> = serde_json::from_str(&resp)
+            .map_err(|e| format!("desrializing '{resp}' failed: {e}"))?;
+        if result.success {
+            Ok(result.data)
+        } else {
+            Err(result.message)
+        }
+    }
+}
+
+#[async_trait]
+impl Runner for HttpAgent {
+    async fn validate_clients(&self, names: &[String]) -> Result<(), String> {
+        self.call::<_, ()>("validate_clients", &NamesArgs { names })
+            .await?;
+        Ok(())
+    }
+
+    async fn find_clients(&self, names: &[String]) -> Result<Vec<String>, String> {
+        Ok(self
+            .call("find_clients", &NamesArgs { names })
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn make_client_paid(&self, name: &str, payment: &PaymentInfo) -> Result<(), String> {
+        self.call::<_, ()>("make_client_paid", &MakeClientPaidArgs { name, payment })
+            .await?;
+        Ok(())
+    }
+
+    async fn get_client_config(&self, name: &str) -> Result<ClientConfig, String> {
+        self.call("client_config", &NameArgs { name })
+            .await?
+            .ok_or_else(|| "agent didn't return config".to_string())
+    }
+}
@@ -0,0 +1,343 @@
+use super::{
+    journal::JournalSender,
+    manjaliof::{Manjaliof, ManjaliofConfig, SshConfig},
+    remote::HttpAgent,
+    ClientConfig, PaymentInfo, Runner,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tracing::error;
+
+/// tokens of agents can only be read from variables with this prefix, so
+/// other secrets of backend are never sent to them
+pub const AGENT_TOKEN_PREFIX: &str = "MANJALIOF_AGENT_TOKEN_";
+
+/// token of an agent from environment variable `token_env`
+pub fn agent_token(token_env: &str) -> Result<String, String> {
+    if !token_env.starts_with(AGENT_TOKEN_PREFIX) {
+        return Err(format!(
+            "token_env '{token_env}' should start with '{AGENT_TOKEN_PREFIX}'"
+        ));
+    }
+    env::var(token_env).map_err(|_| format!("environment variable '{token_env}' is not set"))
+}
+
+/// how backend reaches manjaliof of a server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum Transport {
+    /// manjaliof binary on the same host as backend
+    Cli,
+    Ssh(SshConfig),
+    /// token of agent is read from environment variable `token_env`, so it's
+    /// not kept in database, see [`agent_token`]
+    Http {
+        url: String,
+        token_env: String,
+        timeout_secs: Option<u64>,
+    },
+}
+
+impl Transport {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Transport::Cli => "cli",
+            Transport::Ssh(_) => "ssh",
+            Transport::Http { .. } => "http",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ServerSpec {
+    pub name: String,
+    #[serde(default)]
+    pub region: Option<String>,
+    /// maximum clients the server should have, only informational
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    #[serde(flatten)]
+    pub transport: Transport,
+}
+
+impl ServerSpec {
+    pub fn build(
+        &self,
+        base: &ManjaliofConfig,
+        journal: Option<&JournalSender>,
+    ) -> Arc<dyn Runner> {
+        let manjaliof = |config: ManjaliofConfig| -> Arc<dyn Runner> {
+            let manjaliof = Manjaliof::with_config(config);
+            match journal {
+                Some(journal) => Arc::new(manjaliof.with_journal(journal.clone())),
+                None => Arc::new(manjaliof),
+            }
+        };
+
+        match &self.transport {
+            Transport::Cli => manjaliof(base.clone()),
+            Transport::Ssh(ssh) => manjaliof(ManjaliofConfig {
+                ssh: Some(ssh.clone()),
+                ..base.clone()
+            }),
+            Transport::Http {
+                url,
+                token_env,
+                timeout_secs,
+            } => {
+                let timeout = timeout_secs.map_or(base.timeout, Duration::from_secs);
+                let token = agent_token(token_env).unwrap_or_else(|error| {
+                    error!("cannot get token of server '{}': {error}", self.name);
+                    String::new()
+                });
+                Arc::new(HttpAgent::new(url, &token, timeout))
+            }
+        }
+    }
+
+    /// servers in `MANJALIOF_SERVERS` as a json array, empty if it's not set
+    pub fn from_env() -> Vec<ServerSpec> {
+        match env::var("MANJALIOF_SERVERS") {
+            Ok(servers) => serde_json::from_str(&servers).unwrap_or_else(|e| {
+                panic!("environment variable 'MANJALIOF_SERVERS' is not valid: {e}")
+            }),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Health {
+    /// `None` until the server is checked for the first time
+    pub healthy: Option<bool>,
+    pub message: String,
+    pub latency_ms: Option<u64>,
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+pub struct Server {
+    pub spec: ServerSpec,
+    pub runner: Arc<dyn Runner>,
+    health: RwLock<Health>,
+}
+
+impl Server {
+    pub fn new(spec: ServerSpec, runner: Arc<dyn Runner>) -> Self {
+        Server {
+            spec,
+            runner,
+            health: RwLock::new(Health::default()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    pub async fn health(&self) -> Health {
+        self.health.read().await.clone()
+    }
+
+    /// a server is healthy when it can list its clients
+    pub async fn check_health(&self) -> Health {
+        let started = Instant::now();
+        let result = self.runner.find_clients(&[]).await;
+        let health = Health {
+            healthy: Some(result.is_ok()),
+            message: result.err().unwrap_or_default(),
+            latency_ms: Some(started.elapsed().as_millis() as u64),
+            checked_at: Some(Utc::now()),
+        };
+        *self.health.write().await = health.clone();
+        health
+    }
+}
+
+/// spreads clients over several servers, each client is expected to live on
+/// exactly one of them, servers can be added and removed while running
+pub struct Router {
+    base: ManjaliofConfig,
+    journal: Option<JournalSender>,
+    servers: RwLock<Vec<Arc<Server>>>,
+    /// client name to name of the server that has it
+    owners: RwLock<HashMap<String, String>>,
+}
+
+impl Router {
+    /// `base` is used for servers that run manjaliof binary, locally or over ssh
+    pub fn new(base: ManjaliofConfig, journal: Option<JournalSender>) -> Self {
+        Router {
+            base,
+            journal,
+            servers: RwLock::new(Vec::new()),
+            owners: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn set_server(&self, spec: ServerSpec) {
+        let runner = spec.build(&self.base, self.journal.as_ref());
+        self.set_server_runner(spec, runner).await;
+    }
+
+    /// adds the server or replaces the one with the same name
+    pub async fn set_server_runner(&self, spec: ServerSpec, runner: Arc<dyn Runner>) {
+        let server = Arc::new(Server::new(spec, runner));
+        let mut servers = self.servers.write().await;
+        match servers.iter_mut().find(|s| s.name() == server.name()) {
+            Some(old) => *old = server,
+            None => servers.push(server),
+        }
+    }
+
+    pub async fn remove_server(&self, name: &str) -> bool {
+        let mut servers = self.servers.write().await;
+        let count = servers.len();
+        servers.retain(|server| server.name() != name);
+        self.owners.write().await.retain(|_, owner| owner != name);
+        servers.len() != count
+    }
+
+    pub async fn servers(&self) -> Vec<Arc<Server>> {
+        self.servers.read().await.clone()
+    }
+
+    /// asks every healthy server which of `names` it has, names that no
+    /// server has are left out. servers that are down or cannot be searched
+    /// are skipped so one of them doesn't stop the others, but it fails when
+    /// no server answers or a client turns up away from the skipped server it
+    /// was last found on, since it may still be there too
+    async fn locate(&self, names: &[String]) -> Result<HashMap<String, Arc<Server>>, String> {
+        let mut owners: HashMap<String, Arc<Server>> = HashMap::new();
+        let mut skipped: Vec<(String, String)> = Vec::new();
+        let mut answered = false;
+        for server in self.servers().await {
+            let found = match server.health().await.healthy {
+                Some(false) => Err("it's unhealthy".to_string()),
+                _ => server.runner.find_clients(names).await,
+            };
+            let found = match found {
+                Ok(found) => found,
+                Err(error) => {
+                    error!("cannot search server '{}': {error}", server.name());
+                    skipped.push((server.name().to_string(), error));
+                    continue;
+                }
+            };
+            answered = true;
+
+            for name in found {
+                if let Some(other) = owners.insert(name.clone(), server.clone()) {
+                    return Err(format!(
+                        "client '{name}' exists on both '{}' and '{}'",
+                        other.name(),
+                        server.name()
+                    ));
+                }
+            }
+        }
+        if !answered {
+            let errors: Vec<String> = skipped
+                .iter()
+                .map(|(server, error)| format!("'{server}': {error}"))
+                .collect();
+            return Err(match errors.is_empty() {
+                true => "no server is set".to_string(),
+                false => format!("no server answered: {}", errors.join(", ")),
+            });
+        }
+
+        let mut cache = self.owners.write().await;
+        for (name, server) in &owners {
+            if let Some(last) = cache.get(name).filter(|last| *last != server.name()) {
+                if skipped.iter().any(|(skipped, _)| skipped == last) {
+                    return Err(format!(
+                        "client '{name}' exists on both '{last}' and '{}'",
+                        server.name()
+                    ));
+                }
+            }
+        }
+        for (name, server) in &owners {
+            cache.insert(name.clone(), server.name().to_string());
+        }
+        Ok(owners)
+    }
+
+    async fn owner(&self, name: &str) -> Result<Arc<Server>, String> {
+        let cached = self.owners.read().await.get(name).cloned();
+        let server = match cached {
+            Some(owner) => self
+                .servers()
+                .await
+                .into_iter()
+                .find(|server| server.name() == owner),
+            None => None,
+        };
+
+        match server {
+            Some(server) => Ok(server),
+            None => self
+                .locate(&[name.to_string()])
+                .await?
+                .remove(name)
+                .ok_or_else(|| format!("cannot find server of client '{name}'")),
+        }
+    }
+}
+
+#[async_trait]
+impl Runner for Router {
+    async fn validate_clients(&self, names: &[String]) -> Result<(), String> {
+        let owners = self.locate(names).await?;
+        if names.iter().any(|name| !owners.contains_key(name)) {
+            return Err("cannot find clients".to_string());
+        }
+
+        for server in self.servers().await {
+            let server_names: Vec<String> = names
+                .iter()
+                .filter(|name| owners[*name].name() == server.name())
+                .cloned()
+                .collect();
+            if server_names.is_empty() {
+                continue;
+            }
+
+            server
+                .runner
+                .validate_clients(&server_names)
+                .await
+                .map_err(|e| format!("{}: {e}", server.name()))?;
+        }
+        Ok(())
+    }
+
+    async fn find_clients(&self, names: &[String]) -> Result<Vec<String>, String> {
+        Ok(self.locate(names).await?.into_keys().collect())
+    }
+
+    async fn make_client_paid(&self, name: &str, payment: &PaymentInfo) -> Result<(), String> {
+        let server = self.owner(name).await?;
+        server
+            .runner
+            .make_client_paid(name, payment)
+            .await
+            .map_err(|e| format!("{}: {e}", server.name()))
+    }
+
+    async fn get_client_config(&self, name: &str) -> Result<ClientConfig, String> {
+        let server = self.owner(name).await?;
+        server
+            .runner
+            .get_client_config(name)
+            .await
+            .map_err(|e| format!("{}: {e}", server.name()))
+    }
+}
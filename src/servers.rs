@@ -0,0 +1,254 @@
+use crate::{
+    audit::{self, Actor, Change},
+    db::{db_delete_server, db_list_servers, db_save_server, db_seed_servers, Db},
+    response::RequestResult,
+    runner::router::{agent_token, Health, Router, Server, ServerSpec, Transport},
+    token::{AdminRead, AdminToken, AdminWrite},
+};
+use rocket::{
+    fairing::{self, AdHoc},
+    serde::{json::Json, Serialize},
+    tokio::{self, time},
+    Build, Rocket, State,
+};
+use rocket_db_pools::{
+    sqlx::{SqliteConnection, SqlitePool},
+    Connection, Database,
+};
+use std::{env, sync::Arc, time::Duration};
+use tracing::{error, info};
+
+const DEFAULT_HEALTH_CHECK_SECS: u64 = 60;
+
+type RouterState = State<Arc<Router>>;
+
+/// loads servers of `router` from database, keeps their health up to date
+/// and mounts admin endpoints for managing them
+pub fn stage(router: Arc<Router>) -> AdHoc {
+    AdHoc::on_ignite("server registry", |rocket| async {
+        rocket
+            .manage(router)
+            .attach(AdHoc::try_on_ignite("load servers", load_servers))
+            .attach(AdHoc::try_on_ignite(
+                "health check interval",
+                |rocket| async {
+                    match health_check_interval() {
+                        Ok(interval) => Ok(rocket.manage(HealthCheckInterval(interval))),
+                        Err(error) => {
+                            error!("{error}");
+                            Err(rocket)
+                        }
+                    }
+                },
+            ))
+            .attach(AdHoc::on_liftoff("server health checks", |rocket| {
+                Box::pin(async move {
+                    let router = rocket.state::<Arc<Router>>().unwrap().clone();
+                    let interval = rocket.state::<HealthCheckInterval>().unwrap().0;
+                    let db = match Db::fetch(rocket) {
+                        Some(db) => (**db).clone(),
+                        None => return error!("cannot check servers: database is not set up"),
+                    };
+                    tokio::spawn(check_health_periodically(router, db, interval));
+                })
+            }))
+            .mount("/admin", routes![list_servers, set_server, delete_server])
+    })
+}
+
+async fn load_servers(rocket: Rocket<Build>) -> fairing::Result {
+    let (db, router) = match (Db::fetch(&rocket), rocket.state::<Arc<Router>>()) {
+        (Some(db), Some(router)) => (db, router),
+        _ => return Err(rocket),
+    };
+
+    let result = async {
+        db_seed_servers(db, &ServerSpec::from_env()).await?;
+        db_list_servers(db).await
+    }
+    .await;
+
+    match result {
+        Ok(servers) => {
+            for server in servers {
+                router.set_server(server).await;
+            }
+            Ok(rocket)
+        }
+        Err(error) => {
+            error!("cannot load servers: {error}");
+            Err(rocket)
+        }
+    }
+}
+
+struct HealthCheckInterval(Duration);
+
+/// `MANJALIOF_HEALTH_CHECK_SECS`, it should be a positive number
+fn health_check_interval() -> Result<Duration, String> {
+    let secs = match env::var("MANJALIOF_HEALTH_CHECK_SECS") {
+        Ok(secs) => secs.parse().ok().filter(|secs| *secs > 0).ok_or_else(|| {
+            "environment variable 'MANJALIOF_HEALTH_CHECK_SECS' is not a positive number"
+                .to_string()
+        })?,
+        Err(_) => DEFAULT_HEALTH_CHECK_SECS,
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+async fn check_health_periodically(router: Arc<Router>, db: SqlitePool, every: Duration) {
+    let actor = Actor::named("health checker");
+    let mut interval = time::interval(every);
+    loop {
+        interval.tick().await;
+        let mut db = match db.acquire().await {
+            Ok(db) => db,
+            Err(error) => {
+                error!("cannot check servers: {error}");
+                continue;
+            }
+        };
+        for server in router.servers().await {
+            check_health(&mut db, &actor, &server).await;
+        }
+    }
+}
+
+/// checks health of the server, servers that become unhealthy or healthy
+/// again are audited, the first check is only audited when it fails
+async fn check_health(db: &mut SqliteConnection, actor: &Actor, server: &Server) -> Health {
+    let before = server.health().await.healthy;
+    let health = server.check_health().await;
+    if health.healthy != before && !(before.is_none() && health.healthy == Some(true)) {
+        let state = |healthy: bool| if healthy { "healthy" } else { "unhealthy" };
+        let change = Change {
+            action: "server.health",
+            target: Some(&format!("server:{}", server.name())),
+            before: before.map(state),
+            after: health.healthy.map(state),
+            note: Some(health.message.as_str()).filter(|message| !message.is_empty()),
+            ..Default::default()
+        };
+        audit::record(db, actor, &change).await;
+    }
+    health
+}
+
+/// what admins see of a server, transport details are left out since they
+/// may have secrets in them
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ServerStatus {
+    name: String,
+    region: Option<String>,
+    capacity: Option<u32>,
+    transport: &'static str,
+    health: Health,
+}
+
+#[get("/servers?<check>")]
+async fn list_servers(
+    _token: AdminToken<AdminRead>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    check: Option<bool>,
+    router: &RouterState,
+) -> Json<RequestResult<Vec<ServerStatus>>> {
+    let mut statuses = Vec::new();
+    for server in router.servers().await {
+        let health = match check {
+            Some(true) => check_health(&mut db, &actor, &server).await,
+            _ => server.health().await,
+        };
+        statuses.push(ServerStatus {
+            name: server.spec.name.clone(),
+            region: server.spec.region.clone(),
+            capacity: server.spec.capacity,
+            transport: server.spec.transport.kind(),
+            health,
+        });
+    }
+
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(statuses),
+    })
+}
+
+#[post("/servers", data = "<spec>")]
+async fn set_server(
+    token: AdminToken<AdminWrite>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    spec: Json<ServerSpec>,
+    router: &RouterState,
+) -> Json<RequestResult> {
+    try_in_request!((!spec.name.is_empty())
+        .then_some(())
+        .ok_or("server name is empty".to_string()));
+    match &spec.transport {
+        Transport::Ssh(ssh) => try_in_request!((!ssh.is_customized())
+            .then_some(())
+            .ok_or("ssh program and options can only be set in 'MANJALIOF_SERVERS'".to_string())),
+        Transport::Http { token_env, .. } => try_in_request!(agent_token(token_env).map(|_| ())),
+        Transport::Cli => {}
+    }
+
+    try_in_request!(db_save_server(&mut db, &spec)
+        .await
+        .map_err(|e| format!("cannot save server: {e}")));
+    // transport is not recorded since it may have secrets of the server
+    let change = Change {
+        action: "server.set",
+        target: Some(&format!("server:{}", spec.name)),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+    info!(
+        "server '{}' is set by token '{}'",
+        spec.name, token.caller.name
+    );
+    router.set_server(spec.into_inner()).await;
+
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
+
+#[delete("/servers/<name>")]
+async fn delete_server(
+    token: AdminToken<AdminWrite>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    name: &str,
+    router: &RouterState,
+) -> Json<RequestResult> {
+    let deleted = try_in_request!(db_delete_server(&mut db, name)
+        .await
+        .map_err(|e| format!("cannot delete server: {e}")));
+    router.remove_server(name).await;
+    if deleted {
+        let change = Change {
+            action: "server.delete",
+            target: Some(&format!("server:{name}")),
+            ..Default::default()
+        };
+        audit::record(&mut db, &actor, &change).await;
+    }
+    info!(
+        "server '{name}' is deleted by token '{}'",
+        token.caller.name
+    );
+
+    try_in_request!(deleted
+        .then_some(())
+        .ok_or(format!("server '{name}' doesn't exist")));
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
@@ -1,19 +1,65 @@
-use super::{payment::MockPayment, rocket, runner::MockRunner, Db};
-use mockall::predicate::{always, eq};
+use super::{
+    audit::Actor,
+    bot::{Bot, BotConfig},
+    checkout::{Checkout, Verified},
+    cli,
+    cors::Cors,
+    customer::SESSION_COOKIE,
+    health::{check_gateway, check_servers},
+    notifier::{Event, Notifier, Notify, Target},
+    payment::{parse_authority_ttl, MockPayment, Payment, PaymentReceipt},
+    rate_limit::{parse_limits, LimitKey, RateLimiter},
+    rocket,
+    runner::{
+        journal::{self, JournalEntry},
+        manjaliof::{InfoTemplate, Manjaliof, ManjaliofConfig, SshConfig},
+        native::NativeManjaliof,
+        remote::HttpAgent,
+        router::{Router, ServerSpec, Transport},
+        ClientConfig, MockRunner, PaymentInfo, Runner,
+    },
+    servers,
+    token::{issue_token, revoke_token, rotate_token, Scope},
+    webhook::Webhooks,
+    Db,
+};
+use chrono::{Duration as ChronoDuration, TimeZone, Utc};
+use mockall::predicate::{always, eq, function};
 use rocket::{
-    http::{Header, Status},
+    http::{ContentType, Header, Status},
     local::blocking::Client,
 };
-use rocket_db_pools::{sqlx::Executor, Database};
+use rocket_db_pools::{
+    sqlx::{self, Executor, Row},
+    Database,
+};
+use serde_json::Value;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Once,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-fn run_test<T>(test: T) -> ()
+fn run_test<T>(test: T)
 where
-    T: FnOnce(MockPayment, MockRunner) -> (),
+    T: FnOnce(MockPayment, MockRunner),
 {
     use std::env;
     if env::var("MANJALIOF_BACKEND_TOKEN").is_err() {
         env::set_var("MANJALIOF_BACKEND_TOKEN", "somestrongtoken");
     }
+    if env::var("MANJALIOF_TRUSTED_PROXIES").is_err() {
+        env::set_var("MANJALIOF_TRUSTED_PROXIES", "127.0.0.1");
+    }
+    // most tests pay for clients that aren't linked to a customer
+    if env::var("MANJALIOF_CUSTOMER_REQUIRED").is_err() {
+        env::set_var("MANJALIOF_CUSTOMER_REQUIRED", "false");
+    }
 
     reset_db();
     let payment = MockPayment::new();
@@ -21,11 +67,16 @@ where
     test(payment, runner);
 }
 
+/// tests run in parallel on the same database, so it's only cleaned once
+/// before the first test, otherwise transactions of running tests disappear
 fn reset_db() {
-    let client = Client::untracked(rocket(MockPayment::new(), MockRunner::new())).unwrap();
-    let db = Db::fetch(client.rocket()).unwrap();
-    rocket::async_test(async move {
-        db.execute("DELETE FROM transactions").await.unwrap();
+    static RESET: Once = Once::new();
+    RESET.call_once(|| {
+        let client = Client::untracked(rocket(MockPayment::new(), MockRunner::new())).unwrap();
+        let db = Db::fetch(client.rocket()).unwrap();
+        rocket::async_test(async move {
+            db.execute("DELETE FROM transactions").await.unwrap();
+        });
     });
 }
 
@@ -152,13 +203,21 @@ fn create_payment() {
             .body(r#"{ "clients": ["someone", "anotherone"] }"#)
             .dispatch();
         assert_eq!(res.status(), Status::Ok);
-        assert_eq!(
-            res.into_string().unwrap(),
-            format!(r#"{{"success":true,"message":"{authority}"}}"#)
-        );
+        let res: Value = res.into_json().unwrap();
+        assert_eq!(res["success"], true);
+        assert_eq!(res["message"], authority.as_str());
+        assert_eq!(res["data"]["config_token"].as_str().unwrap().len(), 32);
     });
 }
 
+fn receipt() -> PaymentReceipt {
+    PaymentReceipt {
+        gateway: "zarinpal".to_string(),
+        ref_id: "201".to_string(),
+        fee: 5000,
+    }
+}
+
 fn generate_random_authority() -> String {
     let random_number: String = rand::random::<u32>().to_string();
     let zeros = "0".repeat(35 - random_number.len());
@@ -188,29 +247,36 @@ fn create_and_verify_payment() {
             .body(r#"{ "clients": ["someone", "anotherone"] }"#)
             .dispatch();
         assert_eq!(res.status(), Status::Ok);
-        assert_eq!(
-            res.into_string().unwrap(),
-            r#"{"success":true,"message":"generated_authority"}"#
-        );
+        let res: Value = res.into_json().unwrap();
+        assert_eq!(res["success"], true);
+        assert_eq!(res["message"], "generated_authority");
 
         let mut payment = MockPayment::new();
         payment
             .expect_verify()
             .with(eq("generated_authority"), always())
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _| Ok(receipt()));
 
         let mut runner = MockRunner::new();
         runner
             .expect_make_client_paid()
-            .with(eq("someone"))
+            .with(
+                eq("someone"),
+                function(|payment: &PaymentInfo| {
+                    payment.authority == "generated_authority"
+                        && payment.ref_id == "201"
+                        && payment.gateway == "zarinpal"
+                        && payment.amount == 2 * 55 * 10000
+                }),
+            )
             .times(1)
-            .returning(|_| Ok(()));
+            .returning(|_, _| Ok(()));
         runner
             .expect_make_client_paid()
-            .with(eq("anotherone"))
+            .with(eq("anotherone"), always())
             .times(1)
-            .returning(|_| Ok(()));
+            .returning(|_, _| Ok(()));
 
         let client = Client::untracked(rocket(payment, runner)).unwrap();
         let res = client
@@ -224,3 +290,2972 @@ fn create_and_verify_payment() {
         );
     });
 }
+
+#[test]
+fn fulfilled_payments_should_not_be_verified_again() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        payment
+            .expect_verify()
+            .times(1)
+            .returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        client
+            .post("/create_payment")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .body(r#"{ "clients": ["someone"] }"#)
+            .dispatch();
+        for _ in 0..2 {
+            let res = client
+                .post("/verify_payment")
+                .body(format!(r#"{{ "authority": "{authority}" }}"#))
+                .dispatch();
+            assert_eq!(
+                res.into_string().unwrap(),
+                r#"{"success":true,"message":""}"#
+            );
+        }
+    });
+}
+
+fn create_paid_transaction(authority: &str) -> String {
+    let mut payment = MockPayment::new();
+    let authority_clone = authority.to_string();
+    payment
+        .expect_request_payment_authority()
+        .returning(move |_, _| Ok(authority_clone.clone()));
+    payment.expect_verify().returning(|_, _| Ok(receipt()));
+
+    let mut runner = MockRunner::new();
+    runner.expect_validate_clients().returning(|_| Ok(()));
+    runner.expect_make_client_paid().returning(|_, _| Ok(()));
+
+    let client = Client::untracked(rocket(payment, runner)).unwrap();
+    let res: Value = client
+        .post("/create_payment")
+        .header(Header::new("auth_token", "somestrongtoken"))
+        .body(r#"{ "clients": ["someone", "anotherone"] }"#)
+        .dispatch()
+        .into_json()
+        .unwrap();
+    let config_token = res["data"]["config_token"].as_str().unwrap().to_string();
+
+    let res = client
+        .post("/verify_payment")
+        .body(format!(r#"{{ "authority": "{authority}" }}"#))
+        .dispatch();
+    assert_eq!(
+        res.into_string().unwrap(),
+        r#"{"success":true,"message":""}"#
+    );
+    config_token
+}
+
+#[test]
+fn client_configs_should_fail_when_token_is_wrong() {
+    run_test(|payment, runner| {
+        let authority = generate_random_authority();
+        create_paid_transaction(&authority);
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let res = client
+            .get(format!("/client_configs/{authority}?token=wrong_token"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"cannot find transaction: authority or token is wrong"}"#
+        );
+    });
+}
+
+#[test]
+fn client_configs_should_fail_when_not_paid() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner.expect_get_client_config().never();
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let res: Value = client
+            .post("/create_payment")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .body(r#"{ "clients": ["someone"] }"#)
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let config_token = res["data"]["config_token"].as_str().unwrap();
+
+        let res = client
+            .get(format!("/client_configs/{authority}?token={config_token}"))
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"cannot find transaction: transaction is not fulfilled yet, it's 'created'"}"#
+        );
+    });
+}
+
+#[test]
+fn client_configs() {
+    run_test(|payment, mut runner| {
+        let authority = generate_random_authority();
+        let config_token = create_paid_transaction(&authority);
+
+        runner
+            .expect_get_client_config()
+            .times(2)
+            .returning(|name| {
+                Ok(ClientConfig {
+                    name: name.to_string(),
+                    links: vec![format!("vless://{name}@example.com:443")],
+                })
+            });
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let res: Value = client
+            .get(format!(
+                "/client_configs/{authority}?token={config_token}&format=png"
+            ))
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["success"], true);
+
+        let configs = res["data"].as_array().unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0]["name"], "someone");
+        assert_eq!(
+            configs[0]["links"][0]["link"],
+            "vless://someone@example.com:443"
+        );
+        assert!(configs[0]["links"][0]["qr_code"]
+            .as_str()
+            .unwrap()
+            .starts_with("data:image/png;base64,"));
+        assert_eq!(configs[1]["name"], "anotherone");
+    });
+}
+
+#[test]
+fn info_template_should_render_placeholders() {
+    let payment = PaymentInfo {
+        authority: "A0000000000000000000000000000012345".to_string(),
+        ref_id: "201".to_string(),
+        amount: 550000,
+        gateway: "zarinpal".to_string(),
+        date: Utc.with_ymd_and_hms(2023, 1, 20, 10, 30, 0).unwrap(),
+    };
+
+    let template = InfoTemplate::new("HOSSOBBEED", "{marker} (site)").unwrap();
+    assert_eq!(template.render(&payment), "HOSSOBBEED (site)");
+
+    let template = InfoTemplate::new(
+        "PAID",
+        "{marker} {gateway}:{ref_id} {amount} {date} {authority}",
+    )
+    .unwrap();
+    assert_eq!(
+        template.render(&payment),
+        "PAID zarinpal:201 550000 2023-01-20 A0000000000000000000000000000012345"
+    );
+
+    // values are not searched for placeholders again
+    let payment = PaymentInfo {
+        gateway: "{authority}".to_string(),
+        ref_id: "{marker}".to_string(),
+        ..payment
+    };
+    assert_eq!(
+        template.render(&payment),
+        "PAID {authority}:{marker} 550000 2023-01-20 A0000000000000000000000000000012345"
+    );
+}
+
+#[test]
+fn info_template_should_fail_on_unknown_placeholders() {
+    assert!(InfoTemplate::new("PAID", "{marker} {card}").is_err());
+    assert!(InfoTemplate::new("PAID", "{marker").is_err());
+}
+
+fn manjaliof_running_script(script: &str) -> Manjaliof {
+    Manjaliof::with_config(ManjaliofConfig {
+        binary: "sh".into(),
+        args: vec![
+            "-c".to_string(),
+            script.to_string(),
+            "manjaliof".to_string(),
+        ],
+        timeout: Duration::from_millis(500),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn manjaliof_should_parse_list() {
+    let manjaliof = manjaliof_running_script(
+        r#"[ "$1" = "list" ] && printf 'arian 1 2 NOTPAID\nali 1 2 HOSSOBBEED\n'"#,
+    );
+    rocket::async_test(async move {
+        assert!(manjaliof
+            .validate_clients(&["arian".to_string()])
+            .await
+            .is_ok());
+        assert_eq!(
+            manjaliof.validate_clients(&["ali".to_string()]).await,
+            Err("client 'ali' is not notpaid, it's 'HOSSOBBEED'".to_string())
+        );
+        assert_eq!(
+            manjaliof.validate_clients(&["nobody".to_string()]).await,
+            Err("cannot find clients".to_string())
+        );
+    });
+}
+
+#[test]
+fn manjaliof_should_pass_env_and_args() {
+    let manjaliof = Manjaliof::with_config(ManjaliofConfig {
+        binary: "sh".into(),
+        args: vec![
+            "-c".to_string(),
+            r#"echo "vless://$MANJALIOF_DATA/$3""#.to_string(),
+            "manjaliof".to_string(),
+        ],
+        envs: vec![("MANJALIOF_DATA".to_string(), "data".to_string())],
+        ..Default::default()
+    });
+    rocket::async_test(async move {
+        let config = manjaliof.get_client_config("arian").await.unwrap();
+        assert_eq!(config.links, vec!["vless://data/arian".to_string()]);
+    });
+}
+
+#[test]
+fn manjaliof_should_report_command_errors() {
+    rocket::async_test(async move {
+        let manjaliof = manjaliof_running_script("echo 'no such client' >&2; exit 3");
+        assert_eq!(
+            manjaliof.get_client_config("arian").await,
+            Err("exited with code 3: no such client\n".to_string())
+        );
+
+        let manjaliof = manjaliof_running_script("exec sleep 5");
+        assert_eq!(
+            manjaliof.get_client_config("arian").await,
+            Err("timed out after 0.5 seconds".to_string())
+        );
+
+        let manjaliof = Manjaliof::with_config(ManjaliofConfig {
+            binary: "/nonexistent/manjaliof".into(),
+            ..Default::default()
+        });
+        assert!(manjaliof
+            .get_client_config("arian")
+            .await
+            .unwrap_err()
+            .starts_with("cannot spawn '/nonexistent/manjaliof'"));
+    });
+}
+
+fn payment_info() -> PaymentInfo {
+    PaymentInfo {
+        authority: generate_random_authority(),
+        ref_id: "201".to_string(),
+        amount: 550000,
+        gateway: "zarinpal".to_string(),
+        date: Utc::now(),
+    }
+}
+
+#[test]
+fn manjaliof_should_serialize_and_journal_mutations() {
+    let lock_dir = std::env::temp_dir().join(generate_random_authority());
+    let script = format!(
+        "mkdir {0} || exit 1; sleep 0.1; rmdir {0}; echo done",
+        lock_dir.display()
+    );
+    let (journal, mut journal_receiver) = journal::channel();
+    let manjaliof = manjaliof_running_script(&script).with_journal(journal);
+
+    rocket::async_test(async move {
+        let payment = payment_info();
+        let (first, second) = rocket::tokio::join!(
+            manjaliof.make_client_paid("someone", &payment),
+            manjaliof.make_client_paid("anotherone", &payment)
+        );
+        assert_eq!(first, Ok(()));
+        assert_eq!(second, Ok(()));
+
+        for name in ["someone", "anotherone"] {
+            let entry = journal_receiver.try_recv().unwrap();
+            assert_eq!(entry.command, "set-info");
+            assert_eq!(entry.args, ["--name", name, "--info", "HOSSOBBEED (site)"]);
+            assert_eq!(entry.exit_code, Some(0));
+            assert_eq!(entry.stdout, "done\n");
+        }
+        assert!(journal_receiver.try_recv().is_err());
+    });
+}
+
+#[test]
+fn journal_writer_should_store_entries() {
+    let (journal, journal_receiver) = journal::channel();
+    let rocket =
+        rocket(MockPayment::new(), MockRunner::new()).attach(Db::journal_writer(journal_receiver));
+    let client = Client::untracked(rocket).unwrap();
+
+    let command = generate_random_authority();
+    journal
+        .send(JournalEntry {
+            command: command.clone(),
+            args: vec!["--name".to_string(), "arian".to_string()],
+            exit_code: Some(1),
+            stdout: String::new(),
+            stderr: "no such client".to_string(),
+            duration: Duration::from_millis(42),
+            date: Utc::now(),
+        })
+        .unwrap();
+
+    let db = Db::fetch(client.rocket()).unwrap();
+    rocket::async_test(async move {
+        for _ in 0..50 {
+            let query = sqlx::query(
+                "SELECT args, exit_code, stderr, duration_ms FROM runner_journal WHERE command=?",
+            )
+            .bind(&command);
+            if let Some(row) = db.fetch_optional(query).await.unwrap() {
+                assert_eq!(row.get::<String, _>(0), r#"["--name","arian"]"#);
+                assert_eq!(row.get::<Option<i32>, _>(1), Some(1));
+                assert_eq!(row.get::<String, _>(2), "no such client");
+                assert_eq!(row.get::<i64, _>(3), 42);
+                return;
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("journal entry is not written");
+    });
+}
+
+fn native_manjaliof_with_data(data: &str) -> (NativeManjaliof, std::path::PathBuf) {
+    let data_dir = std::env::temp_dir().join(generate_random_authority());
+    std::fs::create_dir(&data_dir).unwrap();
+    std::fs::write(data_dir.join("data.json"), data).unwrap();
+    let cli = manjaliof_running_script(r#"echo "vless://$3@example.com""#);
+    (NativeManjaliof::new(&data_dir, cli), data_dir)
+}
+
+#[test]
+fn native_manjaliof_should_validate_clients() {
+    let (manjaliof, data_dir) = native_manjaliof_with_data(
+        r#"{ "clients": [
+            { "name": "arian", "info": "NOTPAID", "uuid": "1" },
+            { "name": "ali", "info": "HOSSOBBEED (site)", "uuid": "2" }
+        ] }"#,
+    );
+    rocket::async_test(async move {
+        assert!(manjaliof
+            .validate_clients(&["arian".to_string()])
+            .await
+            .is_ok());
+        assert_eq!(
+            manjaliof.validate_clients(&["ali".to_string()]).await,
+            Err("client 'ali' is not notpaid, it's 'HOSSOBBEED (site)'".to_string())
+        );
+        assert_eq!(
+            manjaliof
+                .validate_clients(&["arian".to_string(), "nobody".to_string()])
+                .await,
+            Err("cannot find clients".to_string())
+        );
+        assert_eq!(
+            manjaliof.get_client_config("arian").await.unwrap().links,
+            vec!["vless://arian@example.com".to_string()]
+        );
+    });
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[test]
+fn native_manjaliof_should_make_client_paid() {
+    let (manjaliof, data_dir) = native_manjaliof_with_data(
+        r#"{ "version": 2, "clients": [
+            { "name": "arian", "info": "NOTPAID", "uuid": "1" },
+            { "name": "ali", "info": "NOTPAID", "uuid": "2" }
+        ] }"#,
+    );
+    let (journal, mut journal_receiver) = journal::channel();
+    let manjaliof = manjaliof.with_journal(journal);
+
+    rocket::async_test(async move {
+        let payment = payment_info();
+        assert_eq!(manjaliof.make_client_paid("arian", &payment).await, Ok(()));
+        assert_eq!(
+            manjaliof.make_client_paid("nobody", &payment).await,
+            Err("client 'nobody' doesn't exist".to_string())
+        );
+
+        let data = std::fs::read_to_string(data_dir.join("data.json")).unwrap();
+        let data: Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["version"], 2);
+        assert_eq!(data["clients"][0]["info"], "HOSSOBBEED (site)");
+        assert_eq!(data["clients"][0]["uuid"], "1");
+        assert_eq!(data["clients"][1]["info"], "NOTPAID");
+        assert!(!data_dir.join("data.json.tmp").exists());
+
+        let entry = journal_receiver.try_recv().unwrap();
+        assert_eq!(
+            entry.args,
+            ["--name", "arian", "--info", "HOSSOBBEED (site)"]
+        );
+        assert_eq!(entry.exit_code, Some(0));
+        let entry = journal_receiver.try_recv().unwrap();
+        assert_eq!(entry.exit_code, None);
+        assert_eq!(entry.stderr, "client 'nobody' doesn't exist");
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    });
+}
+
+#[test]
+fn native_manjaliof_should_not_lose_concurrent_updates() {
+    let (manjaliof, data_dir) = native_manjaliof_with_data(
+        r#"{ "clients": [
+            { "name": "arian", "info": "NOTPAID", "uuid": "1" },
+            { "name": "ali", "info": "NOTPAID", "uuid": "2" },
+            { "name": "sara", "info": "NOTPAID", "uuid": "3" }
+        ] }"#,
+    );
+    // another process that writes the same file only shares the file lock
+    let other = NativeManjaliof::new(
+        &data_dir,
+        manjaliof_running_script(r#"echo "vless://$3@example.com""#),
+    );
+
+    rocket::async_test(async move {
+        let payment = payment_info();
+        let (arian, ali, sara) = rocket::tokio::join!(
+            manjaliof.make_client_paid("arian", &payment),
+            manjaliof.make_client_paid("ali", &payment),
+            other.make_client_paid("sara", &payment),
+        );
+        assert_eq!((arian, ali, sara), (Ok(()), Ok(()), Ok(())));
+
+        let data = std::fs::read_to_string(data_dir.join("data.json")).unwrap();
+        let data: Value = serde_json::from_str(&data).unwrap();
+        for index in 0..3 {
+            assert_eq!(data["clients"][index]["info"], "HOSSOBBEED (site)");
+        }
+        std::fs::remove_dir_all(data_dir).unwrap();
+    });
+}
+
+/// answers each request with the next `(status, body)` and passes the
+/// requests it got as `(request line and headers, body)` to the receiver
+fn stub_server(responses: Vec<(u16, String)>) -> (String, mpsc::Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for (status, body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut head = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                head.push_str(&line);
+            }
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+            sender
+                .send((head, String::from_utf8(request_body).unwrap()))
+                .unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 {status} STUB\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+    });
+    (url, receiver)
+}
+
+fn router_with(servers: Vec<(&str, MockRunner)>) -> Router {
+    let router = Router::new(ManjaliofConfig::default(), None);
+    rocket::async_test(async {
+        for (name, runner) in servers {
+            let spec = ServerSpec {
+                name: name.to_string(),
+                region: None,
+                capacity: None,
+                transport: Transport::Cli,
+            };
+            router.set_server_runner(spec, Arc::new(runner)).await;
+        }
+    });
+    router
+}
+
+#[test]
+fn router_should_dispatch_to_owner_server() {
+    let mut de = MockRunner::new();
+    de.expect_find_clients()
+        .returning(|names| Ok(names.iter().filter(|n| *n == "arian").cloned().collect()));
+    de.expect_validate_clients()
+        .with(eq(vec!["arian".to_string()]))
+        .times(1)
+        .returning(|_| Ok(()));
+    de.expect_make_client_paid()
+        .with(eq("arian"), always())
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let mut nl = MockRunner::new();
+    nl.expect_find_clients()
+        .returning(|names| Ok(names.iter().filter(|n| *n == "ali").cloned().collect()));
+    nl.expect_validate_clients()
+        .with(eq(vec!["ali".to_string()]))
+        .times(1)
+        .returning(|_| Err("client 'ali' is not notpaid, it's 'HOSSOBBEED'".to_string()));
+    nl.expect_make_client_paid()
+        .with(eq("ali"), always())
+        .times(1)
+        .returning(|_, _| Ok(()));
+
+    let router = router_with(vec![("de", de), ("nl", nl)]);
+    rocket::async_test(async move {
+        assert_eq!(
+            router
+                .validate_clients(&["arian".to_string(), "ali".to_string()])
+                .await,
+            Err("nl: client 'ali' is not notpaid, it's 'HOSSOBBEED'".to_string())
+        );
+        assert_eq!(
+            router
+                .validate_clients(&["arian".to_string(), "nobody".to_string()])
+                .await,
+            Err("cannot find clients".to_string())
+        );
+
+        let payment = payment_info();
+        assert_eq!(router.make_client_paid("arian", &payment).await, Ok(()));
+        assert_eq!(router.make_client_paid("ali", &payment).await, Ok(()));
+        assert_eq!(
+            router.make_client_paid("nobody", &payment).await,
+            Err("cannot find server of client 'nobody'".to_string())
+        );
+    });
+}
+
+#[test]
+fn router_should_fail_when_client_is_on_multiple_servers() {
+    let mut de = MockRunner::new();
+    de.expect_find_clients()
+        .returning(|names| Ok(names.to_vec()));
+    let mut nl = MockRunner::new();
+    nl.expect_find_clients()
+        .returning(|names| Ok(names.to_vec()));
+
+    let router = router_with(vec![("de", de), ("nl", nl)]);
+    rocket::async_test(async move {
+        assert_eq!(
+            router.validate_clients(&["arian".to_string()]).await,
+            Err("client 'arian' exists on both 'de' and 'nl'".to_string())
+        );
+    });
+}
+
+#[test]
+fn router_should_fail_when_no_server_answers() {
+    let mut de = MockRunner::new();
+    de.expect_find_clients()
+        .times(1)
+        .returning(|names| Ok(names.to_vec()));
+    de.expect_find_clients()
+        .returning(|_| Err("connection refused".to_string()));
+    let mut nl = MockRunner::new();
+    nl.expect_find_clients()
+        .times(1)
+        .returning(|_| Ok(Vec::new()));
+    nl.expect_find_clients()
+        .times(1)
+        .returning(|names| Ok(names.to_vec()));
+    nl.expect_find_clients()
+        .returning(|_| Err("timed out".to_string()));
+
+    let router = router_with(vec![("de", de), ("nl", nl)]);
+    rocket::async_test(async move {
+        let names = ["arian".to_string()];
+        assert_eq!(router.find_clients(&names).await, Ok(names.to_vec()));
+        // it may still be on the server that is down
+        assert_eq!(
+            router.find_clients(&names).await,
+            Err("client 'arian' exists on both 'de' and 'nl'".to_string())
+        );
+        assert_eq!(
+            router.find_clients(&[]).await,
+            Err("no server answered: 'de': connection refused, 'nl': timed out".to_string())
+        );
+
+        let health = check_servers(&router).await;
+        assert_eq!(health.healthy, Some(false));
+        assert_eq!(
+            health.message,
+            "server 'de': connection refused, server 'nl': timed out"
+        );
+    });
+}
+
+#[test]
+fn manjaliof_over_ssh_should_quote_remote_command() {
+    let manjaliof = Manjaliof::with_config(ManjaliofConfig {
+        envs: vec![("MANJALIOF_DATA".to_string(), "/data".to_string())],
+        ssh: Some(SshConfig {
+            destination: "root@de1".to_string(),
+            options: vec!["-p".to_string(), "2222".to_string()],
+            program: "echo".into(),
+        }),
+        ..Default::default()
+    });
+    rocket::async_test(async move {
+        let config = manjaliof.get_client_config("o'brien").await.unwrap();
+        assert_eq!(
+            config.links,
+            vec![r#"-p 2222 -- root@de1 env 'MANJALIOF_DATA=/data' 'manjaliof' 'get-link' '--name' 'o'\''brien'"#.to_string()]
+        );
+    });
+}
+
+#[test]
+fn http_agent_should_forward_requests() {
+    let (url, requests) = stub_server(vec![
+        (
+            200,
+            r#"{"success":true,"message":"","data":["arian"]}"#.to_string(),
+        ),
+        (200, r#"{"success":true,"message":""}"#.to_string()),
+        (
+            200,
+            r#"{"success":false,"message":"client 'ali' doesn't exist"}"#.to_string(),
+        ),
+        (401, "unauthorized".to_string()),
+    ]);
+    let agent = HttpAgent::new(&url, "agent_token", Duration::from_secs(5));
+
+    rocket::async_test(async move {
+        assert_eq!(
+            agent
+                .find_clients(&["arian".to_string(), "ali".to_string()])
+                .await,
+            Ok(vec!["arian".to_string()])
+        );
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /find_clients "));
+        assert!(head.contains("authorization: Bearer agent_token"));
+        assert_eq!(body, r#"{"names":["arian","ali"]}"#);
+
+        let payment = payment_info();
+        assert_eq!(agent.make_client_paid("arian", &payment).await, Ok(()));
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /make_client_paid "));
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["name"], "arian");
+        assert_eq!(body["payment"]["authority"], payment.authority.as_str());
+
+        assert_eq!(
+            agent.make_client_paid("ali", &payment).await,
+            Err("client 'ali' doesn't exist".to_string())
+        );
+        assert_eq!(
+            agent.validate_clients(&["arian".to_string()]).await,
+            Err("agent responded with '401 Unauthorized': unauthorized".to_string())
+        );
+    });
+}
+
+#[test]
+fn servers_should_be_managed_by_admins() {
+    run_test(|payment, runner| {
+        let base = manjaliof_running_script(r#"printf 'arian 1 2 NOTPAID\n'"#)
+            .config()
+            .clone();
+        let router = Arc::new(Router::new(base, None));
+        let client =
+            Client::untracked(rocket(payment, runner).attach(servers::stage(router.clone())))
+                .unwrap();
+        let (_, admin) = admin_token(&client);
+
+        let (healthy, broken) = (generate_random_authority(), generate_random_authority());
+        let res = client
+            .post("/admin/servers")
+            .header(admin.clone())
+            .body(format!(
+                r#"{{ "name": "{broken}", "transport": "http", "url": "http://127.0.0.1:1", "token_env": "MANJALIOF_MISSING_TOKEN" }}"#
+            ))
+            .dispatch();
+        assert_eq!(
+            res.into_json::<Value>().unwrap()["message"],
+            "token_env 'MANJALIOF_MISSING_TOKEN' should start with 'MANJALIOF_AGENT_TOKEN_'"
+        );
+        // other secrets of backend cannot be sent to agents
+        let res = client
+            .post("/admin/servers")
+            .header(admin.clone())
+            .body(format!(
+                r#"{{ "name": "{broken}", "transport": "http", "url": "http://127.0.0.1:1", "token_env": "MANJALIOF_BACKEND_TOKEN" }}"#
+            ))
+            .dispatch();
+        assert_eq!(
+            res.into_json::<Value>().unwrap()["message"],
+            "token_env 'MANJALIOF_BACKEND_TOKEN' should start with 'MANJALIOF_AGENT_TOKEN_'"
+        );
+        let res = client
+            .post("/admin/servers")
+            .header(admin.clone())
+            .body(format!(
+                r#"{{ "name": "{broken}", "transport": "http", "url": "http://127.0.0.1:1", "token_env": "MANJALIOF_AGENT_TOKEN_MISSING" }}"#
+            ))
+            .dispatch();
+        assert_eq!(
+            res.into_json::<Value>().unwrap()["message"],
+            "environment variable 'MANJALIOF_AGENT_TOKEN_MISSING' is not set"
+        );
+        std::env::set_var("MANJALIOF_AGENT_TOKEN_TEST", "t");
+        assert_eq!(
+            client
+                .post("/admin/servers")
+                .body(format!(r#"{{ "name": "{healthy}", "transport": "cli" }}"#))
+                .dispatch()
+                .status(),
+            Status::Unauthorized
+        );
+
+        for body in [
+            format!(
+                r#"{{ "name": "{healthy}", "region": "de", "capacity": 100, "transport": "cli" }}"#
+            ),
+            format!(
+                r#"{{ "name": "{broken}", "transport": "http", "url": "http://127.0.0.1:1", "token_env": "MANJALIOF_AGENT_TOKEN_TEST" }}"#
+            ),
+        ] {
+            let res = client
+                .post("/admin/servers")
+                .header(admin.clone())
+                .body(body)
+                .dispatch();
+            assert_eq!(
+                res.into_string().unwrap(),
+                r#"{"success":true,"message":""}"#
+            );
+        }
+
+        let res: Value = client
+            .get("/admin/servers?check=true")
+            .header(admin.clone())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let statuses = res["data"].as_array().unwrap();
+        let status = |name: &str| statuses.iter().find(|s| s["name"] == name).unwrap();
+        assert_eq!(status(&healthy)["region"], "de");
+        assert_eq!(status(&healthy)["capacity"], 100);
+        assert_eq!(status(&healthy)["transport"], "cli");
+        assert_eq!(status(&healthy)["health"]["healthy"], true);
+        assert_eq!(status(&broken)["transport"], "http");
+        assert_eq!(status(&broken)["health"]["healthy"], false);
+        assert!(status(&broken).get("token").is_none());
+
+        // only the server that is found unhealthy is audited
+        let audit = |name: &str| -> Value {
+            client
+                .get(format!(
+                    "/admin/audit?action=server.health&target=server:{name}"
+                ))
+                .header(admin.clone())
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["data"]
+                .clone()
+        };
+        assert_eq!(audit(&healthy), serde_json::json!([]));
+        let entries = audit(&broken);
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["before"], Value::Null);
+        assert_eq!(entries[0]["after"], "unhealthy");
+
+        // servers that are down don't stop finding clients on the others
+        rocket::async_test(async {
+            assert_eq!(
+                router.find_clients(&["arian".to_string()]).await,
+                Ok(vec!["arian".to_string()])
+            );
+            assert_eq!(
+                router.validate_clients(&["nobody".to_string()]).await,
+                Err("cannot find clients".to_string())
+            );
+        });
+
+        // they would run any command on backend host
+        for transport in [
+            r#""program": "/bin/sh""#,
+            r#""options": ["-oProxyCommand=sh"]"#,
+        ] {
+            let res = client
+                .post("/admin/servers")
+                .header(admin.clone())
+                .body(format!(
+                    r#"{{ "name": "{broken}", "transport": "ssh", "destination": "nl1", {transport} }}"#
+                ))
+                .dispatch();
+            assert_eq!(
+                res.into_json::<Value>().unwrap()["message"],
+                "ssh program and options can only be set in 'MANJALIOF_SERVERS'"
+            );
+        }
+
+        let res = client
+            .delete(format!("/admin/servers/{broken}"))
+            .header(admin.clone())
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":""}"#
+        );
+        let res = client
+            .delete(format!("/admin/servers/{broken}"))
+            .header(admin.clone())
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            format!(r#"{{"success":false,"message":"server '{broken}' doesn't exist"}}"#)
+        );
+
+        // servers are loaded from database on start
+        let router = Arc::new(Router::new(ManjaliofConfig::default(), None));
+        let _client = Client::untracked(
+            rocket(MockPayment::new(), MockRunner::new()).attach(servers::stage(router.clone())),
+        )
+        .unwrap();
+        rocket::async_test(async {
+            let names: Vec<String> = router
+                .servers()
+                .await
+                .iter()
+                .map(|server| server.name().to_string())
+                .collect();
+            assert!(names.contains(&healthy));
+            assert!(!names.contains(&broken));
+        });
+
+        // database outlives the test, leftover servers would break next runs
+        let res = client
+            .delete(format!("/admin/servers/{healthy}"))
+            .header(admin.clone())
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":""}"#
+        );
+    });
+}
+
+#[test]
+fn tokens_should_be_scoped() {
+    run_test(|payment, runner| {
+        let router = Arc::new(Router::new(ManjaliofConfig::default(), None));
+        let client =
+            Client::untracked(rocket(payment, runner).attach(servers::stage(router))).unwrap();
+        let db = Db::fetch(client.rocket()).unwrap();
+
+        let name = generate_random_authority();
+        let token =
+            rocket::async_test(issue_token(db, &name, &[Scope::CreatePayment], None)).unwrap();
+        let res = client
+            .post("/create_payment")
+            .header(Header::new("auth_token", token.clone()))
+            .body(r#"{ "clients": [] }"#)
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"at least provide one client"}"#
+        );
+        assert_eq!(
+            client
+                .get("/admin/servers")
+                .header(Header::new("auth_token", token.clone()))
+                .dispatch()
+                .status(),
+            Status::Forbidden
+        );
+
+        let (key_id, _) = token.split_once('.').unwrap();
+        let status = |token: String| {
+            client
+                .post("/create_payment")
+                .header(Header::new("auth_token", token))
+                .body(r#"{ "clients": [] }"#)
+                .dispatch()
+                .status()
+        };
+        assert_eq!(
+            status(format!("{key_id}.wrongsecret")),
+            Status::Unauthorized
+        );
+
+        let rotated = rocket::async_test(rotate_token(db, &name)).unwrap();
+        assert_eq!(status(token), Status::Unauthorized);
+        assert_eq!(status(rotated.clone()), Status::Ok);
+
+        rocket::async_test(revoke_token(db, &name)).unwrap();
+        assert_eq!(status(rotated), Status::Unauthorized);
+        assert_eq!(
+            rocket::async_test(rotate_token(db, &name)),
+            Err(format!("token '{name}' doesn't exist or is revoked"))
+        );
+
+        let expired = rocket::async_test(issue_token(
+            db,
+            &generate_random_authority(),
+            &[Scope::CreatePayment],
+            Some(Utc::now() - ChronoDuration::days(1)),
+        ))
+        .unwrap();
+        assert_eq!(status(expired), Status::Unauthorized);
+        assert_eq!(
+            rocket::async_test(issue_token(db, &name, &[], None)),
+            Err("token needs at least one scope".to_string())
+        );
+    });
+}
+
+#[test]
+fn tokens_should_be_accepted_as_bearer_and_keep_roles_apart() {
+    run_test(|payment, runner| {
+        let router = Arc::new(Router::new(ManjaliofConfig::default(), None));
+        let client =
+            Client::untracked(rocket(payment, runner).attach(servers::stage(router))).unwrap();
+        let db = Db::fetch(client.rocket()).unwrap();
+
+        let admin = rocket::async_test(issue_token(
+            db,
+            &generate_random_authority(),
+            &[Scope::AdminRead],
+            None,
+        ))
+        .unwrap();
+        let status = |uri: &str, authorization: String| {
+            let req = match uri {
+                "/create_payment" => client.post(uri).body(r#"{ "clients": [] }"#),
+                _ => client.get(uri.to_string()),
+            };
+            req.header(Header::new("Authorization", authorization))
+                .dispatch()
+                .status()
+        };
+        assert_eq!(
+            status("/admin/servers", format!("Bearer {admin}")),
+            Status::Ok
+        );
+        assert_eq!(
+            status("/admin/servers", format!("bearer {admin}")),
+            Status::Ok
+        );
+        assert_eq!(
+            status("/admin/servers", format!("Basic {admin}")),
+            Status::Unauthorized
+        );
+        assert_eq!(
+            status("/create_payment", format!("Bearer {admin}")),
+            Status::Forbidden
+        );
+        assert_eq!(
+            status("/create_payment", "Bearer somestrongtoken".to_string()),
+            Status::Ok
+        );
+
+        assert_eq!(
+            rocket::async_test(issue_token(
+                db,
+                &generate_random_authority(),
+                &[Scope::CreatePayment, Scope::AdminWrite],
+                None,
+            )),
+            Err("token cannot have both customer and admin scopes".to_string())
+        );
+    });
+}
+
+#[test]
+fn customers_should_only_pay_for_their_clients() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+
+        let client = Client::tracked(rocket(payment, runner)).unwrap();
+        let storefront = || Header::new("auth_token", "somestrongtoken");
+        let name = generate_random_authority();
+        let phone = generate_random_authority();
+        let credentials =
+            |password: &str| format!(r#"{{ "phone": "{phone}", "password": "{password}" }}"#);
+
+        let res = client
+            .post("/customer/register")
+            .header(storefront())
+            .body(credentials("short"))
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"password should be at least 8 characters"}"#
+        );
+        let res: Value = client
+            .post("/customer/register")
+            .header(storefront())
+            .body(credentials("longpassword"))
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let id = res["data"]["id"].as_i64().unwrap();
+        let res = client
+            .post("/customer/register")
+            .header(storefront())
+            .body(credentials("longpassword"))
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"cannot add customer: customer already exists"}"#
+        );
+
+        assert_eq!(
+            client
+                .get("/customer/me")
+                .header(storefront())
+                .dispatch()
+                .status(),
+            Status::Unauthorized
+        );
+        let res = client
+            .post("/customer/login")
+            .header(storefront())
+            .body(credentials("wrongpassword"))
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"login or password is wrong"}"#
+        );
+        // session is only sent in the httpOnly cookie
+        let res = client
+            .post("/customer/login")
+            .header(storefront())
+            .body(credentials("longpassword"))
+            .dispatch();
+        assert!(res.cookies().get(SESSION_COOKIE).is_some());
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":""}"#
+        );
+
+        let create_payment = || {
+            client
+                .post("/create_payment")
+                .header(storefront())
+                .body(format!(r#"{{ "clients": ["{name}"] }}"#))
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()
+        };
+        assert_eq!(
+            create_payment()["message"],
+            format!("cannot authorize clients: client '{name}' is not linked to customer")
+        );
+
+        // storefront token cannot reach admin endpoints
+        let link = || client.put(format!("/admin/customers/{id}/clients/{name}"));
+        assert_eq!(
+            link().header(storefront()).dispatch().status(),
+            Status::Forbidden
+        );
+        let (_, admin) = admin_token(&client);
+        let res = link().header(admin.clone()).dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":""}"#
+        );
+        assert_eq!(create_payment()["message"], authority.as_str());
+
+        let res: Value = client
+            .get("/customer/me")
+            .header(storefront())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["data"]["phone"], phone.as_str());
+        assert_eq!(res["data"]["clients"], serde_json::json!([name]));
+
+        let res: Value = client
+            .get("/customer/transactions")
+            .header(storefront())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let transactions = res["data"].as_array().unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0]["authority"], authority.as_str());
+        assert_eq!(transactions[0]["clients"], serde_json::json!([name]));
+        assert_eq!(transactions[0]["status"], "created");
+
+        client
+            .post("/customer/logout")
+            .header(storefront())
+            .dispatch();
+        assert_eq!(
+            client
+                .get("/customer/me")
+                .header(storefront())
+                .dispatch()
+                .status(),
+            Status::Unauthorized
+        );
+        // clients of a customer cannot be bought without its session
+        assert_eq!(
+            create_payment()["message"],
+            format!(
+                "cannot authorize clients: client '{name}' belongs to a customer, log in first"
+            )
+        );
+        client
+            .delete(format!("/admin/customers/{id}/clients/{name}"))
+            .header(admin)
+            .dispatch();
+    });
+}
+
+#[test]
+fn rate_limiter_should_refill_buckets() {
+    assert_eq!(
+        parse_limits("create_payment:ip=10").err(),
+        Some("limit 'create_payment:ip=10' is not like 'route:ip=10/60'".to_string())
+    );
+
+    let limiter = RateLimiter::new(parse_limits("create_payment:ip=2/10").unwrap());
+    let now = Instant::now();
+    let check =
+        |caller: &str, now: Instant| limiter.check("create_payment", LimitKey::Ip, caller, now);
+    assert_eq!(check("1.1.1.1", now), Ok(()));
+    assert_eq!(check("1.1.1.1", now), Ok(()));
+    assert_eq!(check("1.1.1.1", now), Err(Duration::from_secs(5)));
+    assert_eq!(check("2.2.2.2", now), Ok(()));
+    assert_eq!(check("1.1.1.1", now + Duration::from_secs(5)), Ok(()));
+    assert!(check("1.1.1.1", now + Duration::from_secs(5)).is_err());
+    assert_eq!(
+        limiter.check("verify_payment", LimitKey::Ip, "1.1.1.1", now),
+        Ok(())
+    );
+}
+
+#[test]
+fn verify_payment_should_be_rate_limited() {
+    run_test(|payment, runner| {
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let verify = |remote: &str, real_ip: &str| {
+            client
+                .post("/verify_payment")
+                .remote(format!("{remote}:8000").parse().unwrap())
+                .header(Header::new("X-Real-IP", real_ip.to_string()))
+                .body(r#"{ "authority": "doesnotexist" }"#)
+                .dispatch()
+        };
+
+        // header is ignored unless the request is from a trusted proxy
+        for i in 0..20 {
+            let spoofed = format!("1.1.1.{i}");
+            assert_eq!(verify("10.0.0.1", &spoofed).status(), Status::Ok);
+        }
+        let res = verify("10.0.0.1", "1.1.1.100");
+        assert_eq!(res.status(), Status::TooManyRequests);
+        assert_eq!(res.headers().get_one("Retry-After"), Some("3"));
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"too many requests"}"#
+        );
+        assert_eq!(
+            verify("127.0.0.1", "10.0.0.1").status(),
+            Status::TooManyRequests
+        );
+        assert_eq!(verify("127.0.0.1", "10.0.0.2").status(), Status::Ok);
+        assert_eq!(verify("10.0.0.3", "10.0.0.1").status(), Status::Ok);
+    });
+}
+
+#[test]
+fn cors_should_answer_preflight_of_allowed_origins() {
+    run_test(|payment, runner| {
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let res = client
+            .options("/create_payment")
+            .header(Header::new("Origin", "https://manjaliof.ts22.ir"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .dispatch();
+        assert_eq!(res.status(), Status::NoContent);
+        let headers = res.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Origin"),
+            Some("https://manjaliof.ts22.ir")
+        );
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Headers"),
+            Some("Content-Type, Authorization, auth_token")
+        );
+        assert_eq!(headers.get_one("Access-Control-Max-Age"), Some("86400"));
+    });
+
+    let cors = Cors::new(
+        &["https://shop.example.com", "https://staging.example.com"],
+        "GET",
+        "Authorization",
+        60,
+    );
+    let client = Client::untracked(rocket::build().attach(cors)).unwrap();
+    let origin = |origin: &str| {
+        client
+            .options("/anything")
+            .header(Header::new("Origin", origin.to_string()))
+            .dispatch()
+            .headers()
+            .get_one("Access-Control-Allow-Origin")
+            .map(str::to_string)
+    };
+    assert_eq!(
+        origin("https://staging.example.com").as_deref(),
+        Some("https://staging.example.com")
+    );
+    assert_eq!(
+        origin("https://shop.example.com").as_deref(),
+        Some("https://shop.example.com")
+    );
+    assert_eq!(origin("https://evil.example.com"), None);
+
+    let res = client
+        .get("/anything")
+        .header(Header::new("Origin", "https://shop.example.com"))
+        .dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Methods"), None);
+    assert_eq!(
+        res.headers().get_one("Access-Control-Allow-Credentials"),
+        Some("true")
+    );
+
+    // any origin can call, but not with cookies of customers
+    let cors = Cors::new(
+        &["https://shop.example.com", "*"],
+        "GET",
+        "Authorization",
+        60,
+    );
+    let client = Client::untracked(rocket::build().attach(cors)).unwrap();
+    let allowed = |origin: &str| {
+        let res = client
+            .get("/anything")
+            .header(Header::new("Origin", origin.to_string()))
+            .dispatch();
+        let headers = res.headers();
+        (
+            headers
+                .get_one("Access-Control-Allow-Origin")
+                .map(str::to_string),
+            headers
+                .get_one("Access-Control-Allow-Credentials")
+                .map(str::to_string),
+        )
+    };
+    assert_eq!(
+        allowed("https://evil.example.com"),
+        (Some("*".to_string()), None)
+    );
+    assert_eq!(
+        allowed("https://shop.example.com"),
+        (
+            Some("https://shop.example.com".to_string()),
+            Some("true".to_string())
+        )
+    );
+}
+
+/// issues a token with every admin scope, returns its name and header, env
+/// token is only for storefront
+fn admin_token(client: &Client) -> (String, Header<'static>) {
+    let db = Db::fetch(client.rocket()).unwrap();
+    let name = generate_random_authority();
+    let token = rocket::async_test(issue_token(
+        db,
+        &name,
+        &[Scope::AdminRead, Scope::AdminWrite, Scope::Refunds],
+        None,
+    ))
+    .unwrap();
+    (name, Header::new("auth_token", token))
+}
+
+/// registers and logs in a customer that has `clients`, returns its id
+fn logged_in_customer(client: &Client, clients: &[&str]) -> i64 {
+    let credentials = format!(
+        r#"{{ "phone": "{}", "password": "longpassword" }}"#,
+        generate_random_authority()
+    );
+    let res: Value = client
+        .post("/customer/register")
+        .header(Header::new("auth_token", "somestrongtoken"))
+        .body(&credentials)
+        .dispatch()
+        .into_json()
+        .unwrap();
+    let id = res["data"]["id"].as_i64().unwrap();
+    client
+        .post("/customer/login")
+        .header(Header::new("auth_token", "somestrongtoken"))
+        .body(&credentials)
+        .dispatch();
+
+    let (_, admin) = admin_token(client);
+    for name in clients {
+        client
+            .put(format!("/admin/customers/{id}/clients/{name}"))
+            .header(admin.clone())
+            .dispatch();
+    }
+    id
+}
+
+#[test]
+fn wallet_should_be_topped_up_and_pay_for_clients() {
+    run_test(|mut payment, mut runner| {
+        // clients can be linked to only one customer and database is shared
+        let name = generate_random_authority();
+        payment
+            .expect_request_payment_authority()
+            .with(eq("wallet top up"), always())
+            .returning(|_, _| Ok(generate_random_authority()));
+        payment.expect_verify().returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .with(
+                eq(name.clone()),
+                function(|payment: &PaymentInfo| {
+                    payment.gateway == "wallet" && payment.amount == 550000
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let client = Client::tracked(rocket(payment, runner)).unwrap();
+        logged_in_customer(&client, &[&name]);
+        let storefront = || Header::new("auth_token", "somestrongtoken");
+        let top_up = |amount: u32| {
+            let res: Value = client
+                .post("/wallet/top_up")
+                .header(storefront())
+                .body(format!(r#"{{ "amount": {amount} }}"#))
+                .dispatch()
+                .into_json()
+                .unwrap();
+            let authority = res["message"].as_str().unwrap().to_string();
+            // verifying twice shouldn't credit twice
+            for _ in 0..2 {
+                let res = client
+                    .post("/verify_payment")
+                    .body(format!(r#"{{ "authority": "{authority}" }}"#))
+                    .dispatch();
+                assert_eq!(
+                    res.into_string().unwrap(),
+                    r#"{"success":true,"message":""}"#
+                );
+            }
+        };
+        let buy = || {
+            client
+                .post("/wallet/buy")
+                .header(storefront())
+                .body(format!(r#"{{ "clients": ["{name}"] }}"#))
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()
+        };
+        let wallet = || {
+            client
+                .get("/wallet")
+                .header(storefront())
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()
+        };
+
+        top_up(300000);
+        assert_eq!(wallet()["data"]["balance"], 300000);
+        assert_eq!(
+            buy()["message"],
+            "cannot pay from wallet: not enough balance in wallet"
+        );
+
+        top_up(300000);
+        let res = buy();
+        assert_eq!(res["success"], true);
+        assert!(res["message"].as_str().unwrap().starts_with("wallet-"));
+        assert_eq!(res["data"]["config_token"].as_str().unwrap().len(), 32);
+
+        let wallet = wallet();
+        assert_eq!(wallet["data"]["balance"], 50000);
+        let ledger = wallet["data"]["ledger"].as_array().unwrap();
+        let entries: Vec<(i64, i64, &str)> = ledger
+            .iter()
+            .map(|entry| {
+                (
+                    entry["amount"].as_i64().unwrap(),
+                    entry["balance"].as_i64().unwrap(),
+                    entry["kind"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (-550000, 50000, "purchase"),
+                (300000, 600000, "top_up"),
+                (300000, 300000, "top_up"),
+            ]
+        );
+        assert_eq!(ledger[0]["reference"], res["message"]);
+    });
+}
+
+#[test]
+fn wallet_should_give_back_what_runner_cannot_deliver() {
+    run_test(|mut payment, mut runner| {
+        let (paid, failed) = (generate_random_authority(), generate_random_authority());
+        payment
+            .expect_request_payment_authority()
+            .returning(|_, _| Ok(generate_random_authority()));
+        payment.expect_verify().returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .with(eq(paid.clone()), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .with(eq(failed.clone()), always())
+            .times(2)
+            .returning(|_, _| Err("server is down".to_string()));
+
+        let client = Client::tracked(rocket(payment, runner)).unwrap();
+        let id = logged_in_customer(&client, &[&paid, &failed]);
+        let (_, admin) = admin_token(&client);
+        let storefront = || Header::new("auth_token", "somestrongtoken");
+        let post = |path: &str, body: String| {
+            client
+                .post(path.to_string())
+                .header(storefront())
+                .body(body)
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()
+        };
+
+        let res = post("/wallet/top_up", r#"{ "amount": 1100000 }"#.to_string());
+        let authority = res["message"].as_str().unwrap();
+        post(
+            "/verify_payment",
+            format!(r#"{{ "authority": "{authority}" }}"#),
+        );
+
+        // nothing is paid, so the transaction is kept as refunded
+        let res = post("/wallet/buy", format!(r#"{{ "clients": ["{failed}"] }}"#));
+        let message = format!(
+            "runner failed on names '{failed}': '{failed}': server is down, \
+            550000 is given back to wallet"
+        );
+        assert_eq!(res["message"], message);
+        let res: Value = client
+            .get(format!(
+                "/admin/audit?action=wallet.buy&target=customer:{id}"
+            ))
+            .header(admin.clone())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let refunded = res["data"][0]["authority"].as_str().unwrap().to_string();
+        assert_eq!(res["data"][0]["after"], "refunded");
+        assert_eq!(res["data"][0]["note"], message.as_str());
+        let res: Value = client
+            .get(format!("/admin/transactions/{refunded}"))
+            .header(admin.clone())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["data"]["status"], "refunded");
+        assert_eq!(res["data"]["amount"], 0);
+
+        let res = post(
+            "/wallet/buy",
+            format!(r#"{{ "clients": ["{paid}", "{failed}"] }}"#),
+        );
+        assert_eq!(
+            res["message"],
+            format!(
+                "runner failed on names '{paid},{failed}': '{failed}': server is down, \
+                550000 is given back to wallet"
+            )
+        );
+        let wallet = client
+            .get("/wallet")
+            .header(storefront())
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(wallet["data"]["balance"], 550000);
+        let kinds: Vec<(i64, &str)> = wallet["data"]["ledger"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry["amount"].as_i64().unwrap(),
+                    entry["kind"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (550000, "refund"),
+                (-1100000, "purchase"),
+                (550000, "refund"),
+                (-550000, "purchase"),
+                (1100000, "top_up")
+            ]
+        );
+
+        // a pending payment for the same clients rejects buying them from wallet
+        let res = post(
+            "/create_payment",
+            format!(r#"{{ "clients": ["{failed}"] }}"#),
+        );
+        assert_eq!(res["success"], true);
+        // clients are checked one by one, not as the whole list
+        let res = post(
+            "/wallet/buy",
+            format!(r#"{{ "clients": ["{paid}", "{failed}"] }}"#),
+        );
+        assert_eq!(
+            res["message"],
+            format!("cannot pay from wallet: '{failed}' already have a payment in progress")
+        );
+    });
+}
+
+#[test]
+fn ledger_should_record_payments_and_refunds() {
+    run_test(|payment, runner| {
+        let authority = generate_random_authority();
+        create_paid_transaction(&authority);
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let (_, admin) = admin_token(&client);
+        let refund = |amount: u32| {
+            client
+                .post("/admin/ledger/refunds")
+                .header(admin.clone())
+                .body(format!(
+                    r#"{{ "authority": "{authority}", "amount": {amount} }}"#
+                ))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(
+            refund(2000000),
+            r#"{"success":false,"message":"refund should be between 1 and 1100000"}"#
+        );
+        assert_eq!(refund(100000), r#"{"success":true,"message":""}"#);
+        assert_eq!(
+            refund(100000),
+            format!(r#"{{"success":false,"message":"'{authority}' is already refunded"}}"#)
+        );
+
+        let db = Db::fetch(client.rocket()).unwrap();
+        rocket::async_test(async move {
+            let postings: Vec<(String, String, i64)> = sqlx::query(
+                "SELECT ledger_entries.kind, ledger_postings.account, ledger_postings.amount
+                    FROM ledger_postings JOIN ledger_entries ON ledger_entries.id = entry_id
+                    WHERE reference=? ORDER BY ledger_entries.id, ledger_postings.rowid",
+            )
+            .bind(&authority)
+            .fetch_all(&**db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+            let posting = |kind: &str, account: &str, amount: i64| {
+                (kind.to_string(), account.to_string(), amount)
+            };
+            assert_eq!(
+                postings,
+                vec![
+                    posting("gateway_payment", "gateway", 1095000),
+                    posting("gateway_payment", "gateway_fees", 5000),
+                    posting("gateway_payment", "revenue", -1100000),
+                    posting("refund", "refunds", 100000),
+                    posting("refund", "gateway", -100000),
+                ]
+            );
+
+            let error = db
+                .execute("UPDATE ledger_postings SET amount=0")
+                .await
+                .unwrap_err();
+            assert!(error.to_string().contains("ledger is append only"));
+        });
+
+        let report = |query: &str| {
+            client
+                .get(format!("/admin/reports/revenue?{query}"))
+                .header(admin.clone())
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()
+        };
+        assert_eq!(
+            report("from=2000-01-01&to=2000-12-31")["data"],
+            serde_json::json!([])
+        );
+        let today = Utc::now().format("%Y-%m").to_string();
+        let res = report("period=month");
+        let row = res["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|row| row["period"] == today.as_str())
+            .unwrap();
+        let amount = |name: &str| row[name].as_i64().unwrap();
+        assert!(amount("revenue") >= 1100000);
+        assert!(amount("refunds") >= 100000);
+        assert_eq!(
+            amount("net"),
+            amount("revenue") - amount("fees") - amount("refunds") - amount("discounts")
+        );
+    });
+}
+
+#[test]
+fn ledger_should_record_discounts_and_wallet_refunds() {
+    run_test(|mut payment, mut runner| {
+        let name = generate_random_authority();
+        payment
+            .expect_request_payment_authority()
+            .returning(|_, _| Ok(generate_random_authority()));
+        payment.expect_verify().returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner.expect_make_client_paid().returning(|_, _| Ok(()));
+
+        let client = Client::tracked(rocket(payment, runner)).unwrap();
+        logged_in_customer(&client, &[&name]);
+        let (_, admin) = admin_token(&client);
+        let db = Db::fetch(client.rocket()).unwrap();
+        let post = |path: &str, body: String| -> Value {
+            let header = if path.starts_with("/admin") {
+                admin.clone()
+            } else {
+                Header::new("auth_token", "somestrongtoken")
+            };
+            client
+                .post(path.to_string())
+                .header(header)
+                .body(body)
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+        let postings = |authority: &str| -> Vec<(String, String, i64)> {
+            rocket::async_test(
+                sqlx::query(
+                    "SELECT ledger_entries.kind, ledger_postings.account, ledger_postings.amount
+                        FROM ledger_postings JOIN ledger_entries ON ledger_entries.id = entry_id
+                        WHERE reference=? ORDER BY ledger_entries.id, ledger_postings.rowid",
+                )
+                .bind(authority)
+                .fetch_all(&**db),
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect()
+        };
+        let posting = |kind: &str, account: &str, amount: i64| {
+            (kind.to_string(), account.to_string(), amount)
+        };
+
+        // paid less than price of its clients
+        let res = post("/create_payment", format!(r#"{{ "clients": ["{name}"] }}"#));
+        let discounted = res["message"].as_str().unwrap().to_string();
+        rocket::async_test(
+            sqlx::query("UPDATE transactions SET amount=500000 WHERE authority=?")
+                .bind(&discounted)
+                .execute(&**db),
+        )
+        .unwrap();
+        let res = post(
+            "/verify_payment",
+            format!(r#"{{ "authority": "{discounted}" }}"#),
+        );
+        assert_eq!(res["success"], true);
+        assert_eq!(
+            postings(&discounted),
+            vec![
+                posting("gateway_payment", "gateway", 495000),
+                posting("gateway_payment", "gateway_fees", 5000),
+                posting("gateway_payment", "discounts", 50000),
+                posting("gateway_payment", "revenue", -550000),
+            ]
+        );
+
+        let res = post("/wallet/top_up", r#"{ "amount": 550000 }"#.to_string());
+        let top_up = res["message"].as_str().unwrap().to_string();
+        post(
+            "/verify_payment",
+            format!(r#"{{ "authority": "{top_up}" }}"#),
+        );
+        let res = post("/wallet/buy", format!(r#"{{ "clients": ["{name}"] }}"#));
+        let bought = res["message"].as_str().unwrap().to_string();
+        let res = post(
+            "/admin/ledger/refunds",
+            format!(r#"{{ "authority": "{bought}", "amount": 100000 }}"#),
+        );
+        assert_eq!(res["success"], true);
+        assert_eq!(
+            postings(&bought),
+            vec![
+                posting("wallet_purchase", "wallets", 550000),
+                posting("wallet_purchase", "revenue", -550000),
+                posting("refund", "refunds", 100000),
+                posting("refund", "wallets", -100000),
+            ]
+        );
+        let wallet = client
+            .get("/wallet")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(wallet["data"]["balance"], 100000);
+        assert_eq!(wallet["data"]["ledger"][0]["kind"], "refund");
+    });
+}
+
+#[test]
+fn sales_should_be_reported_by_referrer() {
+    run_test(|mut payment, mut runner| {
+        let (paid, unpaid) = (generate_random_authority(), generate_random_authority());
+        let authorities = Arc::new(std::sync::Mutex::new(vec![unpaid.clone(), paid.clone()]));
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authorities.lock().unwrap().pop().unwrap()));
+        payment.expect_verify().returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner.expect_make_client_paid().returning(|_, _| Ok(()));
+
+        // referrers are free text, csv shouldn't let spreadsheets run them
+        let referrer = format!("=ref,{}", generate_random_authority());
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let (_, admin) = admin_token(&client);
+        for clients in [r#"["someone", "anotherone"]"#, r#"["someone"]"#] {
+            let res: Value = client
+                .post("/create_payment")
+                .header(Header::new("auth_token", "somestrongtoken"))
+                .body(format!(
+                    r#"{{ "clients": {clients}, "reffer": "{referrer}" }}"#
+                ))
+                .dispatch()
+                .into_json()
+                .unwrap();
+            assert_eq!(res["success"], true);
+        }
+        let res = client
+            .post("/create_payment")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .body(format!(
+                r#"{{ "clients": ["someone"], "reffer": "{}" }}"#,
+                "r".repeat(65)
+            ))
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":false,"message":"referrer should be at most 64 characters"}"#
+        );
+        let res = client
+            .post("/verify_payment")
+            .body(format!(r#"{{ "authority": "{paid}" }}"#))
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":""}"#
+        );
+
+        let report = |query: &str| {
+            client
+                .get(format!("/admin/reports/sales?{query}"))
+                .header(admin.clone())
+                .dispatch()
+        };
+        let res: Value = report("by=referrer").into_json().unwrap();
+        let row = res["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|row| row["key"] == referrer.as_str())
+            .unwrap()
+            .clone();
+        assert_eq!(
+            row,
+            serde_json::json!({
+                "key": referrer,
+                "created": 2,
+                "paid": 1,
+                "conversion_rate": 0.5,
+                "revenue": 1100000,
+                "clients": 2,
+            })
+        );
+
+        let res = report("by=referrer&format=csv");
+        assert_eq!(res.content_type(), Some(ContentType::CSV));
+        let csv = res.into_string().unwrap();
+        assert!(csv.starts_with("key,created,paid,conversion_rate,revenue,clients\n"));
+        assert!(csv.contains(&format!("\n\"'{referrer}\",2,1,0.5000,1100000,2\n")));
+
+        let res = report("by=plan&from=2000-01-01&to=2000-12-31");
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":"","data":[]}"#
+        );
+    });
+}
+
+#[test]
+fn notifier_should_send_to_telegram_and_webhooks() {
+    let (url, requests) = stub_server(vec![
+        (200, r#"{"ok":true}"#.to_string()),
+        (500, String::new()),
+    ]);
+    let notifier = Notifier::new(vec![
+        Target::Telegram {
+            api_url: url.clone(),
+            bot_token: "123:secret".to_string(),
+            chat_id: "-100".to_string(),
+        },
+        Target::Webhook(format!("{url}/hook")),
+    ]);
+    let event = Event::RunnerFailed {
+        authority: "A0001".to_string(),
+        names: "someone,anotherone".to_string(),
+        error: "client 'someone' doesn't exist".to_string(),
+    };
+    let text = "CRITICAL: payment 'A0001' is verified but runner failed on names \
+        'someone,anotherone': client 'someone' doesn't exist";
+    assert_eq!(event.to_string(), text);
+    let verified = |names: &str| Event::PaymentVerified {
+        authority: "A0001".to_string(),
+        names: names.to_string(),
+        amount: 1100000,
+    };
+    assert_eq!(
+        verified("someone,anotherone").to_string(),
+        "payment 'A0001' of '1100000' for 'someone,anotherone' is paid"
+    );
+    assert_eq!(
+        verified("").to_string(),
+        "wallet top up 'A0001' of '1100000' is paid"
+    );
+
+    rocket::async_test(async {
+        let error = notifier.send(&event).await.unwrap_err();
+        assert_eq!(error, "got status '500 Internal Server Error'");
+    });
+
+    let (head, body) = requests.recv().unwrap();
+    assert!(head.starts_with("POST /bot123:secret/sendMessage "));
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body, serde_json::json!({ "chat_id": "-100", "text": text }));
+
+    let (head, body) = requests.recv().unwrap();
+    assert!(head.starts_with("POST /hook "));
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "event": "runner_failed",
+            "authority": "A0001",
+            "names": "someone,anotherone",
+            "error": "client 'someone' doesn't exist",
+            "text": text,
+        })
+    );
+}
+
+#[test]
+fn bot_should_sell_clients_like_website() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        runner
+            .expect_validate_clients()
+            .with(eq(vec!["someone".to_string(), "anotherone".to_string()]))
+            .times(2)
+            .returning(|_| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .times(2)
+            .returning(|_, _| Ok(()));
+        payment
+            .expect_request_payment_authority()
+            .with(eq("someone,anotherone"), eq(1100000))
+            .times(1)
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        payment
+            .expect_start_url()
+            .returning(|authority| format!("https://pay/{authority}"));
+        payment
+            .expect_verify()
+            .times(1)
+            .returning(|_, _| Ok(receipt()));
+
+        let update = |id: i64, text: &str| {
+            format!(
+                r#"{{"ok":true,"result":[{{"update_id":{id},"message":{{"message_id":{id},"chat":{{"id":42}},"text":"{text}"}}}}]}}"#
+            )
+        };
+        let sent = r#"{"ok":true,"result":{}}"#.to_string();
+        let (url, requests) = stub_server(vec![
+            (200, update(7, "/pay")),
+            (200, sent.clone()),
+            (200, update(8, "someone, anotherone")),
+            (200, sent.clone()),
+            (200, update(9, "/pay")),
+            (200, sent.clone()),
+            (200, update(10, "/pay")),
+            (200, sent.clone()),
+            (200, update(11, "/verify")),
+            (200, sent.clone()),
+            (200, update(12, "/verify")),
+            (200, sent),
+        ]);
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let rocket = client.rocket();
+        let config = BotConfig {
+            api_url: url,
+            bot_token: "123:secret".to_string(),
+        };
+        let mut bot = Bot::new(
+            &config,
+            (**Db::fetch(rocket).unwrap()).clone(),
+            rocket.state::<Arc<dyn Payment>>().unwrap().clone(),
+            rocket.state::<Arc<dyn Runner>>().unwrap().clone(),
+            rocket.state::<Notify>().unwrap().clone(),
+            rocket.state::<Webhooks>().unwrap().clone(),
+            Arc::new(RateLimiter::new(parse_limits("bot_pay:chat=1/60").unwrap())),
+        );
+        let mut answers = Vec::new();
+        rocket::async_test(async {
+            for _ in 0..6 {
+                bot.poll(Duration::ZERO).await.unwrap();
+                let (head, body) = requests.recv().unwrap();
+                assert!(head.starts_with("POST /bot123:secret/getUpdates "));
+                let offset = serde_json::from_str::<Value>(&body).unwrap()["offset"].clone();
+                let (_, body) = requests.recv().unwrap();
+                let body: Value = serde_json::from_str(&body).unwrap();
+                assert_eq!(body["chat_id"], 42);
+                answers.push((offset, body["text"].as_str().unwrap().to_string()));
+            }
+        });
+
+        let verified =
+            format!("payment '{authority}' is verified and 'someone,anotherone' are activated");
+        assert_eq!(
+            answers,
+            vec![
+                (0.into(), "send names of clients first".to_string()),
+                (
+                    8.into(),
+                    "price of 'someone,anotherone' is 1100000 rials, send /pay to buy them"
+                        .to_string()
+                ),
+                (
+                    9.into(),
+                    format!("pay 1100000 rials at https://pay/{authority} and then send /verify")
+                ),
+                (
+                    10.into(),
+                    "too many payments, try again in 60 seconds".to_string()
+                ),
+                (11.into(), verified),
+                (
+                    12.into(),
+                    "there is no payment to verify, send /pay first".to_string()
+                ),
+            ]
+        );
+    });
+}
+
+#[test]
+fn failures_should_be_reported_once_for_each_status() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        payment
+            .expect_verify()
+            .times(2)
+            .returning(|_, _| Err("NotPaid".to_string()));
+        payment
+            .expect_verify()
+            .times(1)
+            .returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .times(2)
+            .returning(|_, _| Err("server is down".to_string()));
+
+        let webhooks = Webhooks::new(vec!["http://127.0.0.1:1/hook".to_string()], "somesecret");
+        let client = Client::untracked(rocket(MockPayment::new(), MockRunner::new())).unwrap();
+        let db = Db::fetch(client.rocket()).unwrap();
+        let checkout = Checkout {
+            payment: &payment,
+            runner: &runner,
+            notify: client.rocket().state::<Notify>().unwrap(),
+            webhooks: &webhooks,
+            actor: Actor::named("test"),
+        };
+
+        rocket::async_test(async {
+            let mut conn = db.acquire().await.unwrap();
+            let clients = ["someone".to_string()];
+            checkout
+                .create(&mut conn, &clients, None, None)
+                .await
+                .unwrap();
+            for _ in 0..4 {
+                assert!(checkout.verify(&mut conn, &authority).await.is_err());
+            }
+
+            let rows = sqlx::query(
+                "SELECT event FROM webhook_deliveries WHERE payload LIKE ? ORDER BY id",
+            )
+            .bind(format!("%{authority}%"))
+            .fetch_all(&**db)
+            .await
+            .unwrap();
+            let events: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+            assert_eq!(
+                events,
+                [
+                    "payment.created",
+                    "payment.failed",
+                    "payment.verified",
+                    "payment.failed"
+                ]
+            );
+        });
+    });
+}
+
+#[test]
+fn webhooks_should_be_signed_and_retried() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        payment.expect_verify().returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner.expect_make_client_paid().returning(|_, _| Ok(()));
+
+        let ok = r#"{"ok":true}"#.to_string();
+        let (url, requests) = stub_server(vec![
+            (500, String::new()),
+            (200, ok.clone()),
+            (200, ok.clone()),
+            (500, String::new()),
+            (200, ok),
+        ]);
+        let webhooks = Webhooks::new(vec![format!("{url}/hook")], "somesecret");
+        let client = Client::untracked(rocket(MockPayment::new(), MockRunner::new())).unwrap();
+        let (_, admin) = admin_token(&client);
+        let db = Db::fetch(client.rocket()).unwrap();
+        let checkout = Checkout {
+            payment: &payment,
+            runner: &runner,
+            notify: client.rocket().state::<Notify>().unwrap(),
+            webhooks: &webhooks,
+            actor: Actor::named("test"),
+        };
+
+        let now = Utc::now();
+        let ids: Vec<i64> = rocket::async_test(async {
+            let mut conn = db.acquire().await.unwrap();
+            let clients = ["someone".to_string()];
+            checkout
+                .create(&mut conn, &clients, None, None)
+                .await
+                .unwrap();
+            let verified = checkout.verify(&mut conn, &authority).await.unwrap();
+            assert_eq!(verified, Verified::Clients("someone".to_string()));
+
+            let rows = sqlx::query(
+                "SELECT id, event FROM webhook_deliveries WHERE payload LIKE ? ORDER BY id",
+            )
+            .bind(format!("%{authority}%"))
+            .fetch_all(&**db)
+            .await
+            .unwrap();
+            let events: Vec<String> = rows.iter().map(|row| row.get(1)).collect();
+            assert_eq!(
+                events,
+                ["payment.created", "payment.verified", "payment.fulfilled"]
+            );
+
+            assert_eq!(webhooks.deliver_due(db, now).await.unwrap(), 2);
+            // failed one is retried after backoff
+            assert_eq!(webhooks.deliver_due(db, now).await.unwrap(), 0);
+            let later = now + ChronoDuration::seconds(31);
+            assert_eq!(webhooks.deliver_due(db, later).await.unwrap(), 0);
+            rows.iter().map(|row| row.get(0)).collect()
+        });
+
+        for event in ["payment.created", "payment.verified", "payment.fulfilled"] {
+            let (head, body) = requests.recv().unwrap();
+            let head = head.to_lowercase();
+            assert!(head.starts_with("post /hook "));
+            assert!(head.contains(&format!("x-manjaliof-event: {event}\r\n")));
+            let signature = format!("x-manjaliof-signature: sha256={}\r\n", webhooks.sign(&body));
+            assert!(head.contains(&signature));
+            let body: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["event"], event);
+            assert_eq!(body["authority"], authority.as_str());
+            assert_eq!(body["amount"], 550000);
+        }
+        let (head, _) = requests.recv().unwrap();
+        assert!(head
+            .to_lowercase()
+            .contains("x-manjaliof-event: payment.created\r\n"));
+
+        let deliveries = |status: &str| {
+            client
+                .get(format!("/admin/webhooks/deliveries?status={status}"))
+                .header(admin.clone())
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|delivery| delivery["id"] == ids[0])
+                .cloned()
+        };
+        let pending = deliveries("pending").unwrap();
+        assert_eq!(pending["attempts"], 2);
+        assert_eq!(
+            pending["last_error"],
+            "got status '500 Internal Server Error'"
+        );
+
+        let res = client
+            .post(format!("/admin/webhooks/deliveries/{}/redeliver", ids[0]))
+            .header(admin.clone())
+            .dispatch();
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":""}"#
+        );
+        let (_, body) = requests.recv().unwrap();
+        assert_eq!(body, pending["payload"].as_str().unwrap());
+        assert_eq!(deliveries("delivered").unwrap()["attempts"], 1);
+        assert!(deliveries("pending").is_none());
+    });
+}
+
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn logs_should_carry_request_id_and_authority() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_writer(move || writer.clone())
+            .finish();
+        let request_id = generate_random_authority();
+        tracing::subscriber::with_default(subscriber, || {
+            let client = Client::untracked(rocket(payment, runner)).unwrap();
+            let res = client
+                .post("/create_payment")
+                .header(Header::new("auth_token", "somestrongtoken"))
+                .header(Header::new("X-Request-Id", request_id.clone()))
+                .body(r#"{ "clients": ["someone", "anotherone"] }"#)
+                .dispatch();
+            assert_eq!(
+                res.headers().get_one("X-Request-Id"),
+                Some(request_id.as_str())
+            );
+
+            let res = client
+                .post("/verify_payment")
+                .body(r#"{ "authority": "nothing" }"#)
+                .dispatch();
+            let generated = res.headers().get_one("X-Request-Id").unwrap();
+            assert_eq!(generated.len(), 16);
+        });
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let logs: Vec<Value> = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|log: &Value| log["span"]["request_id"] == request_id.as_str())
+            .collect();
+        let created = logs
+            .iter()
+            .find(|log| log["fields"]["message"] == "payment is created")
+            .unwrap();
+        assert_eq!(created["span"]["authority"], authority.as_str());
+        assert_eq!(created["span"]["names"], "someone,anotherone");
+        assert_eq!(created["span"]["uri"], "/create_payment");
+        assert_eq!(created["fields"]["caller"], "env");
+
+        let answered = logs.last().unwrap();
+        assert_eq!(answered["fields"]["message"], "request is answered");
+        assert_eq!(answered["fields"]["status"], 200);
+        assert_eq!(answered["span"]["route"], "/create_payment");
+    });
+}
+
+#[test]
+fn metrics_should_count_requests_and_runner_commands() {
+    run_test(|payment, mut runner| {
+        runner
+            .expect_validate_clients()
+            .returning(|_| Err("something wrong".to_string()));
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let (_, admin) = admin_token(&client);
+        client
+            .post("/create_payment")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .body(r#"{ "clients": ["arian"] }"#)
+            .dispatch();
+        rocket::async_test(async {
+            let manjaliof = manjaliof_running_script("exit 3");
+            assert!(manjaliof
+                .validate_clients(&["arian".to_string()])
+                .await
+                .is_err());
+        });
+
+        let res = client.get("/metrics").dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        let res = client.get("/metrics").header(admin.clone()).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            res.content_type().unwrap().to_string(),
+            "text/plain; version=0.0.4"
+        );
+        let metrics = res.into_string().unwrap();
+        assert!(metrics.contains(
+            r#"manjaliof_http_requests_total{method="POST",route="/create_payment",status="200"}"#
+        ));
+        assert!(metrics.contains(
+            r#"manjaliof_runner_commands_total{command="list",runner="cli",status="3"}"#
+        ));
+        assert!(metrics.contains("manjaliof_db_query_duration_seconds_count"));
+    });
+}
+
+#[test]
+fn ready_should_report_each_component() {
+    run_test(|payment, mut runner| {
+        let runner_fails = Arc::new(AtomicBool::new(false));
+        let fails = runner_fails.clone();
+        runner
+            .expect_find_clients()
+            .with(eq(Vec::<String>::new()))
+            .times(2)
+            .returning(move |_| match fails.load(Ordering::SeqCst) {
+                true => Err("cannot run manjaliof: No such file or directory".to_string()),
+                false => Ok(vec![]),
+            });
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let res = client.get("/health").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"success":true,"message":""}"#
+        );
+
+        let res = client.get("/ready").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res: Value = res.into_json().unwrap();
+        assert_eq!(res["success"], true);
+        assert_eq!(res["data"]["database"]["healthy"], true);
+        assert_eq!(res["data"]["runner"]["healthy"], true);
+        assert_eq!(res["data"]["gateway"]["healthy"], Value::Null);
+
+        // last check is answered until it's a few seconds old
+        runner_fails.store(true, Ordering::SeqCst);
+        assert_eq!(client.get("/ready").dispatch().status(), Status::Ok);
+        thread::sleep(Duration::from_secs(3));
+
+        let res = client.get("/ready").dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        let res: Value = res.into_json().unwrap();
+        assert_eq!(res["success"], false);
+        assert_eq!(res["message"], "not ready");
+        assert_eq!(res["data"]["database"]["healthy"], true);
+        assert_eq!(res["data"]["runner"]["healthy"], false);
+        assert_eq!(res["data"]["runner"]["message"], "");
+    });
+}
+
+#[test]
+fn gateway_should_be_ready_when_its_host_resolves() {
+    let mut payment = MockPayment::new();
+    payment
+        .expect_api_host()
+        .times(1)
+        .returning(|| "localhost".to_string());
+    payment
+        .expect_api_host()
+        .times(1)
+        .returning(|| "gateway.invalid".to_string());
+    rocket::async_test(async move {
+        assert_eq!(check_gateway(&payment).await.healthy, Some(true));
+        let health = check_gateway(&payment).await;
+        assert_eq!(health.healthy, Some(false));
+        assert!(health
+            .message
+            .starts_with("cannot resolve 'gateway.invalid'"));
+    });
+}
+
+#[test]
+fn cli_should_fix_stuck_payments() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let authority_clone = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authority_clone.clone()));
+        payment
+            .expect_verify()
+            .times(1)
+            .returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .times(1)
+            .returning(|_, _| Err("server is down".to_string()));
+        runner
+            .expect_make_client_paid()
+            .with(
+                eq("someone"),
+                function(|info: &PaymentInfo| info.ref_id == "201"),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let res: Value = client
+            .post("/create_payment")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .body(r#"{ "clients": ["someone"] }"#)
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["success"], true);
+        let res: Value = client
+            .post("/verify_payment")
+            .body(format!(r#"{{ "authority": "{authority}" }}"#))
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["success"], false);
+
+        let rocket = client.rocket();
+        rocket::async_test(async {
+            let show = cli::command(rocket, &["show", &authority]).await.unwrap();
+            assert!(show.contains("status\tverified"));
+            assert!(show.contains("ref_id\t201"));
+            let list = cli::command(rocket, &["list-transactions", "verified", "1000"])
+                .await
+                .unwrap();
+            assert!(list.contains(&format!("{authority}\tverified\tclients\t550000\tsomeone")));
+
+            assert_eq!(
+                cli::command(rocket, &["fulfill", &authority]).await,
+                Ok(format!("'someone' of payment '{authority}' are paid"))
+            );
+            assert_eq!(
+                cli::command(rocket, &["fulfill", &authority]).await,
+                Err(format!(
+                    "payment '{authority}' is fulfilled, only verified payments can be fulfilled"
+                ))
+            );
+            // fulfilled payments are not sent to gateway again
+            assert_eq!(
+                cli::command(rocket, &["re-verify", &authority]).await,
+                Ok(format!(
+                    "payment '{authority}' is verified and 'someone' are paid"
+                ))
+            );
+            let show = cli::command(rocket, &["show", &authority]).await.unwrap();
+            assert!(show.contains("status\tfulfilled"));
+
+            let csv = cli::command(rocket, &["export"]).await.unwrap();
+            assert!(csv.starts_with("authority,names,amount,kind,status,"));
+            assert!(csv.contains(&format!(
+                "{authority},someone,550000,clients,fulfilled,201,"
+            )));
+
+            let name = generate_random_authority();
+            let token = cli::command(rocket, &["issue-token", &name, "admin_read", "7"])
+                .await
+                .unwrap();
+            assert!(!token.is_empty());
+            let tokens = cli::command(rocket, &["token", "list"]).await.unwrap();
+            assert!(tokens.contains(&format!("{name}\t")));
+
+            assert!(cli::command(rocket, &["show"])
+                .await
+                .unwrap_err()
+                .starts_with("usage:"));
+            assert_eq!(
+                cli::command(rocket, &["list-transactions", "lost"]).await,
+                Err("transaction status 'lost' is not valid".to_string())
+            );
+        });
+
+        // only commands that pay clients need runner to be set up
+        std::env::set_var("MANJALIOF_RUNNER", "broken");
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        rocket::async_test(async {
+            assert_eq!(cli::run(&args(&["token", "list"])).await, Ok(()));
+            assert_eq!(
+                cli::run(&args(&["fulfill", "A0001"])).await,
+                Err("unknown runner 'broken', use 'cli', 'native' or 'remote'".to_string())
+            );
+        });
+    });
+}
+
+#[test]
+fn admins_should_fix_stuck_payments() {
+    run_test(|mut payment, mut runner| {
+        let (stuck, abandoned) = (generate_random_authority(), generate_random_authority());
+        let authorities = Arc::new(std::sync::Mutex::new(vec![
+            abandoned.clone(),
+            stuck.clone(),
+        ]));
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authorities.lock().unwrap().pop().unwrap()));
+        payment
+            .expect_verify()
+            .with(eq(stuck.clone()), always())
+            .times(1)
+            .returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .with(eq("someone"), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+        runner
+            .expect_make_client_paid()
+            .with(eq("anotherone"), always())
+            .times(1)
+            .returning(|_, _| Err("server is down".to_string()));
+        runner
+            .expect_make_client_paid()
+            .with(eq("anotherone"), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let (admin_name, admin_header) = admin_token(&client);
+        for clients in [r#"["someone", "anotherone"]"#, r#"["someone"]"#] {
+            let res: Value = client
+                .post("/create_payment")
+                .header(Header::new("auth_token", "somestrongtoken"))
+                .body(format!(r#"{{ "clients": {clients} }}"#))
+                .dispatch()
+                .into_json()
+                .unwrap();
+            assert_eq!(res["success"], true);
+        }
+        let verify = |authority: &str| -> Value {
+            client
+                .post("/verify_payment")
+                .body(format!(r#"{{ "authority": "{authority}" }}"#))
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+        assert_eq!(verify(&stuck)["success"], false);
+
+        let admin = |path: &str, body: &str| -> Value {
+            client
+                .post(format!("/admin/transactions/{path}"))
+                .header(admin_header.clone())
+                .remote("10.0.0.1:8000".parse().unwrap())
+                .body(body)
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+        let res = admin(&format!("{stuck}/fulfill"), r#"{ "clients": ["nobody"] }"#);
+        assert_eq!(
+            res["message"],
+            format!("cannot fulfill '{stuck}': client 'nobody' is not in payment '{stuck}'")
+        );
+        let res = admin(
+            &format!("{stuck}/fulfill"),
+            r#"{ "clients": ["anotherone"] }"#,
+        );
+        assert_eq!(res["success"], true);
+        let res = admin(&format!("{stuck}/reverify"), "");
+        assert_eq!(res["success"], true);
+        let res: Value = client
+            .get(format!("/admin/transactions/{stuck}"))
+            .header(admin_header.clone())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["data"]["status"], "fulfilled");
+        assert_eq!(res["data"]["names"], "someone,anotherone");
+
+        let res = admin(&format!("{abandoned}/resolve"), r#"{ "note": " " }"#);
+        assert_eq!(res["message"], "note of resolution is empty");
+        let res = admin(
+            &format!("{abandoned}/resolve"),
+            r#"{ "note": "paid by card to card" }"#,
+        );
+        assert_eq!(res["success"], true);
+        let res = admin(&format!("{abandoned}/resolve"), r#"{ "note": "again" }"#);
+        assert_eq!(
+            res["message"],
+            format!("payment '{abandoned}' is already resolved")
+        );
+        assert_eq!(
+            verify(&abandoned)["message"],
+            format!("payment '{abandoned}' is resolved by an admin")
+        );
+
+        let db = Db::fetch(client.rocket()).unwrap();
+        let (stuck, abandoned) = (stuck.clone(), abandoned.clone());
+        rocket::async_test(async move {
+            let audit = |authority: String| {
+                sqlx::query(
+                    "SELECT actor, ip, action, clients, before, after, note FROM audit_log
+                        WHERE authority=? ORDER BY id",
+                )
+                .bind(authority)
+                .fetch_all(&**db)
+            };
+            let rows: Vec<[Option<String>; 7]> = audit(stuck.clone())
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| std::array::from_fn(|i| row.get(i)))
+                .collect();
+            let s = |value: &str| Some(value.to_string());
+            assert_eq!(rows.len(), 5);
+            assert_eq!(
+                rows[0][..6],
+                [
+                    s("env"),
+                    None,
+                    s("transaction.create"),
+                    s("someone,anotherone"),
+                    None,
+                    s("created")
+                ]
+            );
+            assert_eq!(
+                rows[1][..6],
+                [
+                    s("anonymous"),
+                    None,
+                    s("transaction.verify"),
+                    s("someone,anotherone"),
+                    s("created"),
+                    s("verified")
+                ]
+            );
+            assert!(rows[1][6].as_ref().unwrap().contains("server is down"));
+            assert_eq!(
+                rows[2],
+                [
+                    s(&admin_name),
+                    s("10.0.0.1"),
+                    s("transaction.fulfill"),
+                    s("nobody"),
+                    s("verified"),
+                    s("verified"),
+                    s(&format!("client 'nobody' is not in payment '{stuck}'")),
+                ]
+            );
+            assert_eq!(
+                rows[3][2..6],
+                [
+                    s("transaction.fulfill"),
+                    s("anotherone"),
+                    s("verified"),
+                    s("fulfilled")
+                ]
+            );
+            assert_eq!(rows[3][6], None);
+            assert_eq!(rows[4][2], s("transaction.reverify"));
+
+            let rows = audit(abandoned).await.unwrap();
+            assert_eq!(rows.len(), 2);
+            let note: Option<String> = rows[1].get(6);
+            assert_eq!(note, s("paid by card to card"));
+            let before: Option<String> = rows[1].get(4);
+            assert_eq!(before, s("created"));
+        });
+    });
+}
+
+#[test]
+fn audit_log_should_be_queried_by_authority_client_and_target() {
+    run_test(|mut payment, mut runner| {
+        let (authority, name) = (generate_random_authority(), generate_random_authority());
+        let returned = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(returned.clone()));
+        payment.expect_verify().returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner.expect_make_client_paid().returning(|_, _| Ok(()));
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let (_, admin) = admin_token(&client);
+        client
+            .post("/create_payment")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .body(format!(r#"{{ "clients": ["{name}"] }}"#))
+            .dispatch();
+        client
+            .post("/verify_payment")
+            .remote("10.0.0.2:8000".parse().unwrap())
+            .body(format!(r#"{{ "authority": "{authority}" }}"#))
+            .dispatch();
+        let id = logged_in_customer(&client, &[&name]);
+
+        let audit = |query: &str| -> Value {
+            client
+                .get(format!("/admin/audit?{query}"))
+                .header(admin.clone())
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+        let res = audit(&format!("authority={authority}"));
+        assert_eq!(res["success"], true);
+        let entries = res["data"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        // newest entries come first
+        assert_eq!(entries[0]["action"], "transaction.verify");
+        assert_eq!(entries[0]["actor"], "anonymous");
+        assert_eq!(entries[0]["ip"], "10.0.0.2");
+        assert_eq!(entries[0]["before"], "created");
+        assert_eq!(entries[0]["after"], "fulfilled");
+        assert_eq!(entries[1]["action"], "transaction.create");
+        assert_eq!(entries[1]["actor"], "env");
+
+        let res = audit(&format!("client={name}"));
+        let actions: Vec<&str> = res["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                "customer.link_client",
+                "transaction.verify",
+                "transaction.create"
+            ]
+        );
+        // wildcards of sql are matched as they are
+        let res = audit(&format!("client={}_", &name[..name.len() - 1]));
+        assert_eq!(res["data"].as_array().unwrap().len(), 0);
+
+        let target = format!("customer:{id}");
+        let res = audit(&format!("target={target}&limit=2"));
+        let entries = res["data"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["action"], "customer.link_client");
+        assert_eq!(entries[1]["action"], "customer.login");
+        let res = audit(&format!(
+            "target={target}&action=customer.register&from=2000-01-01"
+        ));
+        assert_eq!(res["data"].as_array().unwrap().len(), 1);
+        let res = audit(&format!("target={target}&to=2000-01-01"));
+        assert_eq!(res["data"].as_array().unwrap().len(), 0);
+
+        let res = client.get("/admin/audit").dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+    });
+}
+
+#[test]
+fn authority_ttl_should_be_a_positive_number() {
+    assert_eq!(parse_authority_ttl(None), Ok(ChronoDuration::minutes(30)));
+    assert_eq!(
+        parse_authority_ttl(Some("5")),
+        Ok(ChronoDuration::minutes(5))
+    );
+    for minutes in ["0", "-5", "soon"] {
+        assert_eq!(
+            parse_authority_ttl(Some(minutes)),
+            Err(
+                "environment variable 'MANJALIOF_AUTHORITY_TTL_MINUTES' is not a positive number"
+                    .to_string()
+            )
+        );
+    }
+}
+
+#[test]
+fn verify_payment_should_expire_authorities_that_are_not_paid_in_time() {
+    run_test(|mut payment, mut runner| {
+        let (expired, late) = (generate_random_authority(), generate_random_authority());
+        let authorities = Arc::new(std::sync::Mutex::new(vec![late.clone(), expired.clone()]));
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(authorities.lock().unwrap().pop().unwrap()));
+        // gateway is asked only once after authority is expired
+        payment
+            .expect_verify()
+            .with(eq(expired.clone()), always())
+            .times(1)
+            .returning(|_, _| Err("-51".to_string()));
+        payment
+            .expect_verify()
+            .with(eq(late.clone()), always())
+            .times(1)
+            .returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        runner.expect_make_client_paid().returning(|_, _| Ok(()));
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let (_, admin) = admin_token(&client);
+        for _ in 0..2 {
+            client
+                .post("/create_payment")
+                .header(Header::new("auth_token", "somestrongtoken"))
+                .body(r#"{ "clients": ["someone"] }"#)
+                .dispatch();
+        }
+        let db = Db::fetch(client.rocket()).unwrap();
+        let (old_expired, old_late) = (expired.clone(), late.clone());
+        rocket::async_test(async move {
+            sqlx::query(
+                "UPDATE transactions SET date='2020-01-01 00:00:00' WHERE authority IN (?, ?)",
+            )
+            .bind(old_expired)
+            .bind(old_late)
+            .execute(&**db)
+            .await
+            .unwrap();
+        });
+
+        let status = |authority: &str| -> Value {
+            client
+                .get(format!("/admin/transactions/{authority}"))
+                .header(admin.clone())
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["data"]["status"]
+                .clone()
+        };
+        // they are shown expired before anyone verifies them
+        assert_eq!(status(&expired), "expired");
+        assert_eq!(status(&late), "expired");
+
+        let verify = |authority: &str| -> Value {
+            client
+                .post("/verify_payment")
+                .body(format!(r#"{{ "authority": "{authority}" }}"#))
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+        for _ in 0..2 {
+            let res = verify(&expired);
+            assert_eq!(res["success"], false);
+            assert_eq!(res["message"], "payment link expired, start again");
+        }
+        // it's paid right before it's expired
+        assert_eq!(verify(&late)["success"], true);
+
+        assert_eq!(status(&expired), "expired");
+        assert_eq!(status(&late), "fulfilled");
+
+        let res: Value = client
+            .get(format!(
+                "/admin/audit?authority={expired}&action=transaction.verify"
+            ))
+            .header(admin.clone())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["data"][0]["before"], "created");
+        assert_eq!(res["data"][0]["after"], "expired");
+        assert_eq!(res["data"][0]["note"], "-51");
+    });
+}
+
+#[test]
+fn payments_should_be_fulfilled_only_when_every_client_is_paid() {
+    run_test(|mut payment, mut runner| {
+        let authority = generate_random_authority();
+        let returned = authority.clone();
+        payment
+            .expect_request_payment_authority()
+            .returning(move |_, _| Ok(returned.clone()));
+        // verified payments are not verified with gateway again
+        payment
+            .expect_verify()
+            .times(1)
+            .returning(|_, _| Ok(receipt()));
+        runner.expect_validate_clients().returning(|_| Ok(()));
+        // a failed client doesn't stop the ones after it and paid clients are
+        // not made paid again
+        for (name, result) in [
+            ("first", Ok(())),
+            ("second", Err("server is down".to_string())),
+            ("third", Ok(())),
+            ("second", Ok(())),
+        ] {
+            runner
+                .expect_make_client_paid()
+                .with(eq(name), always())
+                .times(1)
+                .returning(move |_, _| result.clone());
+        }
+
+        let client = Client::untracked(rocket(payment, runner)).unwrap();
+        let (_, admin) = admin_token(&client);
+        client
+            .post("/create_payment")
+            .header(Header::new("auth_token", "somestrongtoken"))
+            .body(r#"{ "clients": ["first", "second", "third"] }"#)
+            .dispatch();
+        let res: Value = client
+            .post("/verify_payment")
+            .body(format!(r#"{{ "authority": "{authority}" }}"#))
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["success"], false);
+
+        let fulfill = |clients: &str| -> Value {
+            client
+                .post(format!("/admin/transactions/{authority}/fulfill"))
+                .header(admin.clone())
+                .body(format!(r#"{{ "clients": {clients} }}"#))
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+        let status = || -> Value {
+            client
+                .get(format!("/admin/transactions/{authority}"))
+                .header(admin.clone())
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["data"]["status"]
+                .clone()
+        };
+        assert_eq!(fulfill(r#"["third"]"#)["success"], true);
+        assert_eq!(status(), "verified");
+        let res: Value = client
+            .post(format!("/admin/transactions/{authority}/reverify"))
+            .header(admin.clone())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert_eq!(res["success"], true);
+        assert_eq!(status(), "fulfilled");
+    });
+}
@@ -1,33 +1,307 @@
+use crate::db::{
+    db_add_token, db_find_token, db_list_tokens, db_revoke_token, db_rotate_token, Db, StoredToken,
+};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
 use rocket::{
     http::Status,
     request::{FromRequest, Outcome, Request},
 };
-use std::env;
+use rocket_db_pools::{sqlx::SqlitePool, Database};
+use sha2::{Digest, Sha256};
+use std::{env, fmt, marker::PhantomData, str::FromStr};
+use subtle::ConstantTimeEq;
+use tracing::{error, info};
+
+const KEY_ID_LENGTH: usize = 8;
+const SECRET_LENGTH: usize = 32;
 
 lazy_static! {
-    static ref MANJALIOF_TOKEN: String = env::var("MANJALIOF_BACKEND_TOKEN")
-        .expect("environemnt variable 'MANJALIOF_BACKEND_TOKEN' is not set");
+    /// token from before tokens were kept in database, has every scope
+    static ref MANJALIOF_TOKEN: Option<String> = env::var("MANJALIOF_BACKEND_TOKEN").ok();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    CreatePayment,
+    AdminRead,
+    AdminWrite,
+    Refunds,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [
+        Scope::CreatePayment,
+        Scope::AdminRead,
+        Scope::AdminWrite,
+        Scope::Refunds,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::CreatePayment => "create_payment",
+            Scope::AdminRead => "admin_read",
+            Scope::AdminWrite => "admin_write",
+            Scope::Refunds => "refunds",
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        !matches!(self, Scope::CreatePayment)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|s| s.as_str() == scope)
+            .ok_or_else(|| format!("unknown scope '{scope}'"))
+    }
+}
+
+pub fn parse_scopes(scopes: &str) -> Result<Vec<Scope>, String> {
+    scopes
+        .split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .map(Scope::from_str)
+        .collect()
 }
 
-#[derive(Clone)]
-pub struct Token;
+/// scope that an [`AdminToken`] can be asked for
+pub trait AdminScope: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+macro_rules! admin_scope {
+    ($name:ident) => {
+        pub struct $name;
+
+        impl AdminScope for $name {
+            const SCOPE: Scope = Scope::$name;
+        }
+    };
+}
+
+admin_scope!(AdminRead);
+admin_scope!(AdminWrite);
+admin_scope!(Refunds);
+
+/// who sent the request, name of its token and what it's allowed to do
+#[derive(Clone, Debug, PartialEq)]
+pub struct Caller {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// storefront token, it has `create_payment` scope
+pub struct CustomerToken {
+    pub caller: Caller,
+}
+
+/// admin token that has scope `S`
+pub struct AdminToken<S: AdminScope> {
+    pub caller: Caller,
+    _scope: PhantomData<S>,
+}
+
+#[derive(Clone, Debug)]
+pub enum TokenError {
+    Missing,
+    Invalid,
+    Expired,
+    Revoked,
+    MissingScope(Scope),
+    Database(String),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Missing => write!(f, "no token"),
+            TokenError::Invalid => write!(f, "unauthorized token"),
+            TokenError::Expired => write!(f, "token is expired"),
+            TokenError::Revoked => write!(f, "token is revoked"),
+            TokenError::MissingScope(scope) => write!(f, "token doesn't have '{scope}' scope"),
+            TokenError::Database(error) => write!(f, "cannot check token: {error}"),
+        }
+    }
+}
+
+/// token of request, either from `Authorization: Bearer` or `auth_token` header
+fn request_token<'r>(request: &'r Request<'_>) -> Result<&'r str, TokenError> {
+    if let Some(authorization) = request.headers().get_one("Authorization") {
+        return match authorization.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Ok(token.trim()),
+            _ => Err(TokenError::Invalid),
+        };
+    }
+    request
+        .headers()
+        .get_one("auth_token")
+        .ok_or(TokenError::Missing)
+}
+
+/// authenticates token of the request once, every guard that needs the
+/// caller gets the same result
+pub async fn authenticated<'r>(request: &'r Request<'_>) -> &'r Result<Caller, TokenError> {
+    request
+        .local_cache_async(async {
+            let token = request_token(request)?;
+            let db = Db::fetch(request.rocket())
+                .ok_or_else(|| TokenError::Database("database is not set up".to_string()))?;
+            authenticate(db, token, Utc::now()).await
+        })
+        .await
+}
+
+/// authenticates the request and checks that its token has `scope`
+async fn request_caller(request: &Request<'_>, scope: Scope) -> Outcome<Caller, TokenError> {
+    match authenticated(request).await.clone() {
+        Ok(caller) if caller.scopes.contains(&scope) => Outcome::Success(caller),
+        Ok(caller) => {
+            let error = TokenError::MissingScope(scope);
+            info!("'{}' is rejected: {error}", caller.name);
+            Outcome::Failure((Status::Forbidden, error))
+        }
+        Err(error @ TokenError::Database(_)) => {
+            error!("{error}");
+            Outcome::Failure((Status::InternalServerError, error))
+        }
+        Err(error) => {
+            info!("{error}");
+            Outcome::Failure((Status::Unauthorized, error))
+        }
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for CustomerToken {
+    type Error = TokenError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        request_caller(request, Scope::CreatePayment)
+            .await
+            .map(|caller| CustomerToken { caller })
+    }
+}
 
 #[async_trait]
-impl<'r> FromRequest<'r> for Token {
-    type Error = &'static str;
+impl<'r, S: AdminScope> FromRequest<'r> for AdminToken<S> {
+    type Error = TokenError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let maybe_token = request.headers().get_one("auth_token");
-        match maybe_token {
-            Some(token) => {
-                if token == MANJALIOF_TOKEN.to_string() {
-                    Outcome::Success(Token)
-                } else {
-                    Outcome::Failure((Status::Unauthorized, "unauthorized token"))
-                }
-            }
-            None => Outcome::Failure((Status::Unauthorized, "no token")),
+        request_caller(request, S::SCOPE)
+            .await
+            .map(|caller| AdminToken {
+                caller,
+                _scope: PhantomData,
+            })
+    }
+}
+
+async fn authenticate(
+    db: &SqlitePool,
+    token: &str,
+    now: DateTime<Utc>,
+) -> Result<Caller, TokenError> {
+    if let Some(env_token) = MANJALIOF_TOKEN.as_ref() {
+        if bool::from(token.as_bytes().ct_eq(env_token.as_bytes())) {
+            return Ok(Caller {
+                name: "env".to_string(),
+                // it's the storefront token, admins use issued tokens
+                scopes: vec![Scope::CreatePayment],
+            });
         }
     }
+
+    let (key_id, secret) = token.split_once('.').ok_or(TokenError::Invalid)?;
+    let stored = db_find_token(db, key_id)
+        .await
+        .map_err(TokenError::Database)?
+        .ok_or(TokenError::Invalid)?;
+
+    let hash = hash_secret(secret);
+    if !bool::from(hash.as_bytes().ct_eq(stored.hash.as_bytes())) {
+        return Err(TokenError::Invalid);
+    }
+    if stored.revoked_at.is_some() {
+        return Err(TokenError::Revoked);
+    }
+    if stored
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= now)
+    {
+        return Err(TokenError::Expired);
+    }
+    Ok(Caller {
+        name: stored.name,
+        scopes: stored.scopes,
+    })
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+pub fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// makes a new key id and secret, returns the token and what should be stored of it
+fn generate() -> (String, String, String) {
+    let key_id = random_string(KEY_ID_LENGTH);
+    let secret = random_string(SECRET_LENGTH);
+    let hash = hash_secret(&secret);
+    (format!("{key_id}.{secret}"), key_id, hash)
+}
+
+/// returns the token, it's not possible to see it again after this
+pub async fn issue_token(
+    db: &SqlitePool,
+    name: &str,
+    scopes: &[Scope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<String, String> {
+    if scopes.is_empty() {
+        return Err("token needs at least one scope".to_string());
+    }
+    // storefront tokens end up in places like browsers and bots, so they
+    // shouldn't be able to do anything more than buying
+    if scopes.iter().any(Scope::is_admin) && scopes.contains(&Scope::CreatePayment) {
+        return Err("token cannot have both customer and admin scopes".to_string());
+    }
+    let (token, key_id, hash) = generate();
+    db_add_token(db, name, &key_id, &hash, scopes, expires_at).await?;
+    Ok(token)
+}
+
+/// gives the token a new secret while keeping its name, scopes and expiry,
+/// the old secret stops working right away
+pub async fn rotate_token(db: &SqlitePool, name: &str) -> Result<String, String> {
+    let (token, key_id, hash) = generate();
+    db_rotate_token(db, name, &key_id, &hash).await?;
+    Ok(token)
+}
+
+pub async fn revoke_token(db: &SqlitePool, name: &str) -> Result<(), String> {
+    db_revoke_token(db, name, Utc::now()).await
+}
+
+pub async fn list_tokens(db: &SqlitePool) -> Result<Vec<StoredToken>, String> {
+    db_list_tokens(db).await
 }
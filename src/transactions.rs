@@ -0,0 +1,149 @@
+use crate::{
+    audit::{self, Actor, Change},
+    checkout::Checkout,
+    db::{db_set_status, db_transaction, Db, Transaction, TransactionStatus},
+    logging::RequestSpan,
+    response::RequestResult,
+    token::{AdminRead, AdminToken, AdminWrite},
+};
+use rocket::{
+    fairing::AdHoc,
+    serde::{json::Json, Deserialize},
+};
+use rocket_db_pools::Connection;
+use tracing::{info, Instrument};
+
+/// mounts admin endpoints that fix payments which got stuck
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("transactions", |rocket| async {
+        rocket.mount("/admin", routes![transaction, reverify, fulfill, resolve])
+    })
+}
+
+#[get("/transactions/<authority>")]
+async fn transaction(
+    _token: AdminToken<AdminRead>,
+    mut db: Connection<Db>,
+    authority: &str,
+) -> Json<RequestResult<Transaction>> {
+    let transaction = try_in_request!(db_transaction(&mut db, authority).await);
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(transaction),
+    })
+}
+
+/// verifies the payment with gateway again and makes its clients paid
+#[post("/transactions/<authority>/reverify")]
+async fn reverify(
+    span: RequestSpan,
+    token: AdminToken<AdminWrite>,
+    mut db: Connection<Db>,
+    authority: &str,
+    checkout: Checkout<'_>,
+) -> Json<RequestResult> {
+    async move {
+        try_in_request!(checkout
+            .reverify(&mut db, authority)
+            .await
+            .map_err(|e| format!("cannot reverify '{authority}': {e}")));
+        info!(caller = %token.caller.name, "payment is reverified by admin");
+        Json(RequestResult {
+            success: true,
+            message: String::new(),
+            data: None,
+        })
+    }
+    .instrument(span.0)
+    .await
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct FulfillArgs {
+    /// every client of the transaction when it's empty
+    #[serde(default)]
+    clients: Vec<String>,
+}
+
+/// runs the runner again on clients of a verified payment
+#[post("/transactions/<authority>/fulfill", data = "<args>")]
+async fn fulfill(
+    span: RequestSpan,
+    token: AdminToken<AdminWrite>,
+    mut db: Connection<Db>,
+    authority: &str,
+    args: Json<FulfillArgs>,
+    checkout: Checkout<'_>,
+) -> Json<RequestResult> {
+    async move {
+        let names = try_in_request!(checkout
+            .fulfill(&mut db, authority, &args.clients)
+            .await
+            .map_err(|e| format!("cannot fulfill '{authority}': {e}")));
+        info!(caller = %token.caller.name, names, "payment is fulfilled by admin");
+        Json(RequestResult {
+            success: true,
+            message: String::new(),
+            data: None,
+        })
+    }
+    .instrument(span.0)
+    .await
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ResolveArgs {
+    note: String,
+}
+
+/// marks a payment that is taken care of outside of the payment flow, like
+/// when it's refunded, so it's not verified or fulfilled anymore
+#[post("/transactions/<authority>/resolve", data = "<args>")]
+async fn resolve(
+    token: AdminToken<AdminWrite>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    authority: &str,
+    args: Json<ResolveArgs>,
+) -> Json<RequestResult> {
+    let note = args.note.trim();
+    try_in_request!((!note.is_empty())
+        .then_some(())
+        .ok_or("note of resolution is empty".to_string()));
+    let transaction = try_in_request!(db_transaction(&mut db, authority).await);
+    try_in_request!(matches!(
+        transaction.status,
+        TransactionStatus::Created | TransactionStatus::Verified | TransactionStatus::Expired
+    )
+    .then_some(())
+    .ok_or(format!(
+        "payment '{authority}' is already {}",
+        transaction.status.as_str()
+    )));
+
+    try_in_request!(
+        db_set_status(&mut db, authority, TransactionStatus::Resolved)
+            .await
+            .map_err(|e| format!("cannot update transaction status: {e}"))
+    );
+    let change = Change {
+        action: "transaction.resolve",
+        authority: Some(authority),
+        clients: Some(&transaction.names),
+        before: Some(transaction.status.as_str()),
+        after: Some(TransactionStatus::Resolved.as_str()),
+        note: Some(note),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+
+    info!(caller = %token.caller.name, "'{authority}' is resolved by admin");
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}
@@ -0,0 +1,252 @@
+use crate::{
+    audit::{self, Change},
+    checkout::Checkout,
+    client_config::generate_config_token,
+    clients_price,
+    customer::{authorize_clients, Customer},
+    db::{
+        db_add_paid_client, db_add_top_up, db_debit_wallet, db_refund_wallet_purchase,
+        db_set_status, db_wallet, Db, LedgerEntry, TransactionStatus,
+    },
+    ledger::{self, Movement},
+    logging::RequestSpan,
+    notifier::Event,
+    payment::AUTHORITY_TTL,
+    rate_limit::RateLimit,
+    response::RequestResult,
+    runner::PaymentInfo,
+    token::{random_string, CustomerToken},
+};
+use chrono::Utc;
+use rocket::{
+    fairing::AdHoc,
+    serde::{json::Json, Deserialize, Serialize},
+};
+use rocket_db_pools::Connection;
+use tracing::{error, Instrument, Span};
+
+/// `gateway` of transactions that are paid from wallet
+pub const GATEWAY_NAME: &str = "wallet";
+
+/// mounts wallet endpoints, wallets belong to logged in customers
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("wallets", |rocket| async {
+        rocket.mount("/wallet", routes![wallet, top_up, buy])
+    })
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct WalletData {
+    balance: i64,
+    ledger: Vec<LedgerEntry>,
+}
+
+#[get("/")]
+async fn wallet(
+    _token: CustomerToken,
+    customer: Customer,
+    mut db: Connection<Db>,
+) -> Json<RequestResult<WalletData>> {
+    let (balance, ledger) = try_in_request!(db_wallet(&mut db, customer.id)
+        .await
+        .map_err(|e| format!("cannot find wallet: {e}")));
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(WalletData { balance, ledger }),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TopUpArgs {
+    amount: u32,
+}
+
+/// wallet is credited when the payment is verified with `/verify_payment`
+#[post("/top_up", data = "<args>")]
+async fn top_up(
+    span: RequestSpan,
+    _limit: RateLimit,
+    _token: CustomerToken,
+    customer: Customer,
+    mut db: Connection<Db>,
+    args: Json<TopUpArgs>,
+    checkout: Checkout<'_>,
+) -> Json<RequestResult> {
+    async move {
+        try_in_request!((args.amount > 0)
+            .then_some(())
+            .ok_or("amount should be more than zero".to_string()));
+
+        let authority = try_in_request!(checkout
+            .payment
+            .request_payment_authority("wallet top up", args.amount)
+            .await
+            .map_err(|e| format!("cannot request payment: {e}")));
+        try_in_request!(db_add_top_up(&mut db, &authority, args.amount, customer.id)
+            .await
+            .map_err(|e| format!("cannot add transactiont to database: {e}")));
+        let change = Change {
+            action: "wallet.top_up",
+            authority: Some(&authority),
+            target: Some(&format!("customer:{}", customer.id)),
+            after: Some(TransactionStatus::Created.as_str()),
+            note: Some(&format!("amount {}", args.amount)),
+            ..Default::default()
+        };
+        audit::record(&mut db, &checkout.actor, &change).await;
+
+        Json(RequestResult {
+            success: true,
+            message: authority,
+            data: None,
+        })
+    }
+    .instrument(span.0)
+    .await
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct BuyArgs {
+    clients: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BuyData {
+    config_token: String,
+}
+
+/// same as `/create_payment` but paid from wallet, so clients are made paid
+/// right away
+#[post("/buy", data = "<args>")]
+async fn buy(
+    span: RequestSpan,
+    _limit: RateLimit,
+    _token: CustomerToken,
+    customer: Customer,
+    mut db: Connection<Db>,
+    args: Json<BuyArgs>,
+    checkout: Checkout<'_>,
+) -> Json<RequestResult<BuyData>> {
+    async move {
+        try_in_request!((!args.clients.is_empty())
+            .then_some(())
+            .ok_or("at least provide one client".to_string()));
+
+        let customer_id = customer.id;
+        try_in_request!(authorize_clients(&mut db, Ok(customer), &args.clients)
+            .await
+            .map_err(|e| format!("cannot authorize clients: {e}")));
+
+        try_in_request!(checkout
+            .runner
+            .validate_clients(&args.clients)
+            .await
+            .map_err(|e| format!("cannot validate clients: {e}")));
+
+        let price = clients_price(args.clients.len());
+        let names = args.clients.join(",");
+        let authority = format!("{GATEWAY_NAME}-{}", random_string(24));
+        Span::current().record("authority", authority.as_str());
+        Span::current().record("names", names.as_str());
+        let config_token = generate_config_token();
+        let ledger_id = try_in_request!(db_debit_wallet(
+            &mut db,
+            customer_id,
+            &authority,
+            &names,
+            price,
+            &config_token,
+            Utc::now() - *AUTHORITY_TTL,
+        )
+        .await
+        .map_err(|e| format!("cannot pay from wallet: {e}")));
+
+        let payment_info = PaymentInfo {
+            authority: authority.clone(),
+            ref_id: ledger_id.to_string(),
+            amount: price,
+            gateway: GATEWAY_NAME.to_string(),
+            date: Utc::now(),
+        };
+        let (mut paid, mut errors) = (Vec::new(), Vec::new());
+        for name in &args.clients {
+            match checkout.runner.make_client_paid(name, &payment_info).await {
+                Ok(()) => {
+                    if let Err(error) = db_add_paid_client(&mut db, &authority, name).await {
+                        error!("cannot record paid client '{name}': {error}");
+                    }
+                    paid.push(name.as_str());
+                }
+                Err(error) => errors.push(format!("'{name}': {error}")),
+            }
+        }
+
+        if !errors.is_empty() {
+            let error = errors.join(", ");
+            checkout.notify.send(Event::RunnerFailed {
+                authority: authority.clone(),
+                names: names.clone(),
+                error: error.clone(),
+            });
+            // wallet is only charged for the clients that are actually paid
+            let refund = price - clients_price(paid.len());
+            let refunded = db_refund_wallet_purchase(
+                &mut db,
+                customer_id,
+                &authority,
+                &paid.join(","),
+                refund,
+            )
+            .await;
+            let message = match &refunded {
+                Ok(_) => format!(
+                    "runner failed on names '{names}': {error}, {refund} is given back to wallet"
+                ),
+                Err(refund_error) => format!(
+                    "CRITICAL: runner failed on names '{names}': {error}, \
+                    and cannot give {refund} back to wallet: {refund_error}"
+                ),
+            };
+            let change = Change {
+                action: "wallet.buy",
+                authority: Some(&authority),
+                clients: Some(&names),
+                target: Some(&format!("customer:{customer_id}")),
+                after: Some(refunded.unwrap_or(TransactionStatus::Verified).as_str()),
+                note: Some(&message),
+                ..Default::default()
+            };
+            audit::record(&mut db, &checkout.actor, &change).await;
+            try_in_request!(Err(message))
+        }
+
+        try_in_request!(
+            db_set_status(&mut db, &authority, TransactionStatus::Fulfilled)
+                .await
+                .map_err(|e| format!("cannot update transaction status: {e}"))
+        );
+        let change = Change {
+            action: "wallet.buy",
+            authority: Some(&authority),
+            clients: Some(&names),
+            target: Some(&format!("customer:{customer_id}")),
+            after: Some(TransactionStatus::Fulfilled.as_str()),
+            note: Some(&format!("amount {price}")),
+            ..Default::default()
+        };
+        audit::record(&mut db, &checkout.actor, &change).await;
+
+        Json(RequestResult {
+            success: true,
+            message: authority,
+            data: Some(BuyData { config_token }),
+        })
+    }
+    .instrument(span.0)
+    .await
+}
@@ -0,0 +1,294 @@
+use crate::{
+    audit::{self, Actor, Change},
+    db::{
+        db_add_deliveries, db_due_deliveries, db_find_delivery, db_list_deliveries,
+        db_set_delivery_attempt, Db, Delivery, DeliveryStatus,
+    },
+    response::RequestResult,
+    token::{AdminRead, AdminToken, AdminWrite},
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use rocket::{fairing::AdHoc, serde::json::Json, State};
+use rocket_db_pools::{
+    sqlx::{SqliteConnection, SqlitePool},
+    Connection, Database,
+};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{env, time::Duration};
+use tracing::{error, info};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: u32 = 50;
+/// a delivery is failed after this many attempts
+const MAX_ATTEMPTS: u32 = 8;
+/// waited before the second attempt, it's doubled after each attempt
+const FIRST_BACKOFF_SECS: i64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaymentEvent {
+    Created,
+    Verified,
+    Fulfilled,
+    /// verification or runner failed
+    Failed,
+}
+
+impl PaymentEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentEvent::Created => "payment.created",
+            PaymentEvent::Verified => "payment.verified",
+            PaymentEvent::Fulfilled => "payment.fulfilled",
+            PaymentEvent::Failed => "payment.failed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    authority: &'a str,
+    names: &'a str,
+    amount: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    date: DateTime<Utc>,
+}
+
+/// posts transaction events to configured urls, events are first stored as
+/// deliveries and sent in background with retries
+#[derive(Clone)]
+pub struct Webhooks {
+    urls: Vec<String>,
+    secret: String,
+    client: Client,
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<String>, secret: &str) -> Self {
+        let client = Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .expect("cannot build http client");
+        Webhooks {
+            urls,
+            secret: secret.to_string(),
+            client,
+        }
+    }
+
+    /// urls are comma separated in `MANJALIOF_WEBHOOKS`, payloads are signed
+    /// with `MANJALIOF_WEBHOOK_SECRET`
+    pub fn from_env() -> Self {
+        let urls = env::var("MANJALIOF_WEBHOOKS").unwrap_or_default();
+        let urls: Vec<String> = urls
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        let secret = env::var("MANJALIOF_WEBHOOK_SECRET").unwrap_or_default();
+        if !urls.is_empty() && secret.is_empty() {
+            panic!("environment variable 'MANJALIOF_WEBHOOK_SECRET' is not set");
+        }
+        Self::new(urls, &secret)
+    }
+
+    /// hex of hmac-sha256 of payload
+    pub fn sign(&self, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("hmac accepts keys of any length");
+        mac.update(payload.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// stores the event to be delivered to every url, failing to store it
+    /// doesn't fail the transaction so it's only logged
+    pub async fn enqueue(
+        &self,
+        db: &mut SqliteConnection,
+        event: PaymentEvent,
+        authority: &str,
+        names: &str,
+        amount: u32,
+        error: Option<&str>,
+    ) {
+        if self.urls.is_empty() {
+            return;
+        }
+        let payload = Payload {
+            event: event.as_str(),
+            authority,
+            names,
+            amount,
+            error,
+            date: Utc::now(),
+        };
+        let payload = serde_json::to_string(&payload).unwrap();
+        if let Err(error) = db_add_deliveries(db, &self.urls, event.as_str(), &payload).await {
+            error!(
+                "cannot add webhook deliveries of '{}' for '{authority}': {error}",
+                event.as_str()
+            );
+        }
+    }
+
+    async fn send(&self, delivery: &Delivery) -> Result<(), String> {
+        let resp = self
+            .client
+            .post(&delivery.url)
+            .header("Content-Type", "application/json")
+            .header("X-Manjaliof-Event", &delivery.event)
+            .header("X-Manjaliof-Delivery", delivery.id.to_string())
+            .header(
+                "X-Manjaliof-Signature",
+                format!("sha256={}", self.sign(&delivery.payload)),
+            )
+            .body(delivery.payload.clone())
+            .send()
+            .await
+            .map_err(|e| format!("send failed: {e}"))?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("got status '{}'", resp.status())),
+        }
+    }
+
+    /// attempts the delivery once and records the result, failed attempts are
+    /// retried later with backoff until `MAX_ATTEMPTS`
+    pub async fn attempt(
+        &self,
+        db: &mut SqliteConnection,
+        delivery: &Delivery,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let result = self.send(delivery).await;
+        let attempts = delivery.attempts + 1;
+        let status = match &result {
+            Ok(()) => DeliveryStatus::Delivered,
+            Err(_) if attempts >= MAX_ATTEMPTS => DeliveryStatus::Failed,
+            Err(_) => DeliveryStatus::Pending,
+        };
+        let backoff = ChronoDuration::seconds(FIRST_BACKOFF_SECS << (attempts - 1).min(16));
+        let error = result.as_ref().err().map(|error| error.as_str());
+        db_set_delivery_attempt(db, delivery.id, status, attempts, error, now + backoff).await?;
+        result
+    }
+
+    /// attempts deliveries that are due until `now`, returns how many of them
+    /// are delivered, deliveries that are done or given up on are audited
+    pub async fn deliver_due(&self, db: &SqlitePool, now: DateTime<Utc>) -> Result<usize, String> {
+        let deliveries = db_due_deliveries(db, now, BATCH_SIZE).await?;
+        let mut db = db.acquire().await.map_err(|e| e.to_string())?;
+        let mut delivered = 0;
+        let actor = Actor::named("webhook sender");
+        for delivery in deliveries {
+            let result = self.attempt(&mut db, &delivery, now).await;
+            let after = match result {
+                Ok(()) => DeliveryStatus::Delivered,
+                Err(_) if delivery.attempts + 1 >= MAX_ATTEMPTS => DeliveryStatus::Failed,
+                Err(_) => continue,
+            };
+            let change = Change {
+                action: "webhook.deliver",
+                target: Some(&format!("delivery:{}", delivery.id)),
+                before: Some(delivery.status.as_str()),
+                after: Some(after.as_str()),
+                note: result.as_ref().err().map(String::as_str),
+                ..Default::default()
+            };
+            audit::record(&mut db, &actor, &change).await;
+            if result.is_ok() {
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+}
+
+/// manages [`Webhooks`], mounts admin endpoints of deliveries and delivers
+/// them in background after liftoff
+pub fn stage(webhooks: Webhooks) -> AdHoc {
+    AdHoc::on_ignite("webhooks", |rocket| async {
+        rocket
+            .manage(webhooks.clone())
+            .mount("/admin", routes![deliveries, redeliver])
+            .attach(AdHoc::on_liftoff("webhook sender", |rocket| {
+                Box::pin(async move {
+                    if webhooks.urls.is_empty() {
+                        return;
+                    }
+                    let db = match Db::fetch(rocket) {
+                        Some(db) => (**db).clone(),
+                        None => return error!("cannot send webhooks: database is not set up"),
+                    };
+                    rocket::tokio::spawn(async move {
+                        loop {
+                            if let Err(error) = webhooks.deliver_due(&db, Utc::now()).await {
+                                error!("cannot deliver webhooks: {error}");
+                            }
+                            rocket::tokio::time::sleep(POLL_INTERVAL).await;
+                        }
+                    });
+                })
+            }))
+    })
+}
+
+#[get("/webhooks/deliveries?<status>")]
+async fn deliveries(
+    _token: AdminToken<AdminRead>,
+    mut db: Connection<Db>,
+    status: Option<DeliveryStatus>,
+) -> Json<RequestResult<Vec<Delivery>>> {
+    let deliveries = try_in_request!(db_list_deliveries(&mut db, status)
+        .await
+        .map_err(|e| format!("cannot list deliveries: {e}")));
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: Some(deliveries),
+    })
+}
+
+/// delivers again right away, even when it's already delivered
+#[post("/webhooks/deliveries/<id>/redeliver")]
+async fn redeliver(
+    token: AdminToken<AdminWrite>,
+    actor: Actor,
+    mut db: Connection<Db>,
+    id: i64,
+    webhooks: &State<Webhooks>,
+) -> Json<RequestResult> {
+    let mut delivery = try_in_request!(db_find_delivery(&mut db, id).await);
+    // counting starts over so it's retried again if it fails
+    delivery.attempts = 0;
+    let result = webhooks.attempt(&mut db, &delivery, Utc::now()).await;
+    let after = match result {
+        Ok(()) => DeliveryStatus::Delivered,
+        Err(_) => DeliveryStatus::Pending,
+    };
+    let change = Change {
+        action: "webhook.redeliver",
+        target: Some(&format!("delivery:{id}")),
+        before: Some(delivery.status.as_str()),
+        after: Some(after.as_str()),
+        note: result.as_ref().err().map(String::as_str),
+        ..Default::default()
+    };
+    audit::record(&mut db, &actor, &change).await;
+    try_in_request!(result.map_err(|e| format!("cannot deliver '{id}': {e}")));
+
+    info!(
+        "delivery '{id}' is redelivered by token '{}'",
+        token.caller.name
+    );
+    Json(RequestResult {
+        success: true,
+        message: String::new(),
+        data: None,
+    })
+}